@@ -1,9 +1,14 @@
 use crate::matrix_client::{
-    Buddy, InviteInfo, LogEntry, LoginCredentials, MatrixState, Message, MessageDeletedEvent,
-    MessageEditEvent, MessagesPage, PersistedSession, PublicSpace, ReactionEvent, Room,
-    RoomProfile, ServerLog, SharedRoom, Space, SpaceChild, TypingEvent, UserProfile,
-    VerificationEmoji, VerificationEmojisEvent, VerificationEvent,
+    Account, BackupProgressEvent, Buddy, DeviceInfo, EmailVerification, IdentityChangedEvent, IdentityProviderInfo, InviteInfo, KnownIdentities, LogEntry, LoginCredentials, MatrixState, Message, MessageDeletedEvent,
+    MessageEditEvent, MessagesPage, PendingRegistration, PersistedSession, PublicSpace, ReactionEvent, RecoveryStatus, ReplyCache, ReplyPreview, RestoreStatus, RestoredAccount,
+    RegistrationResponse, RegistrationStage, RegistrationStatus, Room,
+    RoomProfile, RoomThread, SendOutcome, ServerCapabilities, ServerError, ServerLog, SessionEvent, SharedRoom, Space, SpaceChild, StoreInfo, SyncStatusEvent, ThreadsPage, TypingEvent, UndecryptedEvents, UserDevice, UserProfile,
+    VerificationEmoji, VerificationEmojisEvent, VerificationEvent, VerificationReadyEvent,
 };
+use crate::secret_store::{SecretBackend, SecretStorageStatus, SecretStore, SessionSecrets};
+use crate::slash_commands::{self, SlashCommand};
+use crate::uiaa::{self, UiaaInfo};
+use futures_util::StreamExt;
 use matrix_sdk::{Client, ServerName};
 use tauri::{Emitter, State};
 
@@ -38,14 +43,61 @@ fn mxc_to_http(homeserver: &str, mxc_url: &str) -> Option<String> {
     ))
 }
 
-/// Extract the mxc:// URL string from a MediaSource (plain only; encrypted media not supported).
+/// Extract the mxc:// URL string from a MediaSource. For encrypted media this
+/// is the ciphertext; its key stays in the event for `fetch_media`.
 fn media_source_to_mxc(source: &matrix_sdk::ruma::events::room::MediaSource) -> Option<String> {
     match source {
         matrix_sdk::ruma::events::room::MediaSource::Plain(uri) => Some(uri.to_string()),
+        matrix_sdk::ruma::events::room::MediaSource::Encrypted(file) => Some(file.url.to_string()),
+    }
+}
+
+/// Media attached to a message, if it's an image, file, audio or video.
+fn message_media_source(
+    msgtype: &matrix_sdk::ruma::events::room::message::MessageType,
+) -> Option<&matrix_sdk::ruma::events::room::MediaSource> {
+    use matrix_sdk::ruma::events::room::message::MessageType;
+    match msgtype {
+        MessageType::Image(img) => Some(&img.source),
+        MessageType::File(file) => Some(&file.source),
+        MessageType::Audio(audio) => Some(&audio.source),
+        MessageType::Video(video) => Some(&video.source),
+        _ => None,
+    }
+}
+
+/// Preview image of a message: its thumbnail, or for images without one the
+/// image itself.
+fn message_thumbnail_source(
+    msgtype: &matrix_sdk::ruma::events::room::message::MessageType,
+) -> Option<&matrix_sdk::ruma::events::room::MediaSource> {
+    use matrix_sdk::ruma::events::room::message::MessageType;
+    match msgtype {
+        MessageType::Image(img) => img.info.as_ref().and_then(|info| info.thumbnail_source.as_ref()).or(Some(&img.source)),
+        MessageType::Video(video) => video.info.as_ref().and_then(|info| info.thumbnail_source.as_ref()),
+        MessageType::File(file) => file.info.as_ref().and_then(|info| info.thumbnail_source.as_ref()),
         _ => None,
     }
 }
 
+/// Wrap downloaded media in a data URL, sniffing common image types.
+fn media_data_url(bytes: &[u8]) -> String {
+    let content_type = if bytes.starts_with(&[0x89, 0x50, 0x4E, 0x47]) {
+        "image/png"
+    } else if bytes.starts_with(&[0xFF, 0xD8]) {
+        "image/jpeg"
+    } else if bytes.starts_with(b"GIF") {
+        "image/gif"
+    } else if bytes.starts_with(b"RIFF") {
+        "image/webp"
+    } else {
+        "application/octet-stream"
+    };
+    use base64::Engine;
+    let b64 = base64::engine::general_purpose::STANDARD.encode(bytes);
+    format!("data:{};base64,{}", content_type, b64)
+}
+
 /// Parse Matrix reply fallback from body. Returns (sender_id, quoted_text).
 fn extract_reply_fallback(body: &str) -> Option<(String, String)> {
     if !body.starts_with("> <") {
@@ -93,6 +145,376 @@ fn strip_reply_fallback(body: &str) -> String {
     lines.collect::<Vec<_>>().join("\n")
 }
 
+/// What a room message means for the UI.
+enum MessageUpdate {
+    New(Box<Message>),
+    Edit(MessageEditEvent),
+}
+
+/// Convert a room message into a new message or an edit of an earlier one;
+/// `None` for edits without text. `raw_json` is the event source, used to find
+/// replies ruma didn't parse.
+fn message_update(
+    account: &str,
+    room_id: &str,
+    event: &matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent,
+    raw_json: Option<&str>,
+) -> Option<MessageUpdate> {
+    use matrix_sdk::ruma::events::room::message::{MessageType, Relation};
+
+    if let Some(Relation::Replacement(replacement)) = &event.content.relates_to {
+        let new_body = match &replacement.new_content.msgtype {
+            MessageType::Text(text) => text.body.clone(),
+            MessageType::Notice(notice) => notice.body.clone(),
+            MessageType::Emote(emote) => format!("* {}", emote.body),
+            _ => return None,
+        };
+        return Some(MessageUpdate::Edit(MessageEditEvent {
+            account: account.to_string(),
+            room_id: room_id.to_string(),
+            original_event_id: replacement.event_id.to_string(),
+            new_body,
+            new_formatted_body: crate::formatting::message_html(&replacement.new_content.msgtype),
+            sender: event.sender.to_string(),
+            sender_name: event.sender.localpart().to_string(),
+        }));
+    }
+
+    // Extract reply relation (Reply or Thread)
+    let mut in_reply_to: Option<String> = None;
+    let mut thread_root: Option<String> = None;
+    let mut reply_sender_name: Option<String> = None;
+    let mut reply_body_text: Option<String> = None;
+
+    match &event.content.relates_to {
+        Some(Relation::Reply { in_reply_to: irt }) => {
+            in_reply_to = Some(irt.event_id.to_string());
+        }
+        Some(Relation::Thread(thread)) => {
+            thread_root = Some(thread.event_id.to_string());
+            // A falling-back in_reply_to only points at the thread's previous
+            // event for clients without threads; it isn't a real reply
+            if let Some(irt) = thread.in_reply_to.as_ref().filter(|_| !thread.is_falling_back) {
+                in_reply_to = Some(irt.event_id.to_string());
+            }
+        }
+        _ => {}
+    }
+
+    // Fallback: parse raw JSON for m.relates_to.m.in_reply_to.event_id
+    // Some servers (e.g. continuwuity) may include fields that ruma
+    // doesn't recognize, causing typed deserialization to miss the reply.
+    if in_reply_to.is_none() && thread_root.is_none() {
+        if let Some(raw_json) = raw_json.and_then(|json| serde_json::from_str::<serde_json::Value>(json).ok()) {
+            if let Some(eid) = raw_json.pointer("/content/m.relates_to/m.in_reply_to/event_id")
+                .and_then(|v| v.as_str())
+            {
+                in_reply_to = Some(eid.to_string());
+            }
+        }
+    }
+
+    let (mut body, msg_type, media_url, filename) = match &event.content.msgtype {
+        MessageType::Text(text) => (text.body.clone(), "text".to_string(), None, None),
+        MessageType::Notice(notice) => (notice.body.clone(), "text".to_string(), None, None),
+        MessageType::Emote(emote) => (format!("* {}", emote.body), "text".to_string(), None, None),
+        MessageType::Image(img) => {
+            (img.body.clone(), "image".to_string(), media_source_to_mxc(&img.source), Some(img.body.clone()))
+        }
+        MessageType::File(file) => {
+            let fname = file.filename.clone().unwrap_or_else(|| file.body.clone());
+            (file.body.clone(), "file".to_string(), media_source_to_mxc(&file.source), Some(fname))
+        }
+        MessageType::Audio(audio) => {
+            (audio.body.clone(), "audio".to_string(), media_source_to_mxc(&audio.source), Some(audio.body.clone()))
+        }
+        MessageType::Video(video) => {
+            (video.body.clone(), "video".to_string(), media_source_to_mxc(&video.source), Some(video.body.clone()))
+        }
+        _ => (String::new(), "unknown".to_string(), None, None),
+    };
+    let formatted_body = crate::formatting::message_html(&event.content.msgtype);
+    let media_encrypted = matches!(
+        message_media_source(&event.content.msgtype),
+        Some(matrix_sdk::ruma::events::room::MediaSource::Encrypted(_))
+    );
+
+    // For text replies, parse and strip fallback. What it quotes is only
+    // shown if the replied-to event itself can't be looked up.
+    if msg_type == "text" && body.starts_with("> <") {
+        if let Some((sender, quoted)) = extract_reply_fallback(&body) {
+            reply_sender_name = Some(sender);
+            reply_body_text = Some(quoted);
+        }
+        body = strip_reply_fallback(&body);
+    }
+
+    Some(MessageUpdate::New(Box::new(Message {
+        account: account.to_string(),
+        room_id: room_id.to_string(),
+        event_id: event.event_id.to_string(),
+        sender: event.sender.to_string(),
+        sender_name: event.sender.localpart().to_string(),
+        body,
+        formatted_body,
+        timestamp: event.origin_server_ts.as_secs().into(),
+        msg_type,
+        media_url,
+        media_encrypted,
+        filename,
+        in_reply_to,
+        reply_sender_name,
+        reply_body: reply_body_text,
+        reply_msg_type: None,
+        reply_thumbnail: None,
+        reply_media_encrypted: false,
+        thread_root,
+        session_id: None,
+    })))
+}
+
+/// Convert fetched timeline events into messages, applying the edits among
+/// them. Undecryptable events become placeholders and are noted in `pending`
+/// for a retry once their key arrives. Returns the messages in the order
+/// given and the number of edits applied.
+fn collect_messages(
+    account: &str,
+    room_id: &str,
+    events: &[matrix_sdk::deserialized_responses::TimelineEvent],
+    pending: Option<&std::sync::Mutex<UndecryptedEvents>>,
+) -> (Vec<Message>, usize) {
+    let mut messages = Vec::new();
+    let mut edits: std::collections::HashMap<String, MessageEditEvent> = std::collections::HashMap::new();
+
+    for event in events {
+        if let matrix_sdk::deserialized_responses::TimelineEventKind::UnableToDecrypt { event: raw, utd_info } = &event.kind {
+            let Ok(encrypted) = raw.deserialize() else {
+                continue;
+            };
+            let message = undecryptable_message(
+                account,
+                room_id,
+                encrypted.event_id(),
+                encrypted.sender(),
+                encrypted.origin_server_ts(),
+                utd_info.session_id.clone(),
+            );
+            if let (Some(pending), Some(session_id)) = (pending, &message.session_id) {
+                pending.lock().unwrap().insert(session_id, room_id, &message.event_id);
+            }
+            messages.push(message);
+            continue;
+        }
+
+        if let Ok(matrix_sdk::ruma::events::AnySyncTimelineEvent::MessageLike(
+            matrix_sdk::ruma::events::AnySyncMessageLikeEvent::RoomMessage(msg),
+        )) = event.raw().deserialize()
+        {
+            let Some(original) = msg.as_original() else {
+                continue;
+            };
+            // Edits are collected and applied below rather than shown themselves
+            match message_update(account, room_id, original, Some(event.raw().json().get())) {
+                Some(MessageUpdate::New(message)) => messages.push(*message),
+                Some(MessageUpdate::Edit(edit)) => {
+                    edits.insert(edit.original_event_id.clone(), edit);
+                }
+                None => {}
+            }
+        }
+    }
+
+    // Apply edits to original messages
+    for msg in &mut messages {
+        if let Some(edit) = edits.get(&msg.event_id) {
+            msg.body = edit.new_body.clone();
+            msg.formatted_body = edit.new_formatted_body.clone();
+        }
+    }
+
+    (messages, edits.len())
+}
+
+/// Fill in what each reply answers from the replied-to event itself, taken
+/// from `cache`, else the SDK's event cache or the server. Messages whose
+/// target can't be loaded keep what their reply fallback quoted.
+async fn resolve_replies(
+    room: &matrix_sdk::Room,
+    account: &str,
+    cache: &std::sync::Mutex<ReplyCache>,
+    messages: &mut [Message],
+) {
+    let room_id = room.room_id().as_str();
+    let mut missing: Vec<String> = messages
+        .iter()
+        .filter_map(|msg| msg.in_reply_to.clone())
+        .filter(|event_id| cache.lock().unwrap().get(room_id, event_id).is_none())
+        .collect();
+    missing.sort();
+    missing.dedup();
+
+    let fetched = futures_util::future::join_all(
+        missing.iter().map(|event_id| fetch_reply_preview(room, account, event_id)),
+    )
+    .await;
+
+    let mut found: std::collections::HashMap<&str, ReplyPreview> = std::collections::HashMap::new();
+    for (event_id, preview) in missing.iter().zip(fetched) {
+        let Some(preview) = preview else {
+            continue;
+        };
+        // Undecryptable targets are looked up again once they might decrypt
+        if preview.msg_type != "undecryptable" {
+            cache.lock().unwrap().insert(room_id, event_id, preview.clone());
+        }
+        found.insert(event_id, preview);
+    }
+
+    for msg in messages.iter_mut() {
+        let Some(event_id) = msg.in_reply_to.as_deref() else {
+            continue;
+        };
+        let preview = found.get(event_id).cloned().or_else(|| cache.lock().unwrap().get(room_id, event_id));
+        if let Some(preview) = preview {
+            msg.reply_sender_name = Some(preview.sender_name);
+            msg.reply_body = Some(preview.body);
+            msg.reply_msg_type = Some(preview.msg_type);
+            msg.reply_thumbnail = preview.thumbnail;
+            msg.reply_media_encrypted = preview.media_encrypted;
+        }
+    }
+}
+
+/// Load a replied-to event and summarize it; `None` if it can't be loaded or
+/// isn't a message.
+async fn fetch_reply_preview(room: &matrix_sdk::Room, account: &str, event_id: &str) -> Option<ReplyPreview> {
+    let event_id = matrix_sdk::ruma::OwnedEventId::try_from(event_id).ok()?;
+    let event = room.load_or_fetch_event(&event_id, None).await.ok()?;
+    let message = collect_messages(account, room.room_id().as_str(), std::slice::from_ref(&event), None).0.pop()?;
+
+    let thumbnail = match event.raw().deserialize() {
+        Ok(matrix_sdk::ruma::events::AnySyncTimelineEvent::MessageLike(
+            matrix_sdk::ruma::events::AnySyncMessageLikeEvent::RoomMessage(msg),
+        )) => msg.as_original().and_then(|original| message_thumbnail_source(&original.content.msgtype)).cloned(),
+        _ => None,
+    };
+
+    let sender = matrix_sdk::ruma::OwnedUserId::try_from(message.sender.as_str()).ok()?;
+    let sender_name = match room.get_member_no_sync(&sender).await {
+        Ok(Some(member)) => member.display_name().unwrap_or(sender.localpart()).to_string(),
+        _ => message.sender_name,
+    };
+
+    Some(ReplyPreview {
+        sender_name,
+        body: message.body,
+        msg_type: message.msg_type,
+        thumbnail: thumbnail.as_ref().and_then(media_source_to_mxc),
+        media_encrypted: matches!(thumbnail, Some(matrix_sdk::ruma::events::room::MediaSource::Encrypted(_))),
+    })
+}
+
+/// Placeholder for an encrypted event we don't have the room key for yet.
+fn undecryptable_message(
+    account: &str,
+    room_id: &str,
+    event_id: &matrix_sdk::ruma::EventId,
+    sender: &matrix_sdk::ruma::UserId,
+    timestamp: matrix_sdk::ruma::MilliSecondsSinceUnixEpoch,
+    session_id: Option<String>,
+) -> Message {
+    Message {
+        account: account.to_string(),
+        room_id: room_id.to_string(),
+        event_id: event_id.to_string(),
+        sender: sender.to_string(),
+        sender_name: sender.localpart().to_string(),
+        body: "Unable to decrypt this message".to_string(),
+        formatted_body: None,
+        timestamp: timestamp.as_secs().into(),
+        msg_type: "undecryptable".to_string(),
+        media_url: None,
+        media_encrypted: false,
+        filename: None,
+        in_reply_to: None,
+        reply_sender_name: None,
+        reply_body: None,
+        reply_msg_type: None,
+        reply_thumbnail: None,
+        reply_media_encrypted: false,
+        thread_root: None,
+        session_id,
+    }
+}
+
+/// Fetch an undecryptable event again after its room key arrived and send the
+/// frontend what it turned out to be. Still-undecryptable events go back to
+/// `pending`.
+#[allow(clippy::too_many_arguments)]
+async fn retry_decryption(
+    client: &Client,
+    app: &tauri::AppHandle,
+    log: &ServerLog,
+    account: &str,
+    pending: &std::sync::Mutex<UndecryptedEvents>,
+    replies: &std::sync::Mutex<ReplyCache>,
+    session_id: &str,
+    room_id: &str,
+    event_id: &str,
+) {
+    let (Ok(room_id_parsed), Ok(event_id_parsed)) = (
+        matrix_sdk::ruma::OwnedRoomId::try_from(room_id),
+        matrix_sdk::ruma::OwnedEventId::try_from(event_id),
+    ) else {
+        return;
+    };
+    let Some(room) = client.get_room(&room_id_parsed) else {
+        return;
+    };
+    let event = match room.event(&event_id_parsed, None).await {
+        Ok(event) => event,
+        Err(e) => {
+            slog(app, log, "warn", format!("Failed to refetch {} for decryption: {}", event_id, e));
+            pending.lock().unwrap().insert(session_id, room_id, event_id);
+            return;
+        }
+    };
+    if let matrix_sdk::deserialized_responses::TimelineEventKind::UnableToDecrypt { .. } = &event.kind {
+        pending.lock().unwrap().insert(session_id, room_id, event_id);
+        return;
+    }
+    slog(app, log, "info", format!("Decrypted {} in {} after its room key arrived", event_id, room_id));
+
+    let update = match event.raw().deserialize() {
+        Ok(matrix_sdk::ruma::events::AnySyncTimelineEvent::MessageLike(
+            matrix_sdk::ruma::events::AnySyncMessageLikeEvent::RoomMessage(msg),
+        )) => msg
+            .as_original()
+            .and_then(|original| message_update(account, room_id, original, Some(event.raw().json().get()))),
+        _ => None,
+    };
+    let placeholder_gone = MessageDeletedEvent {
+        account: account.to_string(),
+        room_id: room_id.to_string(),
+        event_id: event_id.to_string(),
+    };
+    match update {
+        Some(MessageUpdate::New(mut message)) => {
+            resolve_replies(&room, account, replies, std::slice::from_mut(&mut *message)).await;
+            let _ = app.emit("message_decrypted", &message);
+        }
+        Some(MessageUpdate::Edit(edit)) => {
+            replies.lock().unwrap().remove(room_id, &edit.original_event_id);
+            let _ = app.emit("message_deleted", &placeholder_gone);
+            let _ = app.emit("message_edited", &edit);
+        }
+        // Not something we show as a message (e.g. a reaction)
+        None => {
+            let _ = app.emit("message_deleted", &placeholder_gone);
+        }
+    }
+}
+
 /// Fetch an mxc:// avatar as a base64 data URL using authenticated media endpoints.
 /// Tries the authenticated endpoint first (_matrix/client/v1/media), then falls back
 /// to the unauthenticated one (_matrix/media/v3).
@@ -266,1313 +688,2852 @@ pub async fn get_server_log(
     Ok(state.log.get_all())
 }
 
-#[tauri::command]
-pub async fn matrix_login(
-    credentials: LoginCredentials,
-    app: tauri::AppHandle,
-    state: State<'_, MatrixState>,
-) -> Result<String, String> {
-    let log = state.log.clone();
-    slog(&app, &log, "info", format!("Logging in as {} to {}", credentials.username, credentials.homeserver));
-
-    let server_name = ServerName::parse(&credentials.homeserver.replace("https://", ""))
-        .map_err(|e| {
-            slog(&app, &log, "error", format!("Invalid homeserver: {}", e));
-            format!("Invalid homeserver: {}", e)
-        })?;
-
-    let data_path = crate::matrix_client::data_dir()?;
-    // Fresh login — clear stale sqlite stores to avoid device ID mismatches
-    if data_path.exists() {
-        slog(&app, &log, "info", "Clearing old sqlite store for fresh login...".into());
-        let _ = std::fs::remove_dir_all(&data_path);
+/// Parse the homeserver and build an in-memory client for the login request.
+/// The session it produces is moved onto the account's own sqlite-backed
+/// client by `add_account` once we know which user signed in.
+///
+/// A plain `http://` URL is used as-is instead of going through server name
+/// discovery, which lets the login flows run against local development or mock servers.
+async fn build_login_client(
+    homeserver: &str,
+    app: &tauri::AppHandle,
+    log: &std::sync::Arc<ServerLog>,
+) -> Result<Client, String> {
+    let mut builder = Client::builder();
+    if homeserver.starts_with("http://") {
+        builder = builder.homeserver_url(homeserver);
+    } else {
+        let server_name = ServerName::parse(homeserver.replace("https://", ""))
+            .map_err(|e| {
+                slog(app, log, "error", format!("Invalid homeserver: {}", e));
+                format!("Invalid homeserver: {}", e)
+            })?;
+        builder = builder.server_name(&server_name);
     }
-    std::fs::create_dir_all(&data_path)
-        .map_err(|e| format!("Failed to create data dir: {}", e))?;
 
-    slog(&app, &log, "info", "Building client with sqlite store...".into());
     let client = tokio::time::timeout(
         std::time::Duration::from_secs(60),
-        with_heartbeat(&app, &log, "Client build",
-            Client::builder()
-                .server_name(&server_name)
-                .sqlite_store(&data_path, None)
-                .build(),
-        ),
+        with_heartbeat(app, log, "Homeserver discovery", builder.build()),
     )
         .await
         .map_err(|_| {
-            slog(&app, &log, "error", "Client build timed out after 60s".into());
-            "Client build timed out — try restarting the app".to_string()
+            slog(app, log, "error", "Homeserver discovery timed out after 60s".into());
+            "Homeserver discovery timed out".to_string()
         })?
         .map_err(|e| {
-            slog(&app, &log, "error", format!("Failed to build client: {}", e));
+            slog(app, log, "error", format!("Failed to build client: {}", e));
             format!("Failed to build client: {}", e)
         })?;
 
-    slog(&app, &log, "info", format!("Resolved homeserver: {}", client.homeserver()));
-    slog(&app, &log, "info", "Sending login request...".into());
-
-    let response = tokio::time::timeout(
-        std::time::Duration::from_secs(30),
-        client
-            .matrix_auth()
-            .login_username(&credentials.username, &credentials.password)
-            .initial_device_display_name("icq26a")
-            .send(),
-    )
-        .await
-        .map_err(|_| {
-            slog(&app, &log, "error", "Login request timed out after 30s".into());
-            "Login timed out".to_string()
-        })?
-        .map_err(|e| {
-            slog(&app, &log, "error", format!("Login failed: {}", e));
-            format!("Login failed: {}", e)
-        })?;
-
-    let user_id = response.user_id.to_string();
-    slog(&app, &log, "info", format!("Login OK — user_id={}, device_id={}", user_id, response.device_id));
-
-    // Save session for restore on next launch
-    let session_data = PersistedSession {
-        homeserver_url: client.homeserver().to_string(),
-        user_id: response.user_id.to_string(),
-        device_id: response.device_id.to_string(),
-        access_token: response.access_token.clone(),
-        refresh_token: response.refresh_token.clone(),
-    };
-    let session_path = crate::matrix_client::session_file_path()?;
-    let json = serde_json::to_string_pretty(&session_data)
-        .map_err(|e| format!("Failed to serialize session: {}", e))?;
-    std::fs::write(&session_path, json)
-        .map_err(|e| format!("Failed to write session: {}", e))?;
-    slog(&app, &log, "info", "Session saved to disk".into());
-
-    let mut client_lock = state.client.lock().await;
-    *client_lock = Some(client);
-
-    Ok(user_id)
+    slog(app, log, "info", format!("Resolved homeserver: {}", client.homeserver()));
+    Ok(client)
 }
 
-#[tauri::command]
-pub async fn matrix_register(
-    credentials: LoginCredentials,
-    app: tauri::AppHandle,
-    state: State<'_, MatrixState>,
-) -> Result<String, String> {
-    let log = state.log.clone();
-    slog(&app, &log, "info", format!("Registering as {} on {}", credentials.username, credentials.homeserver));
-
-    let server_name = ServerName::parse(&credentials.homeserver.replace("https://", ""))
-        .map_err(|e| {
-            slog(&app, &log, "error", format!("Invalid homeserver: {}", e));
-            format!("Invalid homeserver: {}", e)
-        })?;
-
-    let data_path = crate::matrix_client::data_dir()?;
-    if data_path.exists() {
-        slog(&app, &log, "info", "Clearing old sqlite store for fresh registration...".into());
-        let _ = std::fs::remove_dir_all(&data_path);
-    }
-    std::fs::create_dir_all(&data_path)
-        .map_err(|e| format!("Failed to create data dir: {}", e))?;
-
-    slog(&app, &log, "info", "Building client with sqlite store...".into());
-    let client = tokio::time::timeout(
+/// Build a client backed by the sqlite store in an account's directory,
+/// encrypted with `store_passphrase` if one is given.
+async fn build_account_client(
+    homeserver_url: &str,
+    store_dir: &std::path::Path,
+    store_passphrase: Option<&str>,
+    app: &tauri::AppHandle,
+    log: &std::sync::Arc<ServerLog>,
+) -> Result<Client, String> {
+    slog(app, log, "info", "Building client with sqlite store...".into());
+    tokio::time::timeout(
         std::time::Duration::from_secs(60),
-        with_heartbeat(&app, &log, "Client build",
+        with_heartbeat(app, log, "Client build",
             Client::builder()
-                .server_name(&server_name)
-                .sqlite_store(&data_path, None)
+                .homeserver_url(homeserver_url)
+                .handle_refresh_tokens()
+                .sqlite_store(store_dir, store_passphrase)
                 .build(),
         ),
     )
         .await
         .map_err(|_| {
-            slog(&app, &log, "error", "Client build timed out after 60s".into());
+            slog(app, log, "error", "Client build timed out after 60s".into());
             "Client build timed out — try restarting the app".to_string()
         })?
         .map_err(|e| {
-            slog(&app, &log, "error", format!("Failed to build client: {}", e));
+            slog(app, log, "error", format!("Failed to build client: {}", e));
             format!("Failed to build client: {}", e)
-        })?;
-
-    let homeserver_url = client.homeserver().to_string();
-    slog(&app, &log, "info", format!("Resolved homeserver: {}", homeserver_url));
-    slog(&app, &log, "info", "Sending registration request...".into());
+        })
+}
 
-    // Use raw HTTP for the UIAA handshake — the SDK doesn't expose
-    // session/flows from UIAA errors, so we need direct control.
-    let register_url = format!("{}/_matrix/client/v3/register", homeserver_url.trim_end_matches('/'));
-    let body = serde_json::json!({
-        "username": credentials.username,
-        "password": credentials.password,
-        "kind": "user",
-        "initial_device_display_name": "icq26a"
-    });
+/// Move a freshly created session onto its own account: set aside that
+/// user's old store, restore the session on a sqlite-backed client, save it to disk and
+/// add the account to state. Used by every login and registration path.
+async fn add_account(
+    login_client: &Client,
+    mut session: PersistedSession,
+    app: &tauri::AppHandle,
+    log: &std::sync::Arc<ServerLog>,
+    state: &State<'_, MatrixState>,
+) -> Result<String, String> {
+    let user_id = session.user_id.clone();
+    if state.accounts.lock().await.contains_key(&user_id) {
+        slog(app, log, "warn", format!("{} is already signed in, discarding new session", user_id));
+        let _ = login_client.logout().await;
+        return Err(format!("{} is already signed in", user_id));
+    }
 
-    let http = reqwest::Client::new();
+    let store_dir = crate::matrix_client::account_dir(&user_id, &session.homeserver_url)?;
+    // Fresh login gets a new device — the old store belongs to the previous
+    // device, so keep it aside as a stale store instead of reusing it
+    if store_dir.exists() {
+        let retired = crate::matrix_client::retire_account_dir(&store_dir)?;
+        slog(app, log, "info", format!("Moved previous store for {} to {}", user_id, retired.display()));
+    }
+    std::fs::create_dir_all(&store_dir)
+        .map_err(|e| format!("Failed to create data dir: {}", e))?;
 
-    // Step 1: Initial request — expect 401 with UIAA flows + session
-    let resp = tokio::time::timeout(
-        std::time::Duration::from_secs(30),
-        http.post(&register_url).json(&body).send(),
-    )
+    session.store_passphrase = state
+        .secrets
+        .encrypt_stores()
+        .then(crate::store_encryption::generate_store_passphrase);
+    let client = build_account_client(
+        &session.homeserver_url,
+        &store_dir,
+        session.store_passphrase.as_deref(),
+        app,
+        log,
+    ).await?;
+    client
+        .restore_session(auth_session_from_persisted(session.clone())?)
         .await
-        .map_err(|_| {
-            slog(&app, &log, "error", "Registration request timed out after 30s".into());
-            "Registration timed out".to_string()
-        })?
         .map_err(|e| {
-            slog(&app, &log, "error", format!("Registration failed: {}", e));
-            format!("Registration failed: {}", e)
+            slog(app, log, "error", format!("Failed to restore session: {}", e));
+            format!("Login succeeded but session setup failed: {}", e)
         })?;
 
-    let status = resp.status();
-    let resp_body: serde_json::Value = resp.json().await
-        .map_err(|e| format!("Failed to parse response: {}", e))?;
+    // Save session for restore on next launch
+    save_session(&session, &state.secrets)?;
+    slog(app, log, "info", "Session saved to disk".into());
 
-    // If registration succeeded without UIAA (rare — some servers allow it)
-    if status.is_success() {
-        slog(&app, &log, "info", "Registration succeeded without UIAA".into());
-        return finish_registration(client, &resp_body, &app, &log, &state).await;
-    }
+    state.accounts.lock().await.insert(user_id.clone(), Account::new(client, session.store_passphrase));
+    Ok(user_id)
+}
 
-    // Not 401 → real error, not UIAA
-    if status.as_u16() != 401 {
-        let error_msg = resp_body["error"].as_str().unwrap_or("Registration failed");
-        slog(&app, &log, "error", format!("Registration failed: {}", error_msg));
-        return Err(format!("Registration failed: {}", error_msg));
-    }
+/// Write the session file that `try_restore_session` reads on the next launch.
+/// The tokens and store passphrase go to the secret store; the file only
+/// records which backend holds them.
+fn save_session(session: &PersistedSession, secrets: &SecretStore) -> Result<(), String> {
+    let account_dir = crate::matrix_client::account_dir(&session.user_id, &session.homeserver_url)?;
+    let backend = secrets.store(&session.user_id, &account_dir, &SessionSecrets {
+        access_token: session.access_token.clone(),
+        refresh_token: session.refresh_token.clone(),
+        store_passphrase: session.store_passphrase.clone(),
+    })?;
+    let on_disk = PersistedSession {
+        access_token: String::new(),
+        refresh_token: None,
+        secret_backend: Some(backend),
+        store_passphrase: None,
+        ..session.clone()
+    };
 
-    // 401 UIAA — check if any flow is just m.login.dummy
-    slog(&app, &log, "info", format!("UIAA response, flows: {}", resp_body["flows"]));
+    let session_path = account_dir.join("session.json");
+    let json = serde_json::to_string_pretty(&on_disk)
+        .map_err(|e| format!("Failed to serialize session: {}", e))?;
+    std::fs::write(&session_path, json)
+        .map_err(|e| format!("Failed to write session: {}", e))
+}
 
-    let session = resp_body["session"].as_str();
-    let has_dummy_flow = resp_body["flows"].as_array().map_or(false, |flows| {
-        flows.iter().any(|f| {
-            f["stages"].as_array().map_or(false, |stages| {
-                stages.len() == 1 && stages[0].as_str() == Some("m.login.dummy")
-            })
-        })
-    });
+/// Snapshot the client's current session (Matrix or OAuth) in the on-disk format.
+fn persisted_session_from_client(client: &Client, store_passphrase: Option<String>) -> Option<PersistedSession> {
+    let session = client.session()?;
+    let oauth_client_id = match &session {
+        matrix_sdk::AuthSession::OAuth(oauth) => Some(oauth.client_id.as_str().to_string()),
+        _ => None,
+    };
+    let meta = session.meta();
+    Some(PersistedSession {
+        homeserver_url: client.homeserver().to_string(),
+        user_id: meta.user_id.to_string(),
+        device_id: meta.device_id.to_string(),
+        access_token: session.access_token().to_string(),
+        refresh_token: session.get_refresh_token().map(|t| t.to_string()),
+        secret_backend: None,
+        store_passphrase,
+        oauth_client_id,
+    })
+}
 
-    if !has_dummy_flow {
-        slog(&app, &log, "warn", "Server requires auth flows we can't handle".into());
-        return Err(format!(
-            "This server requires additional verification steps (e.g. email or captcha). Please register at {} in your browser.",
-            credentials.homeserver
-        ));
-    }
+/// Rebuild the SDK session from the on-disk format.
+fn auth_session_from_persisted(saved: PersistedSession) -> Result<matrix_sdk::AuthSession, String> {
+    let meta = matrix_sdk::SessionMeta {
+        user_id: matrix_sdk::ruma::UserId::parse(&saved.user_id)
+            .map_err(|e| format!("Invalid user_id: {}", e))?,
+        device_id: saved.device_id.as_str().into(),
+    };
+    let tokens = matrix_sdk::SessionTokens {
+        access_token: saved.access_token,
+        refresh_token: saved.refresh_token,
+    };
+    Ok(match saved.oauth_client_id {
+        Some(client_id) => matrix_sdk::authentication::oauth::OAuthSession {
+            client_id: matrix_sdk::authentication::oauth::ClientId::new(client_id),
+            user: matrix_sdk::authentication::oauth::UserSession { meta, tokens },
+        }
+        .into(),
+        None => matrix_sdk::authentication::matrix::MatrixSession { meta, tokens }.into(),
+    })
+}
 
-    // Step 2: Retry with m.login.dummy auth + session
-    slog(&app, &log, "info", "Retrying registration with m.login.dummy auth...".into());
+/// Name for a new session, so it can be told apart in the device list.
+fn device_display_name() -> String {
+    let host = std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .ok()
+        .or_else(|| {
+            let output = std::process::Command::new("hostname").output().ok()?;
+            Some(String::from_utf8_lossy(&output.stdout).into_owned())
+        });
+    device_display_name_for(host.as_deref(), std::env::consts::OS)
+}
 
-    let mut retry_body = body.clone();
-    let mut auth = serde_json::json!({"type": "m.login.dummy"});
-    if let Some(s) = session {
-        auth["session"] = serde_json::Value::String(s.to_string());
+fn device_display_name_for(host: Option<&str>, os: &str) -> String {
+    match host.map(str::trim).filter(|h| !h.is_empty()) {
+        Some(host) => format!("ICQ26a on {}", host),
+        None => format!("ICQ26a ({})", os),
     }
-    retry_body["auth"] = auth;
+}
 
-    let resp = tokio::time::timeout(
-        std::time::Duration::from_secs(30),
-        http.post(&register_url).json(&retry_body).send(),
-    )
-        .await
-        .map_err(|_| {
-            slog(&app, &log, "error", "Registration retry timed out".into());
-            "Registration timed out".to_string()
-        })?
-        .map_err(|e| {
-            slog(&app, &log, "error", format!("Registration retry failed: {}", e));
-            format!("Registration failed: {}", e)
-        })?;
+/// Client metadata sent during OAuth 2.0 dynamic client registration.
+fn oauth_client_metadata(
+    redirect_uri: &reqwest::Url,
+) -> Result<matrix_sdk::ruma::serde::Raw<matrix_sdk::authentication::oauth::registration::ClientMetadata>, String> {
+    use matrix_sdk::authentication::oauth::registration::{
+        ApplicationType, ClientMetadata, Localized, OAuthGrantType,
+    };
 
-    if !resp.status().is_success() {
-        let error_body: serde_json::Value = resp.json().await
-            .map_err(|e| format!("Failed to parse error: {}", e))?;
-        let error_msg = error_body["error"].as_str().unwrap_or("Registration failed");
-        slog(&app, &log, "error", format!("Registration failed after UIAA: {}", error_msg));
-        return Err(format!("Registration failed: {}", error_msg));
-    }
+    let client_uri = reqwest::Url::parse("https://github.com/fredsmith/icq26a")
+        .map_err(|e| format!("Invalid client URI: {}", e))?;
+    let mut metadata = ClientMetadata::new(
+        ApplicationType::Native,
+        vec![OAuthGrantType::AuthorizationCode {
+            redirect_uris: vec![redirect_uri.clone()],
+        }],
+        Localized::new(client_uri, []),
+    );
+    metadata.client_name = Some(Localized::new("ICQ26a".to_string(), []));
 
-    let resp_body: serde_json::Value = resp.json().await
-        .map_err(|e| format!("Failed to parse response: {}", e))?;
-
-    slog(&app, &log, "info", "Registration succeeded after UIAA dummy auth".into());
-    finish_registration(client, &resp_body, &app, &log, &state).await
+    matrix_sdk::ruma::serde::Raw::new(&metadata)
+        .map_err(|e| format!("Failed to serialize client metadata: {}", e))
 }
 
-/// Post-registration: restore session on SDK client, save to disk, store in state.
-async fn finish_registration(
+/// Add the account for the session from a `/login` response.
+async fn finish_login(
     client: Client,
-    resp: &serde_json::Value,
+    response: &matrix_sdk::ruma::api::client::session::login::v3::Response,
     app: &tauri::AppHandle,
     log: &std::sync::Arc<ServerLog>,
     state: &State<'_, MatrixState>,
 ) -> Result<String, String> {
-    let user_id = resp["user_id"].as_str()
-        .ok_or("Registration response missing user_id")?;
-    let access_token = resp["access_token"].as_str()
-        .ok_or("Registration response missing access_token")?;
-    let device_id = resp["device_id"].as_str()
-        .ok_or("Registration response missing device_id")?;
-    let refresh_token = resp["refresh_token"].as_str().map(|s| s.to_string());
-
-    slog(app, log, "info", format!("Registered user_id={}, device_id={}", user_id, device_id));
-
-    // Restore session on SDK client so it's authenticated for sync, etc.
-    let session = matrix_sdk::authentication::matrix::MatrixSession {
-        meta: matrix_sdk::SessionMeta {
-            user_id: matrix_sdk::ruma::UserId::parse(user_id)
-                .map_err(|e| format!("Invalid user_id: {}", e))?,
-            device_id: device_id.into(),
-        },
-        tokens: matrix_sdk::SessionTokens {
-            access_token: access_token.to_string(),
-            refresh_token: refresh_token.clone(),
-        },
-    };
-    client.restore_session(session).await.map_err(|e| {
-        slog(app, log, "error", format!("Failed to restore session: {}", e));
-        format!("Registration succeeded but session setup failed: {}", e)
-    })?;
+    let user_id = response.user_id.to_string();
+    slog(app, log, "info", format!("Login OK — user_id={}, device_id={}", user_id, response.device_id));
 
-    // Save session for restore on next launch
-    let session_data = PersistedSession {
+    let session = PersistedSession {
         homeserver_url: client.homeserver().to_string(),
-        user_id: user_id.to_string(),
-        device_id: device_id.to_string(),
-        access_token: access_token.to_string(),
-        refresh_token,
+        user_id,
+        device_id: response.device_id.to_string(),
+        access_token: response.access_token.clone(),
+        refresh_token: response.refresh_token.clone(),
+        secret_backend: None,
+        store_passphrase: None,
+        oauth_client_id: None,
     };
-    let session_path = crate::matrix_client::session_file_path()?;
-    let json = serde_json::to_string_pretty(&session_data)
-        .map_err(|e| format!("Failed to serialize session: {}", e))?;
-    std::fs::write(&session_path, json)
-        .map_err(|e| format!("Failed to write session: {}", e))?;
-    slog(app, log, "info", "Session saved to disk".into());
-
-    let mut client_lock = state.client.lock().await;
-    *client_lock = Some(client);
-
-    Ok(user_id.to_string())
+    add_account(&client, session, app, log, state).await
 }
 
+/// Find out what a homeserver supports before the user tries it. With
+/// `account` the signed-in client is used, which also covers `/capabilities`
+/// and the media config; otherwise `homeserver` is resolved through
+/// `.well-known` as at login.
 #[tauri::command]
-pub async fn matrix_logout(
+pub async fn discover_server(
+    homeserver: Option<String>,
+    account: Option<String>,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
-) -> Result<(), String> {
+) -> Result<ServerCapabilities, String> {
+    use matrix_sdk::ruma::api::client::discovery::get_supported_versions;
+    use matrix_sdk::ruma::api::client::profile::ProfileFieldName;
+    use matrix_sdk::ruma::api::client::session::get_login_types::v3::LoginType;
+
     let log = state.log.clone();
-    slog(&app, &log, "info", "Logging out...".into());
+    let (client, signed_in) = match (account, homeserver) {
+        (Some(account), _) => (state.client(&account).await?, true),
+        (None, Some(homeserver)) => (build_login_client(&homeserver, &app, &log).await?, false),
+        (None, None) => return Err("No homeserver given".to_string()),
+    };
+    let homeserver_url = client.homeserver().to_string();
+    slog(&app, &log, "info", format!("Discovering capabilities of {}", homeserver_url));
+
+    // An empty registration request is answered with the UIAA flows
+    let register_url = format!("{}/_matrix/client/v3/register", homeserver_url.trim_end_matches('/'));
+    let register_probe = async {
+        let resp = reqwest::Client::new()
+            .post(&register_url)
+            .json(&serde_json::json!({}))
+            .send()
+            .await?;
+        let status = resp.status();
+        Ok::<_, reqwest::Error>((status, resp.json::<serde_json::Value>().await?))
+    };
+    let (matrix_auth, oauth) = (client.matrix_auth(), client.oauth());
+    let (versions, login_types, oauth, registration) = tokio::join!(
+        discovery_step("Versions", client.send(get_supported_versions::Request::new()), &app, &log),
+        discovery_step("Login flows", matrix_auth.get_login_types(), &app, &log),
+        discovery_step("OAuth metadata", oauth.server_metadata(), &app, &log),
+        discovery_step("Registration", register_probe, &app, &log),
+    );
 
-    state.abort_sync_tasks();
+    let mut caps = ServerCapabilities {
+        homeserver_url,
+        oauth: oauth.is_some(),
+        registration_flows: registration.and_then(|(status, body)| registration_flows(status, &body)),
+        ..Default::default()
+    };
+    if let Some(versions) = versions {
+        caps.versions = versions.versions;
+        caps.unstable_features = versions
+            .unstable_features
+            .into_iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(feature, _)| feature)
+            .collect();
+    }
+    for flow in login_types.map(|l| l.flows).unwrap_or_default() {
+        caps.login_flows.push(flow.login_type().to_string());
+        if let LoginType::Sso(sso) = flow {
+            caps.identity_providers.extend(sso.identity_providers.into_iter().map(|p| IdentityProviderInfo {
+                id: p.id,
+                name: p.name,
+                brand: p.brand.map(|b| b.as_str().to_string()),
+            }));
+        }
+    }
 
-    let mut client_lock = state.client.lock().await;
-    if let Some(client) = client_lock.as_ref() {
-        let _ = client.matrix_auth().logout().await;
+    // Both need an access token
+    if signed_in {
+        let (capabilities, max_upload_size) = tokio::join!(
+            discovery_step("Capabilities", client.get_capabilities(), &app, &log),
+            discovery_step("Media config", client.load_or_fetch_max_upload_size(), &app, &log),
+        );
+        if let Some(c) = capabilities {
+            caps.change_password = Some(c.change_password.enabled);
+            // Servers on Matrix 1.16 report profile fields instead
+            #[allow(deprecated)]
+            let (displayname, avatar_url) = match &c.profile_fields {
+                Some(fields) => (
+                    fields.can_set_field(&ProfileFieldName::DisplayName),
+                    fields.can_set_field(&ProfileFieldName::AvatarUrl),
+                ),
+                None => (c.set_displayname.enabled, c.set_avatar_url.enabled),
+            };
+            caps.set_displayname = Some(displayname);
+            caps.set_avatar_url = Some(avatar_url);
+            caps.default_room_version = Some(c.room_versions.default.to_string());
+        }
+        caps.max_upload_size = max_upload_size.map(u64::from);
     }
-    *client_lock = None;
 
-    if let Ok(path) = crate::matrix_client::session_file_path() {
-        let _ = std::fs::remove_file(path);
+    slog(&app, &log, "info", format!(
+        "{}: versions {:?}, login {:?}, oauth={}, registration={}",
+        caps.homeserver_url,
+        caps.versions,
+        caps.login_flows,
+        caps.oauth,
+        caps.registration_flows.is_some(),
+    ));
+    Ok(caps)
+}
+
+/// Run one discovery request. A server that lacks the endpoint or doesn't
+/// answer in time just doesn't get the feature, so failures are only logged.
+async fn discovery_step<T, E: std::fmt::Display>(
+    what: &str,
+    request: impl std::future::IntoFuture<Output = Result<T, E>>,
+    app: &tauri::AppHandle,
+    log: &std::sync::Arc<ServerLog>,
+) -> Option<T> {
+    match tokio::time::timeout(std::time::Duration::from_secs(15), request.into_future()).await {
+        Ok(Ok(value)) => Some(value),
+        Ok(Err(e)) => {
+            slog(app, log, "info", format!("{} not available: {}", what, e));
+            None
+        }
+        Err(_) => {
+            slog(app, log, "warn", format!("{} request timed out", what));
+            None
+        }
     }
+}
 
-    slog(&app, &log, "info", "Logged out, session file deleted".into());
-    Ok(())
+/// Registration flows from the answer to an empty `/register` request, or
+/// `None` if the server refused it (closed, or left to an OAuth provider).
+fn registration_flows(status: reqwest::StatusCode, body: &serde_json::Value) -> Option<Vec<Vec<String>>> {
+    if status != reqwest::StatusCode::UNAUTHORIZED {
+        return None;
+    }
+    let info = UiaaInfo::parse(body)?;
+    Some(info.flows.into_iter().map(|flow| flow.stages).collect())
 }
 
 #[tauri::command]
-pub async fn matrix_disconnect(
+pub async fn matrix_login(
+    credentials: LoginCredentials,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
-) -> Result<(), String> {
+) -> Result<String, String> {
     let log = state.log.clone();
-    slog(&app, &log, "info", "Disconnecting (keeping session)...".into());
+    slog(&app, &log, "info", format!("Logging in as {} to {}", credentials.username, credentials.homeserver));
 
-    state.abort_sync_tasks();
+    let client = build_login_client(&credentials.homeserver, &app, &log).await?;
 
-    let mut client_lock = state.client.lock().await;
-    *client_lock = None;
+    slog(&app, &log, "info", "Sending login request...".into());
 
-    slog(&app, &log, "info", "Disconnected, session file preserved".into());
-    Ok(())
+    let response = tokio::time::timeout(
+        std::time::Duration::from_secs(30),
+        client
+            .matrix_auth()
+            .login_username(&credentials.username, &credentials.password)
+            .initial_device_display_name(&device_display_name())
+            .request_refresh_token()
+            .send(),
+    )
+        .await
+        .map_err(|_| {
+            slog(&app, &log, "error", "Login request timed out after 30s".into());
+            "Login timed out".to_string()
+        })?
+        .map_err(|e| {
+            slog(&app, &log, "error", format!("Login failed: {}", e));
+            format!("Login failed: {}", e)
+        })?;
+
+    let user_id = finish_login(client, &response, &app, &log, &state).await?;
+    // Set up cross-signing for a new account while we still have the password
+    offer_cross_signing(&user_id, Some(credentials.password), &app, &log, &state).await;
+    Ok(user_id)
 }
 
+/// Log in through the homeserver's single sign-on flow.
+///
+/// The identity provider page is opened in the system browser (the frontend
+/// receives its URL via the `sso_login_url` event) and the SDK's loopback
+/// listener catches the `loginToken` redirect.
 #[tauri::command]
-pub async fn try_restore_session(
+pub async fn matrix_login_sso(
+    homeserver: String,
+    idp_id: Option<String>,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
 ) -> Result<String, String> {
-    let log = state.log.clone();
-    let session_path = crate::matrix_client::session_file_path()?;
+    use matrix_sdk::ruma::api::client::session::get_login_types::v3::LoginType;
 
-    if !session_path.exists() {
-        slog(&app, &log, "info", "No saved session found".into());
-        return Err("No saved session".to_string());
-    }
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("Logging in via SSO to {} (idp={:?})", homeserver, idp_id));
 
-    slog(&app, &log, "info", "Restoring saved session...".into());
+    let client = build_login_client(&homeserver, &app, &log).await?;
 
-    let json = std::fs::read_to_string(&session_path)
-        .map_err(|e| format!("Failed to read session: {}", e))?;
-    let saved: PersistedSession = serde_json::from_str(&json)
+    let login_types = tokio::time::timeout(
+        std::time::Duration::from_secs(15),
+        client.matrix_auth().get_login_types(),
+    )
+        .await
+        .map_err(|_| "Timed out fetching login flows".to_string())?
         .map_err(|e| {
-            slog(&app, &log, "error", format!("Corrupt session file: {}", e));
-            format!("Failed to parse session: {}", e)
+            slog(&app, &log, "error", format!("Failed to fetch login flows: {}", e));
+            format!("Failed to fetch login flows: {}", e)
         })?;
 
-    slog(&app, &log, "info", format!("Session file: user={}, homeserver={}", saved.user_id, saved.homeserver_url));
+    let sso = login_types
+        .flows
+        .iter()
+        .find_map(|flow| match flow {
+            LoginType::Sso(sso) => Some(sso),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            slog(&app, &log, "warn", "Server does not offer m.login.sso".into());
+            "This server does not support single sign-on".to_string()
+        })?;
 
-    let data_path = crate::matrix_client::data_dir()?;
+    if let Some(idp) = &idp_id {
+        if !sso.identity_providers.iter().any(|p| &p.id == idp) {
+            return Err(format!("Unknown identity provider: {}", idp));
+        }
+    }
+    slog(&app, &log, "info", format!(
+        "SSO offered with {} identity provider(s)",
+        sso.identity_providers.len()
+    ));
+
+    let url_app = app.clone();
+    let url_log = log.clone();
+    let mut builder = client
+        .matrix_auth()
+        .login_sso(move |sso_url| async move {
+            slog(&url_app, &url_log, "info", "Opening identity provider in browser...".into());
+            let _ = url_app.emit("sso_login_url", &sso_url);
+            Ok(())
+        })
+        .initial_device_display_name(&device_display_name())
+        .request_refresh_token();
+    if let Some(idp) = &idp_id {
+        builder = builder.identity_provider_id(idp);
+    }
 
-    let client = tokio::time::timeout(
-        std::time::Duration::from_secs(60),
-        with_heartbeat(&app, &log, "Client build",
-            Client::builder()
-                .homeserver_url(&saved.homeserver_url)
-                .sqlite_store(&data_path, None)
-                .build(),
-        ),
-    )
+    // Give the user time to finish signing in at the identity provider
+    let response = tokio::time::timeout(std::time::Duration::from_secs(300), builder.send())
         .await
         .map_err(|_| {
-            slog(&app, &log, "error", "Client build timed out after 60s".into());
-            "Session restore timed out — try restarting the app".to_string()
+            slog(&app, &log, "error", "SSO login timed out after 5 minutes".into());
+            "SSO login timed out".to_string()
         })?
         .map_err(|e| {
-            slog(&app, &log, "error", format!("Failed to build client: {}", e));
-            format!("Failed to build client: {}", e)
-        })?;
-
-    let session = matrix_sdk::authentication::matrix::MatrixSession {
-        meta: matrix_sdk::SessionMeta {
-            user_id: matrix_sdk::ruma::UserId::parse(&saved.user_id)
-                .map_err(|e| format!("Invalid user_id: {}", e))?,
-            device_id: saved.device_id.as_str().into(),
-        },
-        tokens: matrix_sdk::SessionTokens {
-            access_token: saved.access_token,
-            refresh_token: saved.refresh_token,
-        },
-    };
-
-    client
-        .restore_session(session)
-        .await
-        .map_err(|e| {
-            slog(&app, &log, "error", format!("Session restore failed: {}", e));
-            format!("Failed to restore session: {}", e)
+            slog(&app, &log, "error", format!("SSO login failed: {}", e));
+            format!("SSO login failed: {}", e)
         })?;
 
-    slog(&app, &log, "info", format!("Session restored — user={}", saved.user_id));
-
-    let user_id = saved.user_id;
-
-    let mut client_lock = state.client.lock().await;
-    *client_lock = Some(client);
-
+    let user_id = finish_login(client, &response, &app, &log, &state).await?;
+    offer_cross_signing(&user_id, None, &app, &log, &state).await;
     Ok(user_id)
 }
 
+/// Log in through the homeserver's OAuth 2.0 provider (MSC3861 next-gen auth).
+///
+/// Registers the app dynamically, then runs the authorization code flow with
+/// PKCE. The authorization page opens in the system browser (again via the
+/// `sso_login_url` event) and redirects back to a loopback listener.
 #[tauri::command]
-pub async fn get_user_profile(
-    user_id: String,
+pub async fn matrix_login_oauth(
+    homeserver: String,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
-) -> Result<UserProfile, String> {
+) -> Result<String, String> {
+    use matrix_sdk::utils::local_server::LocalServerBuilder;
+
     let log = state.log.clone();
-    slog(&app, &log, "info", format!("get_user_profile: {}", user_id));
+    slog(&app, &log, "info", format!("Logging in via OAuth 2.0 to {}", homeserver));
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    let client = build_login_client(&homeserver, &app, &log).await?;
 
-    let parsed_user_id = matrix_sdk::ruma::UserId::parse(&user_id)
-        .map_err(|e| format!("Invalid user ID: {}", e))?;
+    let (redirect_uri, redirect_handle) = LocalServerBuilder::new()
+        .spawn()
+        .await
+        .map_err(|e| format!("Failed to start redirect listener: {}", e))?;
+    slog(&app, &log, "info", format!("Listening for OAuth redirect on {}", redirect_uri));
 
-    // Fetch profile (display name + avatar)
-    let mut display_name = user_id.clone();
-    let mut avatar_url: Option<String> = None;
-    let mut mxc_avatar: Option<String> = None;
-    {
-        use matrix_sdk::ruma::api::client::profile::get_profile;
-        use matrix_sdk::ruma::api::client::profile::{AvatarUrl, DisplayName};
-        let request = get_profile::v3::Request::new(parsed_user_id.clone());
-        match tokio::time::timeout(std::time::Duration::from_secs(5), client.send(request)).await {
-            Ok(Ok(response)) => {
-                if let Ok(Some(name)) = response.get_static::<DisplayName>() {
-                    display_name = name;
-                }
-                if let Ok(Some(url)) = response.get_static::<AvatarUrl>() {
-                    mxc_avatar = Some(url.to_string());
-                }
-            }
-            Ok(Err(e)) => {
-                slog(&app, &log, "warn", format!("Profile fetch failed: {}", e));
-            }
-            Err(_) => {
-                slog(&app, &log, "warn", "Profile fetch timed out".into());
-            }
-        }
-    }
+    let auth_data = start_oauth_login(&client, redirect_uri).await.map_err(|e| {
+        slog(&app, &log, "error", format!("OAuth authorization setup failed: {}", e));
+        e
+    })?;
 
-    // Download avatar via authenticated media endpoint → base64 data URL
-    if let Some(mxc) = &mxc_avatar {
-        avatar_url = fetch_avatar_data_url(client, mxc).await;
-    }
+    slog(&app, &log, "info", "Opening authorization page in browser...".into());
+    let _ = app.emit("sso_login_url", auth_data.url.as_str());
 
-    // Fetch presence + last_active_ago
-    let mut presence = "unknown".to_string();
-    let mut last_seen_ago: Option<u64> = None;
-    {
-        use matrix_sdk::ruma::api::client::presence::get_presence;
-        let request = get_presence::v3::Request::new(parsed_user_id.clone());
-        match tokio::time::timeout(std::time::Duration::from_secs(3), client.send(request)).await {
-            Ok(Ok(response)) => {
-                let is_stale = response.currently_active.is_none()
-                    && response.last_active_ago.is_none()
-                    && response.presence == matrix_sdk::ruma::presence::PresenceState::Offline;
-                if !is_stale {
-                    presence = match response.presence {
-                        matrix_sdk::ruma::presence::PresenceState::Online => "online",
-                        matrix_sdk::ruma::presence::PresenceState::Unavailable => "away",
-                        _ => "offline",
-                    }
-                    .to_string();
-                    last_seen_ago = response.last_active_ago.map(|d| d.as_secs());
-                }
-            }
-            _ => {}
+    // Give the user time to finish signing in at the provider
+    let query = match tokio::time::timeout(std::time::Duration::from_secs(300), redirect_handle).await {
+        Ok(Some(query)) => query,
+        Ok(None) | Err(_) => {
+            client.oauth().abort_login(&auth_data.state).await;
+            slog(&app, &log, "error", "OAuth login timed out waiting for redirect".into());
+            return Err("OAuth login timed out".to_string());
         }
-    }
+    };
 
-    // Scan joined rooms for shared membership
-    let mut shared_rooms = Vec::new();
-    for room in client.joined_rooms() {
-        let members = room
-            .members(matrix_sdk::RoomMemberships::ACTIVE)
-            .await
-            .unwrap_or_default();
-        let has_user = members.iter().any(|m| m.user_id() == parsed_user_id);
-        if has_user {
-            let is_direct = room.is_direct().await.unwrap_or(false);
-            let name = resolve_room_name(client, &room, is_direct).await;
-            shared_rooms.push(SharedRoom {
-                room_id: room.room_id().to_string(),
-                name,
-            });
-        }
-    }
+    let session_data = finish_oauth_login(&client, query.0).await.map_err(|e| {
+        slog(&app, &log, "error", format!("OAuth token exchange failed: {}", e));
+        e
+    })?;
+    let user_id = session_data.user_id.clone();
+    slog(&app, &log, "info", format!("OAuth login OK — user_id={}, device_id={}", user_id, session_data.device_id));
 
-    slog(
-        &app,
-        &log,
-        "info",
-        format!(
-            "get_user_profile: {} — presence={}, shared_rooms={}",
-            user_id,
-            presence,
-            shared_rooms.len()
-        ),
-    );
+    let user_id = add_account(&client, session_data, &app, &log, &state).await?;
+    offer_cross_signing(&user_id, None, &app, &log, &state).await;
+    Ok(user_id)
+}
 
-    Ok(UserProfile {
-        user_id,
-        display_name,
-        avatar_url,
-        presence,
-        last_seen_ago,
-        shared_rooms,
-    })
+/// Check that the homeserver delegates auth to an OAuth 2.0 provider, register
+/// the app with it and build the authorization URL for the browser.
+async fn start_oauth_login(
+    client: &Client,
+    redirect_uri: reqwest::Url,
+) -> Result<matrix_sdk::authentication::oauth::OAuthAuthorizationData, String> {
+    use matrix_sdk::authentication::oauth::ClientRegistrationData;
+
+    let oauth = client.oauth();
+    tokio::time::timeout(std::time::Duration::from_secs(15), oauth.server_metadata())
+        .await
+        .map_err(|_| "Timed out fetching OAuth server metadata".to_string())?
+        .map_err(|e| format!("This server does not support OAuth 2.0 login: {}", e))?;
+
+    let registration = ClientRegistrationData::new(oauth_client_metadata(&redirect_uri)?);
+    oauth
+        .login(redirect_uri, None, Some(registration), None)
+        .build()
+        .await
+        .map_err(|e| format!("OAuth login failed: {}", e))
+}
+
+/// Exchange the authorization code from the redirect's query string for
+/// tokens and return the resulting session.
+async fn finish_oauth_login(client: &Client, query: String) -> Result<PersistedSession, String> {
+    use matrix_sdk::authentication::oauth::UrlOrQuery;
+
+    client
+        .oauth()
+        .finish_login(UrlOrQuery::Query(query))
+        .await
+        .map_err(|e| format!("OAuth login failed: {}", e))?;
+    persisted_session_from_client(client, None)
+        .ok_or_else(|| "OAuth login finished without a session".to_string())
 }
 
+/// Registration stages the app completes itself; any other stage is done in
+/// the homeserver's fallback web page.
+const REGISTRATION_STAGES: &[&str] = &[
+    uiaa::DUMMY,
+    uiaa::TERMS,
+    uiaa::REGISTRATION_TOKEN,
+    uiaa::UNSTABLE_REGISTRATION_TOKEN,
+    uiaa::EMAIL_IDENTITY,
+];
+
+/// Start registering an account. Returns the new account if the server asks
+/// for nothing beyond `m.login.dummy`, otherwise the first stage the user has
+/// to complete through `matrix_register_stage`.
 #[tauri::command]
-pub async fn get_room_info(
-    room_id: String,
+pub async fn matrix_register(
+    credentials: LoginCredentials,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
-) -> Result<RoomProfile, String> {
+) -> Result<RegistrationStatus, String> {
     let log = state.log.clone();
-    slog(&app, &log, "info", format!("get_room_info: {}", room_id));
+    slog(&app, &log, "info", format!("Registering as {} on {}", credentials.username, credentials.homeserver));
+    *state.registration.lock().await = None;
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    let client = build_login_client(&credentials.homeserver, &app, &log).await?;
 
-    let room_id_parsed = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
-        .map_err(|e| format!("Invalid room ID: {}", e))?;
-    let room = client.get_room(&room_id_parsed).ok_or("Room not found")?;
+    let homeserver_url = client.homeserver().to_string();
+    slog(&app, &log, "info", "Sending registration request...".into());
 
-    let is_direct = room.is_direct().await.unwrap_or(false);
-    let name = resolve_room_name(client, &room, is_direct).await;
+    // Use raw HTTP for the UIAA handshake — the SDK doesn't expose
+    // session/flows from UIAA errors, so we need direct control.
+    let register_url = format!("{}/_matrix/client/v3/register", homeserver_url.trim_end_matches('/'));
+    let body = serde_json::json!({
+        "username": credentials.username,
+        "password": credentials.password,
+        "kind": "user",
+        "initial_device_display_name": device_display_name(),
+        "refresh_token": true
+    });
 
-    let topic = room.topic();
-
-    let members = room
-        .members(matrix_sdk::RoomMemberships::ACTIVE)
-        .await
-        .unwrap_or_default();
-    let member_count = members.len() as u64;
-
-    Ok(RoomProfile {
-        room_id,
-        name,
-        topic,
-        is_direct,
-        member_count,
-    })
-}
+    let (status, resp_body) = post_registration(&register_url, &body, &app, &log).await?;
+    let pending = PendingRegistration {
+        client,
+        register_url,
+        body,
+        uiaa: UiaaInfo::default(),
+        email: None,
+    };
+    advance_registration(pending, status, resp_body, &app, &log, &state).await
+}
 
+/// Answer the current registration stage and move on to the next one.
 #[tauri::command]
-pub async fn create_dm_room(
-    user_id: String,
+pub async fn matrix_register_stage(
+    response: RegistrationResponse,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
-) -> Result<Room, String> {
+) -> Result<RegistrationStatus, String> {
     let log = state.log.clone();
-    slog(&app, &log, "info", format!("create_dm_room: {}", user_id));
-
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
-
-    let parsed_user_id = matrix_sdk::ruma::UserId::parse(&user_id)
-        .map_err(|e| format!("Invalid user ID: {}", e))?;
-
-    use matrix_sdk::ruma::api::client::room::create_room::v3::Request as CreateRoomRequest;
-    let mut request = CreateRoomRequest::new();
-    request.invite = vec![parsed_user_id];
-    request.is_direct = true;
-    request.preset = Some(matrix_sdk::ruma::api::client::room::create_room::v3::RoomPreset::TrustedPrivateChat);
-
-    let response = client
-        .create_room(request)
+    let mut pending = state
+        .registration
+        .lock()
         .await
-        .map_err(|e| {
-            slog(&app, &log, "error", format!("Failed to create DM room: {}", e));
-            format!("Failed to create room: {}", e)
-        })?;
-
-    let room_id = response.room_id().to_string();
-    slog(&app, &log, "info", format!("DM room created: {}", room_id));
-
-    // Get the room to fetch its display name
-    let name = if let Some(room) = client.get_room(response.room_id()) {
-        room.display_name()
-            .await
-            .map(|n| n.to_string())
-            .unwrap_or_else(|_| user_id.clone())
-    } else {
-        user_id.clone()
+        .take()
+        .ok_or("No registration in progress")?;
+    let stage = pending.uiaa.next_stage(REGISTRATION_STAGES).unwrap_or_default();
+    slog(&app, &log, "info", format!("Registration stage {}: {:?}", stage, response));
+
+    let auth = match response {
+        RegistrationResponse::AcceptTerms => pending.uiaa.auth(uiaa::TERMS, serde_json::json!({})),
+        // Answer with whichever name the server used for the stage
+        RegistrationResponse::RegistrationToken { token } => {
+            pending.uiaa.auth(&stage, serde_json::json!({ "token": token }))
+        }
+        RegistrationResponse::RequestEmail { email } => {
+            if let Err(e) = request_registration_email(&mut pending, &email, &app, &log).await {
+                *state.registration.lock().await = Some(pending);
+                return Err(e);
+            }
+            let status = registration_stage(&pending, &stage, None);
+            *state.registration.lock().await = Some(pending);
+            return Ok(status);
+        }
+        RegistrationResponse::EmailConfirmed => {
+            let Some(email) = &pending.email else {
+                *state.registration.lock().await = Some(pending);
+                return Err("No verification email has been sent".to_string());
+            };
+            pending.uiaa.auth(uiaa::EMAIL_IDENTITY, serde_json::json!({
+                "threepid_creds": { "sid": email.sid, "client_secret": email.client_secret }
+            }))
+        }
+        RegistrationResponse::FallbackDone => pending.uiaa.fallback_auth(),
     };
 
-    Ok(Room {
-        room_id,
-        name,
-        is_direct: true,
-        last_message: None,
-        unread_count: 0,
-    })
+    let mut body = pending.body.clone();
+    body["auth"] = auth;
+    match post_registration(&pending.register_url, &body, &app, &log).await {
+        Ok((status, resp_body)) => advance_registration(pending, status, resp_body, &app, &log, &state).await,
+        Err(e) => {
+            // Keep the session so the user can retry the stage
+            *state.registration.lock().await = Some(pending);
+            Err(e)
+        }
+    }
 }
 
 #[tauri::command]
-pub async fn get_buddy_list(
+pub async fn matrix_register_cancel(
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
-) -> Result<Vec<Buddy>, String> {
-    let log = state.log.clone();
-    slog(&app, &log, "info", "get_buddy_list: running sync_once...".into());
-
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+) -> Result<(), String> {
+    if state.registration.lock().await.take().is_some() {
+        slog(&app, &state.log, "info", "Registration cancelled".into());
+    }
+    Ok(())
+}
 
-    with_heartbeat(&app, &log, "sync_once", client.sync_once(Default::default()))
+async fn post_registration(
+    register_url: &str,
+    body: &serde_json::Value,
+    app: &tauri::AppHandle,
+    log: &std::sync::Arc<ServerLog>,
+) -> Result<(reqwest::StatusCode, serde_json::Value), String> {
+    let resp = tokio::time::timeout(
+        std::time::Duration::from_secs(30),
+        reqwest::Client::new().post(register_url).json(body).send(),
+    )
         .await
+        .map_err(|_| {
+            slog(app, log, "error", "Registration request timed out after 30s".into());
+            "Registration timed out".to_string()
+        })?
         .map_err(|e| {
-            slog(&app, &log, "error", format!("sync_once failed: {}", e));
-            format!("Sync failed: {}", e)
+            slog(app, log, "error", format!("Registration failed: {}", e));
+            format!("Registration failed: {}", e)
         })?;
 
-    slog(&app, &log, "info", "sync_once complete, scanning rooms...".into());
-
-    let joined = client.joined_rooms();
-    slog(&app, &log, "info", format!("Found {} joined rooms", joined.len()));
+    let status = resp.status();
+    let resp_body: serde_json::Value = resp.json().await
+        .map_err(|e| format!("Failed to parse response: {}", e))?;
+    Ok((status, resp_body))
+}
 
-    let mut buddies = Vec::new();
-    let mut seen_user_ids = std::collections::HashSet::new();
-    let mut presence_supported = true;
-    let my_user_id = client.user_id().map(|u| u.to_string()).unwrap_or_default();
+/// Handle a registration response: finish on success, complete dummy stages
+/// on the spot, and otherwise park the registration until the user answers
+/// the next stage.
+async fn advance_registration(
+    mut pending: PendingRegistration,
+    mut status: reqwest::StatusCode,
+    mut resp_body: serde_json::Value,
+    app: &tauri::AppHandle,
+    log: &std::sync::Arc<ServerLog>,
+    state: &State<'_, MatrixState>,
+) -> Result<RegistrationStatus, String> {
+    loop {
+        if status.is_success() {
+            slog(app, log, "info", "Registration succeeded".into());
+            let password = pending.body["password"].as_str().map(str::to_string);
+            let user_id = finish_registration(pending.client, &resp_body, app, log, state).await?;
+            offer_cross_signing(&user_id, password, app, log, state).await;
+            return Ok(RegistrationStatus::Complete { user_id });
+        }
 
-    for room in joined {
-        if room.is_direct().await.unwrap_or(false) {
-            let members = room
-                .members(matrix_sdk::RoomMemberships::ACTIVE)
-                .await
-                .unwrap_or_default();
-            for member in members {
-                let user_id = member.user_id().to_string();
-                if user_id != my_user_id && seen_user_ids.insert(user_id.clone()) {
-                    let presence = if presence_supported {
-                        match fetch_user_presence(client, member.user_id()).await {
-                            Some(p) => p,
-                            None => {
-                                slog(&app, &log, "warn", "Presence not supported by server, skipping remaining".into());
-                                presence_supported = false;
-                                "unknown".to_string()
-                            }
-                        }
-                    } else {
-                        "unknown".to_string()
-                    };
-                    let hs = client.homeserver().to_string();
-                    buddies.push(Buddy {
-                        user_id: user_id.clone(),
-                        display_name: member.display_name().unwrap_or(&user_id).to_string(),
-                        avatar_url: member.avatar_url().and_then(|u| mxc_to_http(&hs, &u.to_string())),
-                        presence,
-                    });
-                }
+        // Not a UIAA challenge → real error
+        let uiaa = match UiaaInfo::parse(&resp_body) {
+            Some(uiaa) if status.as_u16() == 401 => uiaa,
+            _ => {
+                let error_msg = resp_body["error"].as_str().unwrap_or("Registration failed");
+                slog(app, log, "error", format!("Registration failed: {}", error_msg));
+                return Err(format!("Registration failed: {}", error_msg));
             }
+        };
+        slog(app, log, "info", format!("UIAA response, flows: {}, completed: {:?}", resp_body["flows"], uiaa.completed));
+        pending.uiaa = uiaa;
+
+        let Some(stage) = pending.uiaa.next_stage(REGISTRATION_STAGES) else {
+            slog(app, log, "warn", "Server offered no registration flow we can follow".into());
+            return Err("This server offers no registration flow this app can complete".to_string());
+        };
+        if stage != uiaa::DUMMY {
+            let error = pending.uiaa.error.clone();
+            let result = registration_stage(&pending, &stage, error);
+            *state.registration.lock().await = Some(pending);
+            return Ok(result);
         }
+
+        slog(app, log, "info", "Completing m.login.dummy stage...".into());
+        let mut body = pending.body.clone();
+        body["auth"] = pending.uiaa.auth(uiaa::DUMMY, serde_json::json!({}));
+        (status, resp_body) = post_registration(&pending.register_url, &body, app, log).await?;
     }
-    slog(&app, &log, "info", format!("get_buddy_list: returning {} buddies", buddies.len()));
-    Ok(buddies)
 }
 
-#[tauri::command]
-pub async fn get_room_members(
-    room_id: String,
-    app: tauri::AppHandle,
-    state: State<'_, MatrixState>,
-) -> Result<Vec<Buddy>, String> {
-    let log = state.log.clone();
-    slog(&app, &log, "info", format!("get_room_members: {}", room_id));
+/// Describe the stage the user has to complete next.
+fn registration_stage(pending: &PendingRegistration, stage: &str, error: Option<String>) -> RegistrationStatus {
+    let homeserver = pending.client.homeserver().to_string();
+    RegistrationStatus::Stage(RegistrationStage {
+        stage: stage.to_string(),
+        completed: pending.uiaa.completed.clone(),
+        remaining: pending.uiaa.remaining_stages(REGISTRATION_STAGES),
+        policies: pending.uiaa.terms_policies("en"),
+        email_sent_to: pending.email.as_ref().map(|e| e.address.clone()),
+        fallback_url: (!REGISTRATION_STAGES.contains(&stage))
+            .then(|| pending.uiaa.fallback_url(&homeserver, stage))
+            .flatten(),
+        error,
+    })
+}
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+/// Ask the homeserver to mail a verification link for the email stage.
+async fn request_registration_email(
+    pending: &mut PendingRegistration,
+    address: &str,
+    app: &tauri::AppHandle,
+    log: &std::sync::Arc<ServerLog>,
+) -> Result<(), String> {
+    use rand::distributions::{Alphanumeric, DistString};
 
-    let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
-        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    // Resending to the same address reuses the secret with a higher attempt
+    let (client_secret, send_attempt) = match &pending.email {
+        Some(email) if email.address == address => (email.client_secret.clone(), email.send_attempt + 1),
+        _ => (Alphanumeric.sample_string(&mut rand::thread_rng(), 32), 1),
+    };
+    let url = format!("{}/email/requestToken", pending.register_url);
+    let body = serde_json::json!({
+        "client_secret": client_secret,
+        "email": address,
+        "send_attempt": send_attempt,
+    });
+    let (status, resp_body) = post_registration(&url, &body, app, log).await?;
+    if !status.is_success() {
+        let error_msg = resp_body["error"].as_str().unwrap_or("Could not send verification email");
+        slog(app, log, "error", format!("Email verification request failed: {}", error_msg));
+        return Err(error_msg.to_string());
+    }
+    let sid = resp_body["sid"].as_str().ok_or("Server did not return a verification session")?;
+    slog(app, log, "info", format!("Verification email sent to {}", address));
+    pending.email = Some(EmailVerification {
+        address: address.to_string(),
+        client_secret,
+        sid: sid.to_string(),
+        send_attempt,
+    });
+    Ok(())
+}
 
-    let room = client.get_room(&room_id).ok_or("Room not found")?;
+/// Post-registration: add the account for the newly created session.
+async fn finish_registration(
+    client: Client,
+    resp: &serde_json::Value,
+    app: &tauri::AppHandle,
+    log: &std::sync::Arc<ServerLog>,
+    state: &State<'_, MatrixState>,
+) -> Result<String, String> {
+    let user_id = resp["user_id"].as_str()
+        .ok_or("Registration response missing user_id")?;
+    let access_token = resp["access_token"].as_str()
+        .ok_or("Registration response missing access_token")?;
+    let device_id = resp["device_id"].as_str()
+        .ok_or("Registration response missing device_id")?;
+    let refresh_token = resp["refresh_token"].as_str().map(|s| s.to_string());
 
-    let members = with_heartbeat(
-        &app, &log, "get_room_members",
-        room.members(matrix_sdk::RoomMemberships::ACTIVE),
-    )
-        .await
-        .map_err(|e| {
-            slog(&app, &log, "error", format!("Failed to get members: {}", e));
-            format!("Failed to get members: {}", e)
-        })?;
+    slog(app, log, "info", format!("Registered user_id={}, device_id={}", user_id, device_id));
 
-    slog(&app, &log, "info", format!("Room has {} active members", members.len()));
+    let session = PersistedSession {
+        homeserver_url: client.homeserver().to_string(),
+        user_id: user_id.to_string(),
+        device_id: device_id.to_string(),
+        access_token: access_token.to_string(),
+        refresh_token,
+        secret_backend: None,
+        store_passphrase: None,
+        oauth_client_id: None,
+    };
+    add_account(&client, session, app, log, state).await
+}
 
-    let hs = client.homeserver().to_string();
-    let buddies: Vec<Buddy> = members
-        .iter()
-        .map(|member| {
-            let user_id = member.user_id().to_string();
-            Buddy {
-                display_name: member.display_name().unwrap_or(&user_id).to_string(),
-                avatar_url: member.avatar_url().and_then(|u| mxc_to_http(&hs, &u.to_string())),
-                presence: "offline".to_string(),
-                user_id,
+#[tauri::command]
+pub async fn matrix_logout(
+    account: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<(), String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("Logging out {}...", account));
+
+    let removed = state.accounts.lock().await.remove(&account);
+    if let Some(removed) = removed {
+        removed.abort_sync_tasks();
+        let _ = removed.client.logout().await;
+
+        // Keep the store as a stale store the user can delete later; its
+        // session file no longer holds tokens, so it only labels the store
+        let homeserver_url = removed.client.homeserver().to_string();
+        if let Ok(dir) = crate::matrix_client::account_dir(&account, &homeserver_url) {
+            state.secrets.delete(&account, &dir);
+            if let Err(e) = crate::matrix_client::retire_account_dir(&dir) {
+                slog(&app, &log, "warn", format!("Failed to set aside store for {}: {}", account, e));
+                let _ = std::fs::remove_file(dir.join("session.json"));
             }
-        })
-        .collect();
+        }
+    }
 
-    slog(&app, &log, "info", format!("get_room_members: returning {} members", buddies.len()));
-    Ok(buddies)
+    slog(&app, &log, "info", format!("Logged out {}", account));
+    Ok(())
 }
 
 #[tauri::command]
-pub async fn get_rooms(
+pub async fn matrix_disconnect(
+    account: String,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
-) -> Result<Vec<Room>, String> {
+) -> Result<(), String> {
     let log = state.log.clone();
-    slog(&app, &log, "info", "get_rooms: fetching joined rooms...".into());
+    slog(&app, &log, "info", format!("Disconnecting {} (keeping session)...", account));
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
-
-    let mut rooms = Vec::new();
-    for room in client.joined_rooms() {
-        // Skip space rooms — they are handled by get_spaces
-        if room.is_space() {
-            continue;
-        }
-        let is_direct = room.is_direct().await.unwrap_or(false);
-        rooms.push(Room {
-            room_id: room.room_id().to_string(),
-            name: resolve_room_name(client, &room, is_direct).await,
-            is_direct,
-            last_message: None,
-            unread_count: 0,
-        });
+    if let Some(removed) = state.accounts.lock().await.remove(&account) {
+        removed.abort_sync_tasks();
     }
-    slog(&app, &log, "info", format!("get_rooms: returning {} rooms", rooms.len()));
-    Ok(rooms)
+
+    slog(&app, &log, "info", "Disconnected, session file preserved".into());
+    Ok(())
 }
 
+/// Sign an account back in after a soft logout. Logs in again with the same
+/// device ID and reopens the account's existing store, so its keys and
+/// cached rooms are kept. The frontend restarts the sync afterwards.
 #[tauri::command]
-pub async fn get_spaces(
+pub async fn matrix_reauthenticate(
+    account: String,
+    password: String,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
-) -> Result<Vec<Space>, String> {
+) -> Result<String, String> {
     let log = state.log.clone();
-    slog(&app, &log, "info", "get_spaces: fetching joined spaces...".into());
-
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
-
-    // Collect all joined room IDs for filtering children to only joined rooms
-    let joined_room_ids: std::collections::HashSet<String> = client
-        .joined_rooms()
-        .iter()
-        .map(|r| r.room_id().to_string())
-        .collect();
+    let previous = {
+        let accounts = state.accounts.lock().await;
+        let entry = accounts.get(&account).ok_or("Not logged in")?;
+        persisted_session_from_client(&entry.client, entry.store_passphrase.clone())
+            .ok_or("No session to re-authenticate")?
+    };
+    if previous.oauth_client_id.is_some() {
+        return Err("This account signs in through the browser — sign out and sign in again".to_string());
+    }
+    slog(&app, &log, "info", format!("Re-authenticating {} on device {}", account, previous.device_id));
 
-    let mut spaces = Vec::new();
-    for room in client.joined_rooms() {
-        if !room.is_space() {
-            continue;
-        }
+    let login_client = Client::builder()
+        .homeserver_url(&previous.homeserver_url)
+        .build()
+        .await
+        .map_err(|e| format!("Failed to build client: {}", e))?;
+    let response = tokio::time::timeout(
+        std::time::Duration::from_secs(30),
+        login_client
+            .matrix_auth()
+            .login_username(&account, &password)
+            .device_id(&previous.device_id)
+            .request_refresh_token()
+            .send(),
+    )
+        .await
+        .map_err(|_| {
+            slog(&app, &log, "error", "Re-authentication timed out after 30s".into());
+            "Login timed out".to_string()
+        })?
+        .map_err(|e| {
+            slog(&app, &log, "error", format!("Re-authentication failed: {}", e));
+            format!("Login failed: {}", e)
+        })?;
+    if response.device_id.as_str() != previous.device_id {
+        slog(&app, &log, "error", format!("Server issued a new device {} instead of {}", response.device_id, previous.device_id));
+        let _ = login_client.logout().await;
+        return Err("The server did not restore the previous device".to_string());
+    }
 
-        let name = resolve_room_name(client, &room, false).await;
+    // Stop the old client before reopening its store. It goes back into state
+    // if the new one can't be set up, so the account stays listed as expired.
+    let old = state.accounts.lock().await.remove(&account).ok_or("Not logged in")?;
+    old.abort_sync_tasks();
 
-        // Read m.space.child state events from the local store
-        let mut child_room_ids = Vec::new();
-        use matrix_sdk::deserialized_responses::SyncOrStrippedState;
-        use matrix_sdk::ruma::events::space::child::SpaceChildEventContent;
-        use matrix_sdk::ruma::events::SyncStateEvent;
+    let session = PersistedSession {
+        access_token: response.access_token.clone(),
+        refresh_token: response.refresh_token.clone(),
+        ..previous
+    };
+    let reopened = async {
+        let store_dir = crate::matrix_client::account_dir(&account, &session.homeserver_url)?;
+        let client = build_account_client(
+            &session.homeserver_url,
+            &store_dir,
+            session.store_passphrase.as_deref(),
+            &app,
+            &log,
+        ).await?;
+        client
+            .restore_session(auth_session_from_persisted(session.clone())?)
+            .await
+            .map_err(|e| {
+                slog(&app, &log, "error", format!("Failed to restore session: {}", e));
+                format!("Failed to restore session: {}", e)
+            })?;
+        save_session(&session, &state.secrets)?;
+        Ok::<_, String>(client)
+    }
+    .await;
 
-        match room.get_state_events_static::<SpaceChildEventContent>().await {
-            Ok(raw_events) => {
-                for raw_event in raw_events {
-                    match raw_event.deserialize() {
-                        Ok(SyncOrStrippedState::Sync(SyncStateEvent::Original(e))) => {
-                            let child_id = e.state_key.to_string();
-                            if !e.content.via.is_empty() && joined_room_ids.contains(&child_id) {
-                                child_room_ids.push(child_id);
-                            }
-                        }
-                        Ok(SyncOrStrippedState::Sync(SyncStateEvent::Redacted(_))) => {}
-                        Ok(SyncOrStrippedState::Stripped(e)) => {
-                            let child_id = e.state_key.to_string();
-                            let via_non_empty = e.content.via.as_ref().is_some_and(|v| !v.is_empty());
-                            if via_non_empty && joined_room_ids.contains(&child_id) {
-                                child_room_ids.push(child_id);
-                            }
-                        }
-                        Err(e) => {
-                            slog(&app, &log, "warn", format!("get_spaces: failed to deserialize space child event: {}", e));
-                        }
-                    }
-                }
-            }
-            Err(e) => {
-                slog(&app, &log, "warn", format!("get_spaces: failed to read space children for {}: {}", room.room_id(), e));
-            }
-        }
+    if let Err(e) = &reopened {
+        slog(&app, &log, "error", format!("Re-authentication of {} failed, keeping the expired session: {}", account, e));
+    } else {
+        slog(&app, &log, "info", format!("Re-authenticated {}", account));
+    }
+    let reopened = reopened.map(|client| Account::new(client, session.store_passphrase));
+    let mut accounts = state.accounts.lock().await;
+    replace_reauthenticated(&mut accounts, account, old, reopened)
+}
 
-        spaces.push(Space {
-            room_id: room.room_id().to_string(),
-            name,
-            child_room_ids,
-        });
+/// Put the re-authenticated account in place of the old entry, or the old
+/// entry back if the new client couldn't be set up.
+fn replace_reauthenticated(
+    accounts: &mut std::collections::HashMap<String, Account>,
+    account: String,
+    old: Account,
+    reopened: Result<Account, String>,
+) -> Result<String, String> {
+    match reopened {
+        Ok(entry) => {
+            accounts.insert(account.clone(), entry);
+            Ok(account)
+        }
+        Err(e) => {
+            accounts.insert(account, old);
+            Err(e)
+        }
     }
+}
 
-    slog(&app, &log, "info", format!("get_spaces: returning {} spaces", spaces.len()));
-    Ok(spaces)
+/// Every store directory on disk, including stale ones left behind by
+/// sign-outs and fresh logins.
+#[tauri::command]
+pub async fn list_stores() -> Result<Vec<StoreInfo>, String> {
+    crate::matrix_client::list_stores()
 }
 
+/// Delete a stale store. Stores that a saved session still uses are refused.
 #[tauri::command]
-pub async fn get_room_messages(
-    room_id: String,
-    limit: u64,
-    from: Option<String>,
+pub async fn delete_store(
+    id: String,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
-) -> Result<MessagesPage, String> {
+) -> Result<Vec<StoreInfo>, String> {
     let log = state.log.clone();
-    slog(&app, &log, "info", format!("get_room_messages: {} (from={:?})", room_id, from));
-
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
-
-    let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
-        .map_err(|e| format!("Invalid room ID: {}", e))?;
-
-    let room = client.get_room(&room_id).ok_or("Room not found")?;
-
-    slog(&app, &log, "info", "Fetching messages from server...".into());
-    let mut options = matrix_sdk::room::MessagesOptions::backward();
-    if let Some(ref token) = from {
-        options.from = Some(token.clone());
-    }
-    if let Some(l) = matrix_sdk::ruma::UInt::new(limit) {
-        options.limit = l;
+    // Only accept IDs we listed ourselves, so this can't reach outside the accounts dir
+    let store = crate::matrix_client::list_stores()?
+        .into_iter()
+        .find(|s| s.id == id)
+        .ok_or("No such store")?;
+    if !store.stale {
+        return Err("This store belongs to a saved account — sign out first".to_string());
     }
-    let messages_response = with_heartbeat(
-        &app, &log, "messages",
-        room.messages(options),
-    )
-        .await
-        .map_err(|e| {
-            slog(&app, &log, "error", format!("Failed to get messages: {}", e));
-            format!("Failed to get messages: {}", e)
-        })?;
-
-    let end_token = messages_response.end;
-
-    let mut messages = Vec::new();
-    let mut edits: std::collections::HashMap<String, String> = std::collections::HashMap::new();
-
-    for event in messages_response.chunk {
-        if let Ok(timeline_event) = event.raw().deserialize() {
-            if let matrix_sdk::ruma::events::AnySyncTimelineEvent::MessageLike(
-                matrix_sdk::ruma::events::AnySyncMessageLikeEvent::RoomMessage(msg),
-            ) = timeline_event
-            {
-                let Some(original) = msg.as_original() else {
-                    continue;
-                };
-
-                // Handle edits: collect replacement content, skip the edit event itself
-                if let Some(matrix_sdk::ruma::events::room::message::Relation::Replacement(replacement)) = &original.content.relates_to {
-                    let new_body = match &replacement.new_content.msgtype {
-                        matrix_sdk::ruma::events::room::message::MessageType::Text(text) => text.body.clone(),
-                        matrix_sdk::ruma::events::room::message::MessageType::Notice(notice) => notice.body.clone(),
-                        matrix_sdk::ruma::events::room::message::MessageType::Emote(emote) => format!("* {}", emote.body),
-                        _ => String::new(),
-                    };
-                    if !new_body.is_empty() {
-                        edits.insert(replacement.event_id.to_string(), new_body);
-                    }
-                    continue;
-                }
-
-                // Extract reply relation (Reply or Thread)
-                let mut in_reply_to: Option<String> = None;
-                let mut reply_sender_name: Option<String> = None;
-                let mut reply_body_text: Option<String> = None;
-
-                if let Some(relation) = &original.content.relates_to {
-                    match relation {
-                        matrix_sdk::ruma::events::room::message::Relation::Reply { in_reply_to: irt } => {
-                            in_reply_to = Some(irt.event_id.to_string());
-                        }
-                        matrix_sdk::ruma::events::room::message::Relation::Thread(thread) => {
-                            if let Some(irt) = &thread.in_reply_to {
-                                in_reply_to = Some(irt.event_id.to_string());
-                            }
-                        }
-                        _ => {}
-                    }
-                }
 
-                // Fallback: parse raw JSON for m.relates_to.m.in_reply_to.event_id
-                // Some servers (e.g. continuwuity) may include fields that ruma
-                // doesn't recognize, causing typed deserialization to miss the reply.
-                if in_reply_to.is_none() {
-                    if let Ok(raw_json) = serde_json::from_str::<serde_json::Value>(event.raw().json().get()) {
-                        if let Some(eid) = raw_json.pointer("/content/m.relates_to/m.in_reply_to/event_id")
-                            .and_then(|v| v.as_str())
-                        {
-                            in_reply_to = Some(eid.to_string());
-                        }
-                    }
-                }
+    let path = crate::matrix_client::accounts_dir()?.join(&store.id);
+    std::fs::remove_dir_all(&path).map_err(|e| {
+        slog(&app, &log, "error", format!("Failed to delete store {}: {}", store.id, e));
+        format!("Failed to delete store: {}", e)
+    })?;
+    slog(&app, &log, "info", format!("Deleted stale store {} ({:?})", store.id, store.user_id));
+    crate::matrix_client::list_stores()
+}
 
-                let (mut body, msg_type, media_url, filename) = match &original.content.msgtype {
-                    matrix_sdk::ruma::events::room::message::MessageType::Text(text) => {
-                        (text.body.clone(), "text".to_string(), None, None)
-                    }
-                    matrix_sdk::ruma::events::room::message::MessageType::Notice(notice) => {
-                        (notice.body.clone(), "text".to_string(), None, None)
-                    }
-                    matrix_sdk::ruma::events::room::message::MessageType::Emote(emote) => {
-                        (format!("* {}", emote.body), "text".to_string(), None, None)
-                    }
-                    matrix_sdk::ruma::events::room::message::MessageType::Image(img) => {
-                        (img.body.clone(), "image".to_string(), media_source_to_mxc(&img.source), Some(img.body.clone()))
-                    }
-                    matrix_sdk::ruma::events::room::message::MessageType::File(file) => {
-                        let fname = file.filename.clone().unwrap_or_else(|| file.body.clone());
-                        (file.body.clone(), "file".to_string(), media_source_to_mxc(&file.source), Some(fname))
-                    }
-                    matrix_sdk::ruma::events::room::message::MessageType::Audio(audio) => {
-                        (audio.body.clone(), "audio".to_string(), media_source_to_mxc(&audio.source), Some(audio.body.clone()))
-                    }
-                    matrix_sdk::ruma::events::room::message::MessageType::Video(video) => {
-                        (video.body.clone(), "video".to_string(), media_source_to_mxc(&video.source), Some(video.body.clone()))
-                    }
-                    _ => (String::new(), "unknown".to_string(), None, None),
-                };
-
-                // For text replies, parse and strip fallback
-                if msg_type == "text" && body.starts_with("> <") {
-                    if in_reply_to.is_none() {
-                        // Body has reply fallback but typed relation wasn't parsed —
-                        // use body text as the source of reply info
-                        in_reply_to = Some("fallback".to_string());
-                    }
-                    if let Some((sender, quoted)) = extract_reply_fallback(&body) {
-                        reply_sender_name = Some(sender);
-                        reply_body_text = Some(quoted);
-                    }
-                    body = strip_reply_fallback(&body);
-                }
+/// The account's sessions, this one first, then by when they were last used.
+#[tauri::command]
+pub async fn list_devices(
+    account: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<Vec<DeviceInfo>, String> {
+    let log = state.log.clone();
+    let client = state.client(&account).await?;
+    let user_id = client.user_id().ok_or("No user ID")?.to_owned();
+    let current = client.device_id().map(|d| d.to_owned());
 
-                messages.push(Message {
-                    room_id: room_id.to_string(),
-                    event_id: msg.event_id().to_string(),
-                    sender: msg.sender().to_string(),
-                    sender_name: msg.sender().localpart().to_string(),
-                    body,
-                    timestamp: msg.origin_server_ts().as_secs().into(),
-                    msg_type,
-                    media_url,
-                    filename,
-                    in_reply_to,
-                    reply_sender_name,
-                    reply_body: reply_body_text,
-                });
-            }
-        }
-    }
+    let response = client.devices().await.map_err(|e| {
+        slog(&app, &log, "error", format!("Failed to list devices: {}", e));
+        format!("Failed to list devices: {}", e)
+    })?;
 
-    // Apply edits to original messages
-    for msg in &mut messages {
-        if let Some(new_body) = edits.get(&msg.event_id) {
-            msg.body = new_body.clone();
-        }
+    let mut devices = Vec::with_capacity(response.devices.len());
+    for device in response.devices {
+        let cross_signed = match client.encryption().get_device(&user_id, &device.device_id).await {
+            Ok(Some(d)) => d.is_cross_signed_by_owner(),
+            _ => false,
+        };
+        devices.push(DeviceInfo {
+            is_current: current.as_ref() == Some(&device.device_id),
+            device_id: device.device_id.to_string(),
+            display_name: device.display_name,
+            last_seen_ip: device.last_seen_ip,
+            last_seen_ts: device.last_seen_ts.map(|ts| ts.get().into()),
+            cross_signed,
+        });
     }
-
-    messages.reverse();
-    slog(&app, &log, "info", format!("get_room_messages: returning {} messages ({} edits applied)", messages.len(), edits.len()));
-    Ok(MessagesPage { messages, end_token })
+    devices.sort_by(|a, b| b.is_current.cmp(&a.is_current).then(b.last_seen_ts.cmp(&a.last_seen_ts)));
+    Ok(devices)
 }
 
 #[tauri::command]
-pub async fn send_message(
-    room_id: String,
-    body: String,
-    in_reply_to_event_id: Option<String>,
+pub async fn rename_device(
+    account: String,
+    device_id: String,
+    name: String,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
 ) -> Result<(), String> {
     let log = state.log.clone();
-    slog(&app, &log, "info", format!("send_message: room={}, len={}, reply={:?}", room_id, body.len(), in_reply_to_event_id));
-
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
-
-    let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
-        .map_err(|e| format!("Invalid room ID: {}", e))?;
-
-    let room = client.get_room(&room_id).ok_or("Room not found")?;
-
-    let mut content =
-        matrix_sdk::ruma::events::room::message::RoomMessageEventContent::text_plain(&body);
-
-    if let Some(reply_id) = in_reply_to_event_id {
-        let event_id = matrix_sdk::ruma::OwnedEventId::try_from(reply_id.as_str())
-            .map_err(|e| format!("Invalid event ID: {}", e))?;
-        content.relates_to = Some(
-            matrix_sdk::ruma::events::room::message::Relation::Reply {
-                in_reply_to: matrix_sdk::ruma::events::relation::InReplyTo::new(event_id),
-            }
-        );
-    }
-
-    room.send(content)
-        .await
-        .map_err(|e| {
-            slog(&app, &log, "error", format!("Send failed: {}", e));
-            format!("Send failed: {}", e)
-        })?;
-
-    slog(&app, &log, "info", "Message sent OK".into());
+    let client = state.client(&account).await?;
+    let device_id: matrix_sdk::ruma::OwnedDeviceId = device_id.into();
+    client.rename_device(&device_id, name.trim()).await.map_err(|e| {
+        slog(&app, &log, "error", format!("Failed to rename device {}: {}", device_id, e));
+        format!("Failed to rename device: {}", e)
+    })?;
+    slog(&app, &log, "info", format!("Renamed device {} to {:?}", device_id, name.trim()));
     Ok(())
 }
 
+/// Sign out other sessions. Fails with `PASSWORD_REQUIRED` until the
+/// account password is given, if the server asks for it.
 #[tauri::command]
-pub async fn edit_message(
-    room_id: String,
-    event_id: String,
-    new_body: String,
+pub async fn delete_devices(
+    account: String,
+    device_ids: Vec<String>,
+    password: Option<String>,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
 ) -> Result<(), String> {
     let log = state.log.clone();
-    slog(&app, &log, "info", format!("edit_message: room={}, event={}", room_id, event_id));
-
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
-
-    let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
-        .map_err(|e| format!("Invalid room ID: {}", e))?;
-    let room = client.get_room(&room_id).ok_or("Room not found")?;
-
-    // Build the edit event as raw JSON — the SDK's Replacement type is non_exhaustive/private
-    let raw_content = serde_json::json!({
-        "msgtype": "m.text",
-        "body": format!("* {}", new_body),
-        "m.new_content": {
-            "msgtype": "m.text",
-            "body": new_body,
-        },
-        "m.relates_to": {
-            "rel_type": "m.replace",
-            "event_id": event_id,
-        }
-    });
-    let content: matrix_sdk::ruma::events::room::message::RoomMessageEventContent =
-        serde_json::from_value(raw_content)
-            .map_err(|e| format!("Failed to build edit content: {}", e))?;
+    let client = state.client(&account).await?;
+    let device_ids: Vec<matrix_sdk::ruma::OwnedDeviceId> = device_ids.into_iter().map(Into::into).collect();
+    if client.device_id().is_some_and(|current| device_ids.iter().any(|d| d == current)) {
+        return Err("Use Logout to sign out of this session".to_string());
+    }
 
-    room.send(content)
+    uiaa::with_password(&client, password.as_deref(), |auth| client.delete_devices(&device_ids, auth))
         .await
         .map_err(|e| {
-            slog(&app, &log, "error", format!("Edit failed: {}", e));
-            format!("Edit failed: {}", e)
+            if e.message != uiaa::PASSWORD_REQUIRED {
+                slog(&app, &log, "error", format!("Failed to delete devices: {}", e));
+            }
+            e.message
         })?;
-
-    slog(&app, &log, "info", "Message edited OK".into());
+    slog(&app, &log, "info", format!("Deleted {} device(s): {:?}", device_ids.len(), device_ids));
     Ok(())
 }
 
+/// Change the account password. `password` is the current one, for the
+/// server's UIAA check; `logout_devices` signs out every other session.
 #[tauri::command]
-pub async fn delete_message(
-    room_id: String,
-    event_id: String,
+pub async fn change_password(
+    account: String,
+    password: String,
+    new_password: String,
+    logout_devices: bool,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
-) -> Result<(), String> {
-    let log = state.log.clone();
-    slog(&app, &log, "info", format!("delete_message: room={}, event={}", room_id, event_id));
-
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+) -> Result<(), ServerError> {
+    use matrix_sdk::ruma::api::client::account::change_password;
 
-    let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
-        .map_err(|e| format!("Invalid room ID: {}", e))?;
-    let room = client.get_room(&room_id).ok_or("Room not found")?;
-
-    let event_id = matrix_sdk::ruma::OwnedEventId::try_from(event_id.as_str())
-        .map_err(|e| format!("Invalid event ID: {}", e))?;
-
-    room.redact(&event_id, None, None)
-        .await
-        .map_err(|e| {
-            slog(&app, &log, "error", format!("Delete failed: {}", e));
-            format!("Delete failed: {}", e)
-        })?;
+    let log = state.log.clone();
+    let client = state.client(&account).await?;
+    slog(&app, &log, "info", format!("Changing password for {} (logout_devices={})", account, logout_devices));
+
+    uiaa::with_password(&client, Some(&password), |auth| {
+        let mut request = change_password::v3::Request::new(new_password.clone());
+        request.logout_devices = logout_devices;
+        request.auth = auth;
+        client.send(request)
+    })
+    .await
+    .inspect_err(|e| slog(&app, &log, "error", format!("Failed to change password: {}", e)))?;
 
-    slog(&app, &log, "info", "Message deleted OK".into());
+    slog(&app, &log, "info", format!("Changed password for {}", account));
     Ok(())
 }
 
+/// Deactivate the account for good, optionally asking the server to erase
+/// its messages, then remove the account's session, secrets and store.
 #[tauri::command]
-pub async fn send_reaction(
-    room_id: String,
-    event_id: String,
-    reaction_key: String,
+pub async fn deactivate_account(
+    account: String,
+    password: String,
+    erase: bool,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
-) -> Result<(), String> {
+) -> Result<(), ServerError> {
+    use matrix_sdk::ruma::api::client::account::deactivate;
+
     let log = state.log.clone();
-    slog(&app, &log, "info", format!("send_reaction: room={}, event={}, key={}", room_id, event_id, reaction_key));
+    let client = state.client(&account).await?;
+    slog(&app, &log, "warn", format!("Deactivating {} (erase={})", account, erase));
+
+    uiaa::with_password(&client, Some(&password), |auth| {
+        let mut request = deactivate::v3::Request::new();
+        request.erase = erase;
+        request.auth = auth;
+        client.send(request)
+    })
+    .await
+    .inspect_err(|e| slog(&app, &log, "error", format!("Failed to deactivate {}: {}", account, e)))?;
+    slog(&app, &log, "info", format!("Deactivated {}", account));
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    // The server has dropped the account, so nothing here is worth keeping
+    if let Some(removed) = state.accounts.lock().await.remove(&account) {
+        removed.abort_sync_tasks();
+    }
+    let homeserver_url = client.homeserver().to_string();
+    drop(client);
+    if let Ok(dir) = crate::matrix_client::account_dir(&account, &homeserver_url) {
+        state.secrets.delete(&account, &dir);
+        if let Err(e) = std::fs::remove_dir_all(&dir) {
+            slog(&app, &log, "warn", format!("Failed to delete store for {}: {}", account, e));
+            let _ = std::fs::remove_file(dir.join("session.json"));
+        }
+    }
+    let _ = app.emit("account_removed", &account);
+    Ok(())
+}
 
-    let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
-        .map_err(|e| format!("Invalid room ID: {}", e))?;
-    let room = client.get_room(&room_id).ok_or("Room not found")?;
+/// User IDs of the accounts currently signed in, sorted.
+#[tauri::command]
+pub async fn get_accounts(
+    state: State<'_, MatrixState>,
+) -> Result<Vec<String>, String> {
+    let mut accounts: Vec<String> = state.accounts.lock().await.keys().cloned().collect();
+    accounts.sort();
+    Ok(accounts)
+}
 
-    let event_id = matrix_sdk::ruma::OwnedEventId::try_from(event_id.as_str())
-        .map_err(|e| format!("Invalid event ID: {}", e))?;
+#[tauri::command]
+pub async fn get_secret_storage(
+    state: State<'_, MatrixState>,
+) -> Result<SecretStorageStatus, String> {
+    Ok(state.secrets.status())
+}
 
-    let content = matrix_sdk::ruma::events::reaction::ReactionEventContent::new(
-        matrix_sdk::ruma::events::relation::Annotation::new(event_id, reaction_key),
-    );
+/// Unlock (or, on first use, create) the passphrase-protected token file.
+#[tauri::command]
+pub async fn unlock_secret_storage(
+    passphrase: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<SecretStorageStatus, String> {
+    let log = state.log.clone();
+    state.secrets.unlock(&passphrase).map_err(|e| {
+        slog(&app, &log, "warn", format!("Failed to unlock secret storage: {}", e));
+        e
+    })?;
+    slog(&app, &log, "info", "Secret storage unlocked".into());
+    Ok(state.secrets.status())
+}
 
-    room.send(content)
+/// Choose where tokens are kept and move every signed-in account's tokens there.
+#[tauri::command]
+pub async fn set_secret_storage(
+    backend: SecretBackend,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<SecretStorageStatus, String> {
+    let log = state.log.clone();
+    if backend == SecretBackend::EncryptedFile && !state.secrets.is_unlocked() {
+        return Err("Secret store is locked".to_string());
+    }
+    state.secrets.set_configured_backend(backend)?;
+    slog(&app, &log, "info", format!("Secret storage backend set to {:?}", backend));
+
+    let accounts: Vec<(Client, Option<String>)> = state
+        .accounts
+        .lock()
         .await
-        .map_err(|e| {
-            slog(&app, &log, "error", format!("Reaction failed: {}", e));
-            format!("Reaction failed: {}", e)
-        })?;
+        .values()
+        .map(|a| (a.client.clone(), a.store_passphrase.clone()))
+        .collect();
+    for (client, store_passphrase) in accounts {
+        if let Some(session) = persisted_session_from_client(&client, store_passphrase) {
+            save_session(&session, &state.secrets).map_err(|e| {
+                slog(&app, &log, "error", format!("Failed to move tokens for {}: {}", session.user_id, e));
+                e
+            })?;
+        }
+    }
+    Ok(state.secrets.status())
+}
 
-    slog(&app, &log, "info", "Reaction sent OK".into());
-    Ok(())
+/// Turn random store passphrases on or off for accounts signed in from now
+/// on. With `existing`, unencrypted stores are also encrypted on their next
+/// restore. Stores that are already encrypted stay that way.
+#[tauri::command]
+pub async fn set_store_encryption(
+    enabled: bool,
+    existing: bool,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<SecretStorageStatus, String> {
+    let log = state.log.clone();
+    state.secrets.set_encrypt_stores(enabled, existing)?;
+    slog(&app, &log, "info", format!(
+        "Store encryption {}{}",
+        if enabled { "enabled" } else { "disabled" },
+        if enabled && existing { ", existing stores are encrypted on next launch" } else { "" },
+    ));
+    Ok(state.secrets.status())
 }
 
+/// Restore every account with a saved session that isn't already signed in.
+/// Returns all signed-in accounts; fails only if none could be restored.
 #[tauri::command]
-pub async fn set_presence(
-    status: String,
+pub async fn try_restore_session(
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
-) -> Result<(), String> {
+) -> Result<Vec<RestoredAccount>, String> {
     let log = state.log.clone();
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
 
-    // Map ICQ status names to Matrix presence states
-    let presence = match status.as_str() {
-        "online" | "free_for_chat" => matrix_sdk::ruma::presence::PresenceState::Online,
-        "away" | "na" => matrix_sdk::ruma::presence::PresenceState::Unavailable,
-        "occupied" | "dnd" => matrix_sdk::ruma::presence::PresenceState::Unavailable,
-        "invisible" | "offline" => matrix_sdk::ruma::presence::PresenceState::Offline,
-        _ => matrix_sdk::ruma::presence::PresenceState::Online,
-    };
+    match crate::matrix_client::migrate_legacy_store() {
+        Ok(moved) => {
+            for user_id in moved {
+                slog(&app, &log, "info", format!("Moved existing store into account dir for {}", user_id));
+            }
+        }
+        Err(e) => slog(&app, &log, "error", format!("Failed to migrate existing store: {}", e)),
+    }
 
-    use matrix_sdk::ruma::api::client::presence::set_presence;
-    let user_id = client.user_id().ok_or("No user ID")?.to_owned();
-    let mut request = set_presence::v3::Request::new(user_id, presence.clone());
-    // Set a status message for non-standard ICQ statuses
-    match status.as_str() {
-        "dnd" => request.status_msg = Some("Do Not Disturb".to_string()),
-        "occupied" => request.status_msg = Some("Occupied".to_string()),
-        "na" => request.status_msg = Some("Not Available".to_string()),
-        "free_for_chat" => request.status_msg = Some("Free for Chat".to_string()),
-        _ => {}
+    let session_files = crate::matrix_client::saved_session_files()?;
+    if session_files.is_empty() && state.accounts.lock().await.is_empty() {
+        slog(&app, &log, "info", "No saved session found".into());
+        return Err("No saved session".to_string());
     }
 
-    match client.send(request).await {
-        Ok(_) => {
-            slog(&app, &log, "info", format!("Presence set to {} (matrix: {:?})", status, presence));
-        }
-        Err(e) => {
-            // Some servers don't support presence — log but don't fail
-            slog(&app, &log, "warn", format!("Failed to set presence: {}", e));
+    let mut restored = Vec::new();
+    let mut last_error = None;
+    for session_path in session_files {
+        match restore_account(&session_path, &app, &log, &state).await {
+            Ok(Some(account)) => restored.push(account),
+            Ok(None) => {}
+            Err(e) => last_error = Some(e),
         }
     }
 
-    Ok(())
+    // Accounts signed in before this call (e.g. after unlocking secrets) count as online
+    for user_id in get_accounts(state.clone()).await? {
+        if !restored.iter().any(|a: &RestoredAccount| a.user_id == user_id) {
+            restored.push(RestoredAccount { user_id, status: RestoreStatus::Online });
+        }
+    }
+    restored.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+    match (restored.is_empty(), last_error) {
+        (true, Some(e)) => Err(e),
+        _ => Ok(restored),
+    }
 }
 
-#[tauri::command]
-pub async fn send_typing(
-    room_id: String,
-    typing: bool,
-    state: State<'_, MatrixState>,
-) -> Result<(), String> {
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+/// Restore one account from its session file unless it is already signed in
+/// (then `None`), and check the session with the server.
+async fn restore_account(
+    session_path: &std::path::Path,
+    app: &tauri::AppHandle,
+    log: &std::sync::Arc<ServerLog>,
+    state: &State<'_, MatrixState>,
+) -> Result<Option<RestoredAccount>, String> {
+    let json = std::fs::read_to_string(session_path)
+        .map_err(|e| format!("Failed to read session: {}", e))?;
+    let mut saved: PersistedSession = serde_json::from_str(&json)
+        .map_err(|e| {
+            slog(app, log, "error", format!("Corrupt session file {}: {}", session_path.display(), e));
+            format!("Failed to parse session: {}", e)
+        })?;
 
-    let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
-        .map_err(|e| format!("Invalid room ID: {}", e))?;
-    let room = client.get_room(&room_id).ok_or("Room not found")?;
+    let store_dir = session_path.parent().ok_or("Session file has no account dir")?.to_path_buf();
+    if state.accounts.lock().await.contains_key(&saved.user_id) {
+        return Ok(None);
+    }
 
-    room.typing_notice(typing)
+    match saved.secret_backend {
+        Some(backend) => {
+            let secrets = state.secrets.load(&saved.user_id, &store_dir, backend).map_err(|e| {
+                slog(app, log, "error", format!("Failed to load tokens for {}: {}", saved.user_id, e));
+                e
+            })?;
+            saved.access_token = secrets.access_token;
+            saved.refresh_token = secrets.refresh_token;
+            saved.store_passphrase = secrets.store_passphrase;
+        }
+        // Written before the secret store existed — move the tokens out of the file
+        None => match save_session(&saved, &state.secrets) {
+            Ok(()) => slog(app, log, "info", format!("Moved tokens for {} out of plaintext session file", saved.user_id)),
+            Err(e) => slog(app, log, "warn", format!("Could not move tokens for {} to secret store: {}", saved.user_id, e)),
+        },
+    }
+
+    slog(app, log, "info", format!("Restoring saved session: user={}, homeserver={}", saved.user_id, saved.homeserver_url));
+
+    // A crash during store encryption leaves the old store in a backup dir
+    if let Err(e) = crate::store_encryption::recover_interrupted_swap(&store_dir, saved.store_passphrase.is_some()) {
+        slog(app, log, "error", format!("Failed to recover store for {}: {}", saved.user_id, e));
+        return Err(e);
+    }
+    if saved.store_passphrase.is_none() && state.secrets.encrypt_existing_stores() {
+        if let Err(e) = encrypt_account_store(&mut saved, &store_dir, app, log, state).await {
+            slog(app, log, "warn", format!("Keeping unencrypted store for {}: {}", saved.user_id, e));
+        }
+    }
+    let client = build_account_client(
+        &saved.homeserver_url,
+        &store_dir,
+        saved.store_passphrase.as_deref(),
+        app,
+        log,
+    ).await?;
+
+    let user_id = saved.user_id.clone();
+    let store_passphrase = saved.store_passphrase.clone();
+    let session = auth_session_from_persisted(saved)?;
+
+    client
+        .restore_session(session)
         .await
-        .map_err(|e| format!("Typing notice failed: {}", e))?;
+        .map_err(|e| {
+            slog(app, log, "error", format!("Session restore failed: {}", e));
+            format!("Failed to restore session: {}", e)
+        })?;
+
+    let account = Account::new(client.clone(), store_passphrase);
+    let status = match verify_session(&client, &user_id).await {
+        Ok(()) => RestoreStatus::Online,
+        Err(SessionCheck::Unreachable(e)) => {
+            slog(app, log, "warn", format!("Can't reach homeserver for {} ({}), starting offline", user_id, e));
+            RestoreStatus::Offline
+        }
+        Err(SessionCheck::Rejected { soft_logout: true }) => {
+            slog(app, log, "warn", format!("Session for {} expired, password needed", user_id));
+            account.mark_expired();
+            RestoreStatus::Expired
+        }
+        Err(SessionCheck::Rejected { soft_logout: false }) => {
+            slog(app, log, "error", format!("Server rejected the saved session for {}", user_id));
+            // The device is gone server-side; keep its store as a stale one
+            drop(account);
+            drop(client);
+            state.secrets.delete(&user_id, &store_dir);
+            if let Err(e) = crate::matrix_client::retire_account_dir(&store_dir) {
+                slog(app, log, "warn", format!("Failed to set aside store for {}: {}", user_id, e));
+                let _ = std::fs::remove_file(session_path);
+            }
+            return Err(crate::matrix_client::SESSION_REJECTED.to_string());
+        }
+    };
+
+    slog(app, log, "info", format!("Session restored — user={} ({:?})", user_id, status));
+    state.accounts.lock().await.insert(user_id.clone(), account);
+    Ok(Some(RestoredAccount { user_id, status }))
+}
+
+enum SessionCheck {
+    /// No answer, or not one about the token; the session may still be good.
+    Unreachable(String),
+    Rejected { soft_logout: bool },
+}
+
+/// Ask the server who the restored session belongs to. A token the client
+/// can't refresh is rejected; anything else that fails leaves the session to
+/// be tried again by sync.
+async fn verify_session(client: &Client, user_id: &str) -> Result<(), SessionCheck> {
+    let response = tokio::time::timeout(std::time::Duration::from_secs(10), client.whoami())
+        .await
+        .map_err(|_| SessionCheck::Unreachable("timed out".to_string()))?
+        .map_err(|e| match token_rejection(&e) {
+            Some(soft_logout) => SessionCheck::Rejected { soft_logout },
+            None => SessionCheck::Unreachable(e.to_string()),
+        })?;
+    if response.user_id.as_str() != user_id {
+        return Err(SessionCheck::Rejected { soft_logout: false });
+    }
     Ok(())
 }
 
-#[tauri::command]
-pub async fn mark_as_read(
-    room_id: String,
-    event_id: String,
-    state: State<'_, MatrixState>,
-) -> Result<(), String> {
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+/// OAuth 2.0 error codes for a refresh token or client the provider no
+/// longer accepts, as opposed to the provider being unreachable.
+const OAUTH_REJECTIONS: &[&str] = &["invalid_grant", "invalid_client", "unauthorized_client"];
 
-    let room_id_parsed = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
-        .map_err(|e| format!("Invalid room ID: {}", e))?;
-    let room = client.get_room(&room_id_parsed).ok_or("Room not found")?;
+fn oauth_refresh_rejected(message: &str) -> bool {
+    OAUTH_REJECTIONS.iter().any(|code| message.contains(code))
+}
 
-    let event_id_parsed = matrix_sdk::ruma::OwnedEventId::try_from(event_id.as_str())
-        .map_err(|e| format!("Invalid event ID: {}", e))?;
+/// `Some(soft_logout)` if the server no longer accepts the session's token,
+/// including when refreshing it failed that way.
+fn token_rejection(e: &matrix_sdk::HttpError) -> Option<bool> {
+    use matrix_sdk::ruma::api::client::error::ErrorKind;
+    use matrix_sdk::RefreshTokenError;
+
+    match e {
+        matrix_sdk::HttpError::RefreshToken(RefreshTokenError::MatrixAuth(inner)) => token_rejection(inner),
+        // An expired token with nothing to refresh it: signing in again keeps the device
+        matrix_sdk::HttpError::RefreshToken(RefreshTokenError::RefreshTokenRequired) => Some(true),
+        // The provider refused the refresh token, e.g. because it was revoked
+        matrix_sdk::HttpError::RefreshToken(RefreshTokenError::OAuth(error)) => {
+            oauth_refresh_rejected(&error.to_string()).then_some(false)
+        }
+        _ => match e.client_api_error_kind() {
+            Some(ErrorKind::UnknownToken { soft_logout }) => Some(*soft_logout),
+            _ => None,
+        },
+    }
+}
 
-    room.send_single_receipt(
-        matrix_sdk::ruma::api::client::receipt::create_receipt::v3::ReceiptType::Read,
-        matrix_sdk::ruma::events::receipt::ReceiptThread::Unthreaded,
-        event_id_parsed,
-    )
-    .await
-    .map_err(|e| format!("Read receipt failed: {}", e))?;
+/// Give an account saved without a store passphrase an encrypted store. An
+/// existing unencrypted store is copied over so the device keeps its keys.
+/// The passphrase is only saved once the encrypted copy has replaced the old
+/// store and opened with it; on any failure before that the unencrypted
+/// store is put back and the session stays without a passphrase.
+async fn encrypt_account_store(
+    saved: &mut PersistedSession,
+    store_dir: &std::path::Path,
+    app: &tauri::AppHandle,
+    log: &std::sync::Arc<ServerLog>,
+    state: &State<'_, MatrixState>,
+) -> Result<(), String> {
+    let passphrase = crate::store_encryption::generate_store_passphrase();
+    let swap = if crate::store_encryption::has_store(store_dir) {
+        slog(app, log, "info", format!("Encrypting existing store for {}...", saved.user_id));
+        let staging = crate::store_encryption::copy_to_encrypted_store(store_dir, &passphrase).await?;
+        Some(crate::store_encryption::swap_in_encrypted_store(store_dir, &staging, &passphrase).await?)
+    } else {
+        None
+    };
 
+    let encrypted = PersistedSession {
+        store_passphrase: Some(passphrase),
+        ..saved.clone()
+    };
+    if let Err(e) = save_session(&encrypted, &state.secrets) {
+        // The secrets may have been written before the session file failed
+        let _ = save_session(saved, &state.secrets);
+        if let Some(swap) = swap {
+            swap.roll_back().map_err(|rollback| {
+                slog(app, log, "error", format!("Failed to put back unencrypted store for {}: {}", saved.user_id, rollback));
+                format!("{}; {}", e, rollback)
+            })?;
+        }
+        return Err(e);
+    }
+    if let Some(swap) = swap {
+        swap.commit();
+        slog(app, log, "info", format!("Store for {} is now encrypted", saved.user_id));
+    }
+    *saved = encrypted;
     Ok(())
 }
 
 #[tauri::command]
-pub async fn start_sync(
+pub async fn get_user_profile(
+    account: String,
+    user_id: String,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
-) -> Result<(), String> {
+) -> Result<UserProfile, String> {
     let log = state.log.clone();
-    slog(&app, &log, "info", "start_sync: beginning background sync...".into());
-
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?.clone();
-    drop(client_lock);
+    slog(&app, &log, "info", format!("get_user_profile: {}", user_id));
 
-    let app_handle = app.clone();
+    let client = state.client(&account).await?;
 
-    // Abort any existing sync tasks before starting new ones
-    state.abort_sync_tasks();
+    let parsed_user_id = matrix_sdk::ruma::UserId::parse(&user_id)
+        .map_err(|e| format!("Invalid user ID: {}", e))?;
 
-    // Poll for room list changes (new rooms joined from other clients)
-    let poll_client = client.clone();
-    let poll_app = app.clone();
-    let poll_log = log.clone();
-    let poll_task = tokio::spawn(async move {
-        let mut known_ids: std::collections::HashSet<String> = poll_client
-            .joined_rooms()
-            .iter()
-            .map(|r| r.room_id().to_string())
-            .collect();
-        loop {
-            tokio::time::sleep(std::time::Duration::from_secs(3)).await;
-            let current_ids: std::collections::HashSet<String> = poll_client
-                .joined_rooms()
-                .iter()
-                .map(|r| r.room_id().to_string())
-                .collect();
-            if current_ids != known_ids {
-                slog(&poll_app, &poll_log, "info", format!("Room list changed: {} -> {} rooms", known_ids.len(), current_ids.len()));
-                known_ids = current_ids;
-                let _ = poll_app.emit("rooms_changed", "");
+    // Fetch profile (display name + avatar)
+    let mut display_name = user_id.clone();
+    let mut avatar_url: Option<String> = None;
+    let mut mxc_avatar: Option<String> = None;
+    {
+        use matrix_sdk::ruma::api::client::profile::get_profile;
+        use matrix_sdk::ruma::api::client::profile::{AvatarUrl, DisplayName};
+        let request = get_profile::v3::Request::new(parsed_user_id.clone());
+        match tokio::time::timeout(std::time::Duration::from_secs(5), client.send(request)).await {
+            Ok(Ok(response)) => {
+                if let Ok(Some(name)) = response.get_static::<DisplayName>() {
+                    display_name = name;
+                }
+                if let Ok(Some(url)) = response.get_static::<AvatarUrl>() {
+                    mxc_avatar = Some(url.to_string());
+                }
+            }
+            Ok(Err(e)) => {
+                slog(&app, &log, "warn", format!("Profile fetch failed: {}", e));
+            }
+            Err(_) => {
+                slog(&app, &log, "warn", "Profile fetch timed out".into());
             }
         }
-    });
+    }
 
-    // Verification request handler
-    let verify_app = app.clone();
-    let verify_client = client.clone();
-    let verify_log = log.clone();
+    // Download avatar via authenticated media endpoint → base64 data URL
+    if let Some(mxc) = &mxc_avatar {
+        avatar_url = fetch_avatar_data_url(&client, mxc).await;
+    }
 
-    let sync_log = log.clone();
-    let sync_app = app.clone();
+    // Fetch presence + last_active_ago
+    let mut presence = "unknown".to_string();
+    let mut last_seen_ago: Option<u64> = None;
+    {
+        use matrix_sdk::ruma::api::client::presence::get_presence;
+        let request = get_presence::v3::Request::new(parsed_user_id.clone());
+        match tokio::time::timeout(std::time::Duration::from_secs(3), client.send(request)).await {
+            Ok(Ok(response)) => {
+                let is_stale = response.currently_active.is_none()
+                    && response.last_active_ago.is_none()
+                    && response.presence == matrix_sdk::ruma::presence::PresenceState::Offline;
+                if !is_stale {
+                    presence = match response.presence {
+                        matrix_sdk::ruma::presence::PresenceState::Online => "online",
+                        matrix_sdk::ruma::presence::PresenceState::Unavailable => "away",
+                        _ => "offline",
+                    }
+                    .to_string();
+                    last_seen_ago = response.last_active_ago.map(|d| d.as_secs());
+                }
+            }
+            _ => {}
+        }
+    }
 
-    let sync_task = tokio::spawn(async move {
-        // Handle incoming verification requests
-        let va = verify_app.clone();
-        let vc = verify_client.clone();
-        let vl = verify_log.clone();
-        verify_client.add_event_handler(
-            move |event: matrix_sdk::ruma::events::key::verification::request::ToDeviceKeyVerificationRequestEvent| {
-                let app = va.clone();
-                let client = vc.clone();
-                let log = vl.clone();
-                async move {
-                    let user_id = event.sender;
-                    let flow_id = event.content.transaction_id.to_string();
-                    slog(&app, &log, "info", format!("Verification request from {} (flow={})", user_id, flow_id));
-                    if let Some(request) = client
+    // Scan joined rooms for shared membership
+    let mut shared_rooms = Vec::new();
+    for room in client.joined_rooms() {
+        let members = room
+            .members(matrix_sdk::RoomMemberships::ACTIVE)
+            .await
+            .unwrap_or_default();
+        let has_user = members.iter().any(|m| m.user_id() == parsed_user_id);
+        if has_user {
+            let is_direct = room.is_direct().await.unwrap_or(false);
+            let name = resolve_room_name(&client, &room, is_direct).await;
+            shared_rooms.push(SharedRoom {
+                room_id: room.room_id().to_string(),
+                name,
+            });
+        }
+    }
+
+    // Cross-signing identity and devices, refreshed from the server if it answers
+    let encryption = client.encryption();
+    let identity = match tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        encryption.request_user_identity(&parsed_user_id),
+    ).await {
+        Ok(Ok(identity)) => identity,
+        _ => encryption.get_user_identity(&parsed_user_id).await.ok().flatten(),
+    };
+    let verified = identity.as_ref().is_some_and(|i| i.is_verified());
+    let identity_changed = identity
+        .as_ref()
+        .is_some_and(|i| i.has_verification_violation() || identity_replaced(&client, &account, i));
+    let mut devices: Vec<UserDevice> = match encryption.get_user_devices(&parsed_user_id).await {
+        Ok(devices) => devices
+            .devices()
+            .map(|d| UserDevice {
+                device_id: d.device_id().to_string(),
+                display_name: d.display_name().map(str::to_string),
+                verified: d.is_verified(),
+            })
+            .collect(),
+        Err(e) => {
+            slog(&app, &log, "warn", format!("Failed to load devices of {}: {}", user_id, e));
+            Vec::new()
+        }
+    };
+    devices.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+
+    slog(
+        &app,
+        &log,
+        "info",
+        format!(
+            "get_user_profile: {} — presence={}, shared_rooms={}, devices={}, verified={}, identity_changed={}",
+            user_id,
+            presence,
+            shared_rooms.len(),
+            devices.len(),
+            verified,
+            identity_changed,
+        ),
+    );
+
+    Ok(UserProfile {
+        user_id,
+        display_name,
+        avatar_url,
+        presence,
+        last_seen_ago,
+        shared_rooms,
+        verified,
+        identity_changed,
+        devices,
+    })
+}
+
+/// Whether a user's master key replaces the one we remembered for them. The
+/// first key seen is remembered.
+fn identity_replaced(client: &Client, account: &str, identity: &matrix_sdk::encryption::identities::UserIdentity) -> bool {
+    let Some(master_key) = identity.master_key().get_first_key().map(|k| k.to_base64()) else {
+        return false;
+    };
+    let Ok(dir) = crate::matrix_client::account_dir(account, client.homeserver().as_str()) else {
+        return false;
+    };
+    let mut known = KnownIdentities::load(&dir);
+    let replaced = known.check(identity.user_id().as_str(), &master_key);
+    if !replaced {
+        let _ = known.save(&dir);
+    }
+    replaced
+}
+
+/// Trust a contact's new identity: remember its master key and clear the
+/// SDK's pin or verification violation.
+#[tauri::command]
+pub async fn accept_identity_change(
+    account: String,
+    user_id: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<(), String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("accept_identity_change: {}", user_id));
+
+    let client = state.client(&account).await?;
+    let parsed_user_id = matrix_sdk::ruma::UserId::parse(&user_id)
+        .map_err(|e| format!("Invalid user ID: {}", e))?;
+    let identity = client
+        .encryption()
+        .get_user_identity(&parsed_user_id)
+        .await
+        .map_err(|e| format!("Failed to load identity: {}", e))?
+        .ok_or("User has no cross-signing identity")?;
+
+    if identity.has_verification_violation() {
+        identity.withdraw_verification().await
+    } else {
+        identity.pin().await
+    }
+    .map_err(|e| format!("Failed to accept identity: {}", e))?;
+
+    if let Some(master_key) = identity.master_key().get_first_key().map(|k| k.to_base64()) {
+        let dir = crate::matrix_client::account_dir(&account, client.homeserver().as_str())?;
+        let mut known = KnownIdentities::load(&dir);
+        known.accept(&user_id, &master_key);
+        known.save(&dir)?;
+    }
+
+    let _ = app.emit("identity_changed", IdentityChangedEvent {
+        account,
+        user_id,
+        changed: false,
+    });
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_room_info(
+    account: String,
+    room_id: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<RoomProfile, String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("get_room_info: {}", room_id));
+
+    let client = state.client(&account).await?;
+
+    let room_id_parsed = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id_parsed).ok_or("Room not found")?;
+
+    let is_direct = room.is_direct().await.unwrap_or(false);
+    let name = resolve_room_name(&client, &room, is_direct).await;
+
+    let topic = room.topic();
+
+    let members = room
+        .members(matrix_sdk::RoomMemberships::ACTIVE)
+        .await
+        .unwrap_or_default();
+    let member_count = members.len() as u64;
+    let is_encrypted = room.latest_encryption_state().await.is_ok_and(|s| s.is_encrypted());
+
+    Ok(RoomProfile {
+        room_id,
+        name,
+        topic,
+        is_direct,
+        is_encrypted,
+        member_count,
+    })
+}
+
+/// Turn on encryption for an existing room. Rooms can't be switched back.
+#[tauri::command]
+pub async fn enable_room_encryption(
+    account: String,
+    room_id: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<(), String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("enable_room_encryption: {}", room_id));
+
+    let client = state.client(&account).await?;
+
+    let room_id_parsed = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id_parsed).ok_or("Room not found")?;
+
+    room.enable_encryption().await.map_err(|e| {
+        slog(&app, &log, "error", format!("Failed to enable encryption in {}: {}", room_id, e));
+        format!("Failed to enable encryption: {}", e)
+    })?;
+    slog(&app, &log, "info", format!("Encryption enabled in {}", room_id));
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn create_dm_room(
+    account: String,
+    user_id: String,
+    encrypted: Option<bool>,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<Room, String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("create_dm_room: {}", user_id));
+    let encrypted = encrypted.unwrap_or(true);
+
+    let client = state.client(&account).await?;
+
+    let parsed_user_id = matrix_sdk::ruma::UserId::parse(&user_id)
+        .map_err(|e| format!("Invalid user ID: {}", e))?;
+
+    use matrix_sdk::ruma::api::client::room::create_room::v3::Request as CreateRoomRequest;
+    let mut request = CreateRoomRequest::new();
+    request.invite = vec![parsed_user_id];
+    request.is_direct = true;
+    request.preset = Some(matrix_sdk::ruma::api::client::room::create_room::v3::RoomPreset::TrustedPrivateChat);
+    if encrypted {
+        request.initial_state = vec![encryption_initial_state()];
+    }
+
+    let response = client
+        .create_room(request)
+        .await
+        .map_err(|e| {
+            slog(&app, &log, "error", format!("Failed to create DM room: {}", e));
+            format!("Failed to create room: {}", e)
+        })?;
+
+    let room_id = response.room_id().to_string();
+    slog(&app, &log, "info", format!("DM room created: {}", room_id));
+
+    // Get the room to fetch its display name
+    let name = if let Some(room) = client.get_room(response.room_id()) {
+        room.display_name()
+            .await
+            .map(|n| n.to_string())
+            .unwrap_or_else(|_| user_id.clone())
+    } else {
+        user_id.clone()
+    };
+
+    Ok(Room {
+        account: account.clone(),
+        room_id,
+        name,
+        is_direct: true,
+        is_encrypted: encrypted,
+        last_message: None,
+        unread_count: 0,
+    })
+}
+
+/// Initial state that turns on Megolm encryption for a room being created.
+fn encryption_initial_state() -> matrix_sdk::ruma::serde::Raw<matrix_sdk::ruma::events::AnyInitialStateEvent> {
+    use matrix_sdk::ruma::events::{room::encryption::RoomEncryptionEventContent, InitialStateEvent};
+
+    InitialStateEvent::with_empty_state_key(RoomEncryptionEventContent::with_recommended_defaults()).to_raw_any()
+}
+
+#[tauri::command]
+pub async fn get_buddy_list(
+    account: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<Vec<Buddy>, String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", "get_buddy_list: running sync_once...".into());
+
+    let client = state.client(&account).await?;
+
+    with_heartbeat(&app, &log, "sync_once", client.sync_once(Default::default()))
+        .await
+        .map_err(|e| {
+            slog(&app, &log, "error", format!("sync_once failed: {}", e));
+            format!("Sync failed: {}", e)
+        })?;
+
+    slog(&app, &log, "info", "sync_once complete, scanning rooms...".into());
+
+    let joined = client.joined_rooms();
+    slog(&app, &log, "info", format!("Found {} joined rooms", joined.len()));
+
+    let mut buddies = Vec::new();
+    let mut seen_user_ids = std::collections::HashSet::new();
+    let mut presence_supported = true;
+    let my_user_id = client.user_id().map(|u| u.to_string()).unwrap_or_default();
+
+    for room in joined {
+        if room.is_direct().await.unwrap_or(false) {
+            let members = room
+                .members(matrix_sdk::RoomMemberships::ACTIVE)
+                .await
+                .unwrap_or_default();
+            for member in members {
+                let user_id = member.user_id().to_string();
+                if user_id != my_user_id && seen_user_ids.insert(user_id.clone()) {
+                    let presence = if presence_supported {
+                        match fetch_user_presence(&client, member.user_id()).await {
+                            Some(p) => p,
+                            None => {
+                                slog(&app, &log, "warn", "Presence not supported by server, skipping remaining".into());
+                                presence_supported = false;
+                                "unknown".to_string()
+                            }
+                        }
+                    } else {
+                        "unknown".to_string()
+                    };
+                    let hs = client.homeserver().to_string();
+                    buddies.push(Buddy {
+                        account: account.clone(),
+                        user_id: user_id.clone(),
+                        display_name: member.display_name().unwrap_or(&user_id).to_string(),
+                        avatar_url: member.avatar_url().and_then(|u| mxc_to_http(&hs, &u.to_string())),
+                        presence,
+                    });
+                }
+            }
+        }
+    }
+    slog(&app, &log, "info", format!("get_buddy_list: returning {} buddies", buddies.len()));
+    Ok(buddies)
+}
+
+#[tauri::command]
+pub async fn get_room_members(
+    account: String,
+    room_id: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<Vec<Buddy>, String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("get_room_members: {}", room_id));
+
+    let client = state.client(&account).await?;
+
+    let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let members = with_heartbeat(
+        &app, &log, "get_room_members",
+        room.members(matrix_sdk::RoomMemberships::ACTIVE),
+    )
+        .await
+        .map_err(|e| {
+            slog(&app, &log, "error", format!("Failed to get members: {}", e));
+            format!("Failed to get members: {}", e)
+        })?;
+
+    slog(&app, &log, "info", format!("Room has {} active members", members.len()));
+
+    let hs = client.homeserver().to_string();
+    let buddies: Vec<Buddy> = members
+        .iter()
+        .map(|member| {
+            let user_id = member.user_id().to_string();
+            Buddy {
+                account: account.clone(),
+                display_name: member.display_name().unwrap_or(&user_id).to_string(),
+                avatar_url: member.avatar_url().and_then(|u| mxc_to_http(&hs, &u.to_string())),
+                presence: "offline".to_string(),
+                user_id,
+            }
+        })
+        .collect();
+
+    slog(&app, &log, "info", format!("get_room_members: returning {} members", buddies.len()));
+    Ok(buddies)
+}
+
+#[tauri::command]
+pub async fn get_rooms(
+    account: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<Vec<Room>, String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", "get_rooms: fetching joined rooms...".into());
+
+    let client = state.client(&account).await?;
+
+    let mut rooms = Vec::new();
+    for room in client.joined_rooms() {
+        // Skip space rooms — they are handled by get_spaces
+        if room.is_space() {
+            continue;
+        }
+        let is_direct = room.is_direct().await.unwrap_or(false);
+        rooms.push(Room {
+            account: account.clone(),
+            room_id: room.room_id().to_string(),
+            name: resolve_room_name(&client, &room, is_direct).await,
+            is_direct,
+            is_encrypted: room.encryption_state().is_encrypted(),
+            last_message: None,
+            unread_count: 0,
+        });
+    }
+    slog(&app, &log, "info", format!("get_rooms: returning {} rooms", rooms.len()));
+    Ok(rooms)
+}
+
+#[tauri::command]
+pub async fn get_spaces(
+    account: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<Vec<Space>, String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", "get_spaces: fetching joined spaces...".into());
+
+    let client = state.client(&account).await?;
+
+    // Collect all joined room IDs for filtering children to only joined rooms
+    let joined_room_ids: std::collections::HashSet<String> = client
+        .joined_rooms()
+        .iter()
+        .map(|r| r.room_id().to_string())
+        .collect();
+
+    let mut spaces = Vec::new();
+    for room in client.joined_rooms() {
+        if !room.is_space() {
+            continue;
+        }
+
+        let name = resolve_room_name(&client, &room, false).await;
+
+        // Read m.space.child state events from the local store
+        let mut child_room_ids = Vec::new();
+        use matrix_sdk::deserialized_responses::SyncOrStrippedState;
+        use matrix_sdk::ruma::events::space::child::SpaceChildEventContent;
+        use matrix_sdk::ruma::events::SyncStateEvent;
+
+        match room.get_state_events_static::<SpaceChildEventContent>().await {
+            Ok(raw_events) => {
+                for raw_event in raw_events {
+                    match raw_event.deserialize() {
+                        Ok(SyncOrStrippedState::Sync(SyncStateEvent::Original(e))) => {
+                            let child_id = e.state_key.to_string();
+                            if !e.content.via.is_empty() && joined_room_ids.contains(&child_id) {
+                                child_room_ids.push(child_id);
+                            }
+                        }
+                        Ok(SyncOrStrippedState::Sync(SyncStateEvent::Redacted(_))) => {}
+                        Ok(SyncOrStrippedState::Stripped(e)) => {
+                            let child_id = e.state_key.to_string();
+                            let via_non_empty = e.content.via.as_ref().is_some_and(|v| !v.is_empty());
+                            if via_non_empty && joined_room_ids.contains(&child_id) {
+                                child_room_ids.push(child_id);
+                            }
+                        }
+                        Err(e) => {
+                            slog(&app, &log, "warn", format!("get_spaces: failed to deserialize space child event: {}", e));
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                slog(&app, &log, "warn", format!("get_spaces: failed to read space children for {}: {}", room.room_id(), e));
+            }
+        }
+
+        spaces.push(Space {
+            account: account.clone(),
+            room_id: room.room_id().to_string(),
+            name,
+            child_room_ids,
+        });
+    }
+
+    slog(&app, &log, "info", format!("get_spaces: returning {} spaces", spaces.len()));
+    Ok(spaces)
+}
+
+#[tauri::command]
+pub async fn get_room_messages(
+    account: String,
+    room_id: String,
+    limit: u64,
+    from: Option<String>,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<MessagesPage, String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("get_room_messages: {} (from={:?})", room_id, from));
+
+    let client = state.client(&account).await?;
+
+    let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    slog(&app, &log, "info", "Fetching messages from server...".into());
+    let mut options = matrix_sdk::room::MessagesOptions::backward();
+    if let Some(ref token) = from {
+        options.from = Some(token.clone());
+    }
+    if let Some(l) = matrix_sdk::ruma::UInt::new(limit) {
+        options.limit = l;
+    }
+    let messages_response = with_heartbeat(
+        &app, &log, "messages",
+        room.messages(options),
+    )
+        .await
+        .map_err(|e| {
+            slog(&app, &log, "error", format!("Failed to get messages: {}", e));
+            format!("Failed to get messages: {}", e)
+        })?;
+
+    let end_token = messages_response.end;
+
+    let (pending, replies) =
+        state.accounts.lock().await.get(&account).map(|a| (a.undecrypted.clone(), a.replies.clone())).unzip();
+    let (mut messages, edit_count) =
+        collect_messages(&account, room_id.as_str(), &messages_response.chunk, pending.as_deref());
+    if let Some(replies) = &replies {
+        resolve_replies(&room, &account, replies, &mut messages).await;
+    }
+
+    messages.reverse();
+    slog(&app, &log, "info", format!("get_room_messages: returning {} messages ({} edits applied)", messages.len(), edit_count));
+    Ok(MessagesPage { messages, end_token })
+}
+
+#[tauri::command]
+pub async fn get_room_threads(
+    account: String,
+    room_id: String,
+    limit: u64,
+    from: Option<String>,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<ThreadsPage, String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("get_room_threads: {} (from={:?})", room_id, from));
+
+    let client = state.client(&account).await?;
+
+    let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let options = matrix_sdk::room::ListThreadsOptions {
+        from,
+        limit: matrix_sdk::ruma::UInt::new(limit),
+        ..Default::default()
+    };
+    let response = room.list_threads(options).await.map_err(|e| {
+        slog(&app, &log, "error", format!("Failed to list threads: {}", e));
+        format!("Failed to list threads: {}", e)
+    })?;
+
+    let pending = state.accounts.lock().await.get(&account).map(|a| a.undecrypted.clone());
+    let to_message = |event: &matrix_sdk::deserialized_responses::TimelineEvent| {
+        collect_messages(&account, room_id.as_str(), std::slice::from_ref(event), pending.as_deref()).0.pop()
+    };
+
+    // Roots come newest thread first, each with the server's summary bundled
+    let threads: Vec<RoomThread> = response
+        .chunk
+        .iter()
+        .filter_map(|event| {
+            Some(RoomThread {
+                root: to_message(event)?,
+                reply_count: event.thread_summary.summary().map_or(0, |summary| summary.num_replies),
+                latest_reply: event.bundled_latest_thread_event.as_deref().and_then(to_message),
+            })
+        })
+        .collect();
+
+    slog(&app, &log, "info", format!("get_room_threads: returning {} threads", threads.len()));
+    Ok(ThreadsPage { threads, end_token: response.prev_batch_token })
+}
+
+/// Replies in a thread, newest page first like `get_room_messages`; the root
+/// itself isn't included.
+#[tauri::command]
+pub async fn get_thread_messages(
+    account: String,
+    room_id: String,
+    thread_root: String,
+    limit: u64,
+    from: Option<String>,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<MessagesPage, String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("get_thread_messages: {} in {} (from={:?})", thread_root, room_id, from));
+
+    let client = state.client(&account).await?;
+
+    let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+    let root = matrix_sdk::ruma::OwnedEventId::try_from(thread_root.as_str())
+        .map_err(|e| format!("Invalid thread root: {}", e))?;
+
+    let options = matrix_sdk::room::RelationsOptions {
+        from,
+        limit: matrix_sdk::ruma::UInt::new(limit),
+        include_relations: matrix_sdk::room::IncludeRelations::RelationsOfType(
+            matrix_sdk::ruma::events::relation::RelationType::Thread,
+        ),
+        ..Default::default()
+    };
+    let relations = room.relations(root, options).await.map_err(|e| {
+        slog(&app, &log, "error", format!("Failed to get thread: {}", e));
+        format!("Failed to get thread: {}", e)
+    })?;
+
+    let (pending, replies) =
+        state.accounts.lock().await.get(&account).map(|a| (a.undecrypted.clone(), a.replies.clone())).unzip();
+    let (mut messages, _) = collect_messages(&account, room_id.as_str(), &relations.chunk, pending.as_deref());
+    if let Some(replies) = &replies {
+        resolve_replies(&room, &account, replies, &mut messages).await;
+    }
+
+    messages.reverse();
+    slog(&app, &log, "info", format!("get_thread_messages: returning {} messages", messages.len()));
+    Ok(MessagesPage { messages, end_token: relations.next_batch_token })
+}
+
+#[tauri::command]
+pub async fn send_message(
+    account: String,
+    room_id: String,
+    body: String,
+    in_reply_to_event_id: Option<String>,
+    thread_root: Option<String>,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<SendOutcome, String> {
+    use matrix_sdk::ruma::events::relation::{InReplyTo, Thread};
+    use matrix_sdk::ruma::events::room::message::{Relation, RoomMessageEventContent};
+
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("send_message: room={}, len={}, reply={:?}, thread={:?}", room_id, body.len(), in_reply_to_event_id, thread_root));
+
+    let command = slash_commands::parse(&body)?;
+
+    let client = state.client(&account).await?;
+
+    let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    // Markdown only adds a formatted_body when it changes something
+    let mut content = match command {
+        SlashCommand::Text(text) => RoomMessageEventContent::text_markdown(text),
+        SlashCommand::Me(action) => RoomMessageEventContent::emote_markdown(action),
+        SlashCommand::Notice(text) => RoomMessageEventContent::notice_markdown(text),
+        // Plain text, or Markdown would eat the shrug's backslash
+        SlashCommand::Shrug(text) if text.is_empty() => RoomMessageEventContent::text_plain(slash_commands::SHRUG),
+        SlashCommand::Shrug(text) => RoomMessageEventContent::text_plain(format!("{} {}", text, slash_commands::SHRUG)),
+        command => return run_slash_command(command, account, room, app, state).await,
+    };
+
+    let in_reply_to = in_reply_to_event_id
+        .map(|id| matrix_sdk::ruma::OwnedEventId::try_from(id.as_str()))
+        .transpose()
+        .map_err(|e| format!("Invalid event ID: {}", e))?;
+
+    if let Some(root) = thread_root {
+        let root = matrix_sdk::ruma::OwnedEventId::try_from(root.as_str())
+            .map_err(|e| format!("Invalid thread root: {}", e))?;
+        let thread = match in_reply_to {
+            Some(event_id) => Thread::reply(root, event_id),
+            None => {
+                let latest = latest_thread_event(&room, &root).await.unwrap_or_else(|| root.clone());
+                Thread::plain(root, latest)
+            }
+        };
+        content.relates_to = Some(Relation::Thread(thread));
+    } else if let Some(event_id) = in_reply_to {
+        content.relates_to = Some(Relation::Reply { in_reply_to: InReplyTo::new(event_id) });
+    }
+
+    room.send(content)
+        .await
+        .map_err(|e| {
+            slog(&app, &log, "error", format!("Send failed: {}", e));
+            format!("Send failed: {}", e)
+        })?;
+
+    slog(&app, &log, "info", "Message sent OK".into());
+    Ok(SendOutcome::Sent)
+}
+
+/// Latest event in a thread, which a new thread message names as its reply
+/// fallback for clients without threads.
+async fn latest_thread_event(
+    room: &matrix_sdk::Room,
+    root: &matrix_sdk::ruma::OwnedEventId,
+) -> Option<matrix_sdk::ruma::OwnedEventId> {
+    let options = matrix_sdk::room::RelationsOptions {
+        limit: Some(matrix_sdk::ruma::uint!(1)),
+        include_relations: matrix_sdk::room::IncludeRelations::RelationsOfType(
+            matrix_sdk::ruma::events::relation::RelationType::Thread,
+        ),
+        ..Default::default()
+    };
+    room.relations(root.clone(), options).await.ok()?.chunk.first()?.event_id()
+}
+
+/// Carry out a slash command that isn't itself a message in `room`.
+async fn run_slash_command(
+    command: SlashCommand,
+    account: String,
+    room: matrix_sdk::Room,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<SendOutcome, String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("slash command: {:?}", command));
+    let client = state.client(&account).await?;
+
+    let parse_user = |user: &str| {
+        matrix_sdk::ruma::UserId::parse(user).map_err(|e| format!("Invalid user ID: {}", e))
+    };
+    let failed = |what: &str, e: matrix_sdk::Error| {
+        slog(&app, &log, "error", format!("{} failed: {}", what, e));
+        format!("{} failed: {}", what, e)
+    };
+
+    match command {
+        SlashCommand::Join(target) => {
+            let room = join_room(account, target, app.clone(), state).await?;
+            Ok(SendOutcome::OpenRoom { room })
+        }
+        SlashCommand::Part(target) => {
+            let room = match target {
+                None => room,
+                Some(target) => {
+                    let id = matrix_sdk::ruma::OwnedRoomOrAliasId::try_from(target.as_str())
+                        .map_err(|e| format!("Invalid room ID or alias: {}", e))?;
+                    let room_id = match matrix_sdk::ruma::OwnedRoomId::try_from(id.clone()) {
+                        Ok(room_id) => room_id,
+                        Err(alias) => client
+                            .resolve_room_alias(&alias)
+                            .await
+                            .map_err(|e| format!("Could not find {}: {}", alias, e))?
+                            .room_id,
+                    };
+                    client.get_room(&room_id).ok_or("Room not found")?
+                }
+            };
+            room.leave().await.map_err(|e| failed("Leave", e))?;
+            Ok(SendOutcome::Left { room_id: room.room_id().to_string() })
+        }
+        SlashCommand::Invite(user) => {
+            room.invite_user_by_id(&parse_user(&user)?).await.map_err(|e| failed("Invite", e))?;
+            Ok(SendOutcome::Sent)
+        }
+        SlashCommand::Nick(name) => {
+            client.account().set_display_name(Some(&name)).await.map_err(|e| failed("Nick change", e))?;
+            Ok(SendOutcome::Sent)
+        }
+        SlashCommand::Topic(topic) => {
+            room.set_room_topic(&topic).await.map_err(|e| failed("Topic change", e))?;
+            Ok(SendOutcome::Sent)
+        }
+        SlashCommand::Ignore(user) => {
+            client.account().ignore_user(&parse_user(&user)?).await.map_err(|e| failed("Ignore", e))?;
+            Ok(SendOutcome::Sent)
+        }
+        SlashCommand::Msg { user, body } => {
+            let user_id = parse_user(&user)?;
+            let dm = match client.get_dm_room(&user_id) {
+                Some(dm) => Room {
+                    account: account.clone(),
+                    room_id: dm.room_id().to_string(),
+                    name: dm.display_name().await.map(|n| n.to_string()).unwrap_or_else(|_| user.clone()),
+                    is_direct: true,
+                    is_encrypted: dm.encryption_state().is_encrypted(),
+                    last_message: None,
+                    unread_count: 0,
+                },
+                None => create_dm_room(account.clone(), user, None, app.clone(), state.clone()).await?,
+            };
+            let dm_room = client
+                .get_room(&matrix_sdk::ruma::OwnedRoomId::try_from(dm.room_id.as_str()).map_err(|e| e.to_string())?)
+                .ok_or("Room not found")?;
+            dm_room
+                .send(matrix_sdk::ruma::events::room::message::RoomMessageEventContent::text_markdown(body))
+                .await
+                .map_err(|e| failed("Send", e))?;
+            Ok(SendOutcome::OpenRoom { room: dm })
+        }
+        SlashCommand::Text(_) | SlashCommand::Me(_) | SlashCommand::Notice(_) | SlashCommand::Shrug(_) => {
+            Err("Not a room command".to_string())
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn edit_message(
+    account: String,
+    room_id: String,
+    event_id: String,
+    new_body: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<(), String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("edit_message: room={}, event={}", room_id, event_id));
+
+    let client = state.client(&account).await?;
+
+    let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    // Build the edit event as raw JSON — the SDK's Replacement type is non_exhaustive/private
+    let mut new_content = serde_json::json!({
+        "msgtype": "m.text",
+        "body": new_body,
+    });
+    let mut raw_content = serde_json::json!({
+        "msgtype": "m.text",
+        "body": format!("* {}", new_body),
+        "m.relates_to": {
+            "rel_type": "m.replace",
+            "event_id": event_id,
+        }
+    });
+    if let Some(formatted) = matrix_sdk::ruma::events::room::message::FormattedBody::markdown(&new_body) {
+        new_content["format"] = "org.matrix.custom.html".into();
+        new_content["formatted_body"] = formatted.body.clone().into();
+        raw_content["format"] = "org.matrix.custom.html".into();
+        raw_content["formatted_body"] = format!("* {}", formatted.body).into();
+    }
+    raw_content["m.new_content"] = new_content;
+    let content: matrix_sdk::ruma::events::room::message::RoomMessageEventContent =
+        serde_json::from_value(raw_content)
+            .map_err(|e| format!("Failed to build edit content: {}", e))?;
+
+    room.send(content)
+        .await
+        .map_err(|e| {
+            slog(&app, &log, "error", format!("Edit failed: {}", e));
+            format!("Edit failed: {}", e)
+        })?;
+
+    slog(&app, &log, "info", "Message edited OK".into());
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn delete_message(
+    account: String,
+    room_id: String,
+    event_id: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<(), String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("delete_message: room={}, event={}", room_id, event_id));
+
+    let client = state.client(&account).await?;
+
+    let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let event_id = matrix_sdk::ruma::OwnedEventId::try_from(event_id.as_str())
+        .map_err(|e| format!("Invalid event ID: {}", e))?;
+
+    room.redact(&event_id, None, None)
+        .await
+        .map_err(|e| {
+            slog(&app, &log, "error", format!("Delete failed: {}", e));
+            format!("Delete failed: {}", e)
+        })?;
+
+    slog(&app, &log, "info", "Message deleted OK".into());
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn send_reaction(
+    account: String,
+    room_id: String,
+    event_id: String,
+    reaction_key: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<(), String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("send_reaction: room={}, event={}, key={}", room_id, event_id, reaction_key));
+
+    let client = state.client(&account).await?;
+
+    let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let event_id = matrix_sdk::ruma::OwnedEventId::try_from(event_id.as_str())
+        .map_err(|e| format!("Invalid event ID: {}", e))?;
+
+    let content = matrix_sdk::ruma::events::reaction::ReactionEventContent::new(
+        matrix_sdk::ruma::events::relation::Annotation::new(event_id, reaction_key),
+    );
+
+    room.send(content)
+        .await
+        .map_err(|e| {
+            slog(&app, &log, "error", format!("Reaction failed: {}", e));
+            format!("Reaction failed: {}", e)
+        })?;
+
+    slog(&app, &log, "info", "Reaction sent OK".into());
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn set_presence(
+    account: String,
+    status: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<(), String> {
+    let log = state.log.clone();
+    let client = state.client(&account).await?;
+
+    // Map ICQ status names to Matrix presence states
+    let presence = match status.as_str() {
+        "online" | "free_for_chat" => matrix_sdk::ruma::presence::PresenceState::Online,
+        "away" | "na" => matrix_sdk::ruma::presence::PresenceState::Unavailable,
+        "occupied" | "dnd" => matrix_sdk::ruma::presence::PresenceState::Unavailable,
+        "invisible" | "offline" => matrix_sdk::ruma::presence::PresenceState::Offline,
+        _ => matrix_sdk::ruma::presence::PresenceState::Online,
+    };
+
+    use matrix_sdk::ruma::api::client::presence::set_presence;
+    let user_id = client.user_id().ok_or("No user ID")?.to_owned();
+    let mut request = set_presence::v3::Request::new(user_id, presence.clone());
+    // Set a status message for non-standard ICQ statuses
+    match status.as_str() {
+        "dnd" => request.status_msg = Some("Do Not Disturb".to_string()),
+        "occupied" => request.status_msg = Some("Occupied".to_string()),
+        "na" => request.status_msg = Some("Not Available".to_string()),
+        "free_for_chat" => request.status_msg = Some("Free for Chat".to_string()),
+        _ => {}
+    }
+
+    match client.send(request).await {
+        Ok(_) => {
+            slog(&app, &log, "info", format!("Presence set to {} (matrix: {:?})", status, presence));
+        }
+        Err(e) => {
+            // Some servers don't support presence — log but don't fail
+            slog(&app, &log, "warn", format!("Failed to set presence: {}", e));
+        }
+    }
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn send_typing(
+    account: String,
+    room_id: String,
+    typing: bool,
+    state: State<'_, MatrixState>,
+) -> Result<(), String> {
+    let client = state.client(&account).await?;
+
+    let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    room.typing_notice(typing)
+        .await
+        .map_err(|e| format!("Typing notice failed: {}", e))?;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn mark_as_read(
+    account: String,
+    room_id: String,
+    event_id: String,
+    state: State<'_, MatrixState>,
+) -> Result<(), String> {
+    let client = state.client(&account).await?;
+
+    let room_id_parsed = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id_parsed).ok_or("Room not found")?;
+
+    let event_id_parsed = matrix_sdk::ruma::OwnedEventId::try_from(event_id.as_str())
+        .map_err(|e| format!("Invalid event ID: {}", e))?;
+
+    room.send_single_receipt(
+        matrix_sdk::ruma::api::client::receipt::create_receipt::v3::ReceiptType::Read,
+        matrix_sdk::ruma::events::receipt::ReceiptThread::Unthreaded,
+        event_id_parsed,
+    )
+    .await
+    .map_err(|e| format!("Read receipt failed: {}", e))?;
+
+    Ok(())
+}
+
+const SESSION_EXPIRED_EVENT: &str = "session_expired";
+const SESSION_ENDED_EVENT: &str = "session_ended";
+
+/// Event to tell the frontend about a session change, or `None` when the
+/// tokens were refreshed and only need saving. `UnknownToken` is only sent
+/// once refreshing has failed too. With soft logout the device is kept, so
+/// the user can sign back in to it; otherwise the session is gone.
+fn session_change_event(change: &matrix_sdk::SessionChange) -> Option<&'static str> {
+    match change {
+        matrix_sdk::SessionChange::TokensRefreshed => None,
+        matrix_sdk::SessionChange::UnknownToken { soft_logout: true } => Some(SESSION_EXPIRED_EVENT),
+        matrix_sdk::SessionChange::UnknownToken { soft_logout: false } => Some(SESSION_ENDED_EVENT),
+    }
+}
+
+#[tauri::command]
+pub async fn start_sync(
+    account: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<(), String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("start_sync: beginning background sync for {}...", account));
+
+    let client = state.client(&account).await?;
+
+    let app_handle = app.clone();
+
+    // Abort any existing sync tasks before starting new ones. The account's
+    // shared state is read under the same lock so it all comes from one entry.
+    let (store_passphrase, pending, replies) = {
+        let accounts = state.accounts.lock().await;
+        let existing = accounts.get(&account).ok_or("Not logged in")?;
+        existing.abort_sync_tasks();
+        (existing.store_passphrase.clone(), existing.undecrypted.clone(), existing.replies.clone())
+    };
+
+    // Poll for room list changes (new rooms joined from other clients)
+    let poll_client = client.clone();
+    let poll_app = app.clone();
+    let poll_log = log.clone();
+    let poll_account = account.clone();
+    let poll_task = tokio::spawn(async move {
+        let mut known_ids: std::collections::HashSet<String> = poll_client
+            .joined_rooms()
+            .iter()
+            .map(|r| r.room_id().to_string())
+            .collect();
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+            let current_ids: std::collections::HashSet<String> = poll_client
+                .joined_rooms()
+                .iter()
+                .map(|r| r.room_id().to_string())
+                .collect();
+            if current_ids != known_ids {
+                slog(&poll_app, &poll_log, "info", format!("Room list changed for {}: {} -> {} rooms", poll_account, known_ids.len(), current_ids.len()));
+                known_ids = current_ids;
+                let _ = poll_app.emit("rooms_changed", &poll_account);
+            }
+        }
+    });
+
+    // Rewrite the session file whenever the SDK rotates our tokens
+    let session_client = client.clone();
+    let session_app = app.clone();
+    let session_log = log.clone();
+    let session_secrets = state.secrets.clone();
+    let session_account = account.clone();
+    let session_accounts = state.accounts.clone();
+    let session_store_passphrase = store_passphrase;
+    let session_task = tokio::spawn(async move {
+        let mut changes = session_client.subscribe_to_session_changes();
+        loop {
+            match changes.recv().await {
+                Ok(change) => match session_change_event(&change) {
+                    None => {
+                        let result = persisted_session_from_client(&session_client, session_store_passphrase.clone())
+                            .ok_or_else(|| "no active session".to_string())
+                            .and_then(|session| save_session(&session, &session_secrets));
+                        match result {
+                            Ok(()) => slog(&session_app, &session_log, "info", "Access token refreshed, session saved".into()),
+                            Err(e) => slog(&session_app, &session_log, "error", format!("Failed to save refreshed session: {}", e)),
+                        }
+                    }
+                    Some(SESSION_EXPIRED_EVENT) => {
+                        slog(&session_app, &session_log, "warn", format!("Session for {} expired (soft logout)", session_account));
+                        if let Some(entry) = session_accounts.lock().await.get(&session_account) {
+                            entry.mark_expired();
+                        }
+                        let _ = session_app.emit(SESSION_EXPIRED_EVENT, SessionEvent {
+                            account: session_account.clone(),
+                        });
+                    }
+                    Some(event) => {
+                        slog(&session_app, &session_log, "warn", format!("Access token for {} rejected and could not be refreshed", session_account));
+                        let _ = session_app.emit(event, SessionEvent {
+                            account: session_account.clone(),
+                        });
+                    }
+                },
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    // Retry undecryptable events as their room keys arrive
+    let keys_client = client.clone();
+    let keys_app = app.clone();
+    let keys_log = log.clone();
+    let keys_account = account.clone();
+    let keys_pending = pending.clone();
+    let keys_replies = replies.clone();
+    let keys_task = tokio::spawn(async move {
+        let Some(keys) = keys_client.encryption().room_keys_received_stream().await else {
+            return;
+        };
+        let mut keys = std::pin::pin!(keys);
+        while let Some(update) = keys.next().await {
+            let retry = match update {
+                Ok(infos) => {
+                    let mut pending = keys_pending.lock().unwrap();
+                    infos
+                        .iter()
+                        .flat_map(|info| {
+                            pending
+                                .take(info.room_id.as_str(), &info.session_id)
+                                .into_iter()
+                                .map(|(room_id, event_id)| (info.session_id.clone(), room_id, event_id))
+                        })
+                        .collect::<Vec<_>>()
+                }
+                // We missed some keys, so anything might be decryptable now
+                Err(_) => keys_pending.lock().unwrap().take_all(),
+            };
+            for (session_id, room_id, event_id) in retry {
+                retry_decryption(&keys_client, &keys_app, &keys_log, &keys_account, &keys_pending, &keys_replies, &session_id, &room_id, &event_id).await;
+            }
+        }
+    });
+
+    // Warn about contacts whose cross-signing identity is replaced
+    let identity_client = client.clone();
+    let identity_app = app.clone();
+    let identity_account = account.clone();
+    let identity_task = tokio::spawn(async move {
+        let Ok(updates) = identity_client.encryption().user_identities_stream().await else {
+            return;
+        };
+        let mut updates = std::pin::pin!(updates);
+        while let Some(update) = updates.next().await {
+            for identity in update.new.values().chain(update.changed.values()) {
+                if identity_client.user_id() == Some(identity.user_id()) {
+                    continue;
+                }
+                if identity.has_verification_violation() || identity_replaced(&identity_client, &identity_account, identity) {
+                    let _ = identity_app.emit("identity_changed", IdentityChangedEvent {
+                        account: identity_account.clone(),
+                        user_id: identity.user_id().to_string(),
+                        changed: true,
+                    });
+                }
+            }
+        }
+    });
+
+    // Verification request handler
+    let verify_app = app.clone();
+    let verify_client = client.clone();
+    let verify_log = log.clone();
+    let verify_account = account.clone();
+
+    let sync_log = log.clone();
+    let sync_account = account.clone();
+    let sync_app = app.clone();
+
+    let sync_task = tokio::spawn(async move {
+        // Handle incoming verification requests
+        let va = verify_app.clone();
+        let vc = verify_client.clone();
+        let vl = verify_log.clone();
+        let vacc = verify_account.clone();
+        verify_client.add_event_handler(
+            move |event: matrix_sdk::ruma::events::key::verification::request::ToDeviceKeyVerificationRequestEvent| {
+                let app = va.clone();
+                let client = vc.clone();
+                let log = vl.clone();
+                let account = vacc.clone();
+                async move {
+                    let user_id = event.sender;
+                    let flow_id = event.content.transaction_id.to_string();
+                    slog(&app, &log, "info", format!("Verification request from {} (flow={})", user_id, flow_id));
+                    if let Some(request) = client
                         .encryption()
                         .get_verification_request(&user_id, &flow_id)
                         .await
                     {
                         let payload = VerificationEvent {
+                            account,
                             flow_id,
                             user_id: user_id.to_string(),
                             is_self_verification: request.is_self_verification(),
@@ -1586,11 +3547,13 @@ pub async fn start_sync(
         // Typing event handler
         let typing_app = app_handle.clone();
         let typing_client = client.clone();
+        let typing_account = sync_account.clone();
         client.add_event_handler(
             move |event: matrix_sdk::ruma::events::SyncEphemeralRoomEvent<matrix_sdk::ruma::events::typing::TypingEventContent>,
                   room: matrix_sdk::Room| {
                 let app = typing_app.clone();
                 let cl = typing_client.clone();
+                let account = typing_account.clone();
                 async move {
                     let my_id_str = cl.user_id().map(|u| u.to_string());
                     let mut display_names = Vec::new();
@@ -1609,6 +3572,7 @@ pub async fn start_sync(
                     }
 
                     let payload = TypingEvent {
+                        account,
                         room_id: room.room_id().to_string(),
                         user_ids,
                         display_names,
@@ -1618,123 +3582,88 @@ pub async fn start_sync(
             },
         );
 
+        let message_account = sync_account.clone();
+        let message_replies = replies.clone();
         client.add_event_handler(
             move |event: matrix_sdk::ruma::events::room::message::SyncRoomMessageEvent,
                   room: matrix_sdk::Room| {
                 let app = app_handle.clone();
+                let account = message_account.clone();
+                let replies = message_replies.clone();
                 async move {
-                    if let Some(original) = event.as_original() {
-                        // Handle edits: emit message_edited event
-                        if let Some(matrix_sdk::ruma::events::room::message::Relation::Replacement(replacement)) = &original.content.relates_to {
-                            let new_body = match &replacement.new_content.msgtype {
-                                matrix_sdk::ruma::events::room::message::MessageType::Text(text) => text.body.clone(),
-                                matrix_sdk::ruma::events::room::message::MessageType::Notice(notice) => notice.body.clone(),
-                                matrix_sdk::ruma::events::room::message::MessageType::Emote(emote) => format!("* {}", emote.body),
-                                _ => return,
-                            };
-                            let edit = MessageEditEvent {
-                                room_id: room.room_id().to_string(),
-                                original_event_id: replacement.event_id.to_string(),
-                                new_body,
-                                sender: event.sender().to_string(),
-                                sender_name: event.sender().localpart().to_string(),
-                            };
-                            let _ = app.emit("message_edited", &edit);
-                            return;
+                    let Some(original) = event.as_original() else {
+                        return;
+                    };
+                    match message_update(&account, room.room_id().as_str(), original, None) {
+                        Some(MessageUpdate::New(mut msg)) if msg.msg_type != "unknown" => {
+                            resolve_replies(&room, &account, &replies, std::slice::from_mut(&mut *msg)).await;
+                            let _ = app.emit("new_message", &msg);
                         }
-
-                        // Extract reply relation (Reply or Thread)
-                        let mut in_reply_to: Option<String> = None;
-                        let mut reply_sender_name: Option<String> = None;
-                        let mut reply_body_text: Option<String> = None;
-
-                        if let Some(relation) = &original.content.relates_to {
-                            match relation {
-                                matrix_sdk::ruma::events::room::message::Relation::Reply { in_reply_to: irt } => {
-                                    in_reply_to = Some(irt.event_id.to_string());
-                                }
-                                matrix_sdk::ruma::events::room::message::Relation::Thread(thread) => {
-                                    if let Some(irt) = &thread.in_reply_to {
-                                        in_reply_to = Some(irt.event_id.to_string());
-                                    }
-                                }
-                                _ => {}
-                            }
+                        Some(MessageUpdate::Edit(edit)) => {
+                            replies.lock().unwrap().remove(room.room_id().as_str(), &edit.original_event_id);
+                            let _ = app.emit("message_edited", &edit);
                         }
+                        _ => {}
+                    }
+                }
+            },
+        );
 
-                        let (mut body, msg_type, media_url, filename) = match &original.content.msgtype {
-                            matrix_sdk::ruma::events::room::message::MessageType::Text(text) => {
-                                (text.body.clone(), "text".to_string(), None, None)
-                            }
-                            matrix_sdk::ruma::events::room::message::MessageType::Notice(notice) => {
-                                (notice.body.clone(), "text".to_string(), None, None)
-                            }
-                            matrix_sdk::ruma::events::room::message::MessageType::Emote(emote) => {
-                                (format!("* {}", emote.body), "text".to_string(), None, None)
-                            }
-                            matrix_sdk::ruma::events::room::message::MessageType::Image(img) => {
-                                (img.body.clone(), "image".to_string(), media_source_to_mxc(&img.source), Some(img.body.clone()))
-                            }
-                            matrix_sdk::ruma::events::room::message::MessageType::File(file) => {
-                                let fname = file.filename.clone().unwrap_or_else(|| file.body.clone());
-                                (file.body.clone(), "file".to_string(), media_source_to_mxc(&file.source), Some(fname))
-                            }
-                            matrix_sdk::ruma::events::room::message::MessageType::Audio(audio) => {
-                                (audio.body.clone(), "audio".to_string(), media_source_to_mxc(&audio.source), Some(audio.body.clone()))
-                            }
-                            matrix_sdk::ruma::events::room::message::MessageType::Video(video) => {
-                                (video.body.clone(), "video".to_string(), media_source_to_mxc(&video.source), Some(video.body.clone()))
-                            }
-                            _ => return,
-                        };
-
-                        // For text replies, parse and strip fallback
-                        if msg_type == "text" && body.starts_with("> <") {
-                            if in_reply_to.is_none() {
-                                // Body has reply fallback but typed relation wasn't parsed —
-                                // use body text as the source of reply info
-                                in_reply_to = Some("fallback".to_string());
-                            }
-                            if let Some((sender, quoted)) = extract_reply_fallback(&body) {
-                                reply_sender_name = Some(sender);
-                                reply_body_text = Some(quoted);
-                            }
-                            body = strip_reply_fallback(&body);
+        // Encrypted events only reach this handler when they couldn't be decrypted
+        let utd_app = sync_app.clone();
+        let utd_account = sync_account.clone();
+        let utd_pending = pending.clone();
+        client.add_event_handler(
+            move |event: matrix_sdk::ruma::events::room::encrypted::SyncRoomEncryptedEvent,
+                  room: matrix_sdk::Room| {
+                let app = utd_app.clone();
+                let account = utd_account.clone();
+                let pending = utd_pending.clone();
+                async move {
+                    let Some(original) = event.as_original() else {
+                        return;
+                    };
+                    let session_id = match &original.content.scheme {
+                        matrix_sdk::ruma::events::room::encrypted::EncryptedEventScheme::MegolmV1AesSha2(megolm) => {
+                            Some(megolm.session_id.clone())
                         }
-
-                        let msg = Message {
-                            room_id: room.room_id().to_string(),
-                            event_id: event.event_id().to_string(),
-                            sender: event.sender().to_string(),
-                            sender_name: event.sender().localpart().to_string(),
-                            body,
-                            timestamp: event.origin_server_ts().as_secs().into(),
-                            msg_type,
-                            media_url,
-                            filename,
-                            in_reply_to,
-                            reply_sender_name,
-                            reply_body: reply_body_text,
-                        };
-                        let _ = app.emit("new_message", &msg);
+                        _ => None,
+                    };
+                    let msg = undecryptable_message(
+                        &account,
+                        room.room_id().as_str(),
+                        &original.event_id,
+                        &original.sender,
+                        original.origin_server_ts,
+                        session_id,
+                    );
+                    if let Some(session_id) = &msg.session_id {
+                        pending.lock().unwrap().insert(session_id, room.room_id().as_str(), &msg.event_id);
                     }
+                    let _ = app.emit("new_message", &msg);
                 }
             },
         );
 
         // Redaction event handler (message deletion)
         let redact_app = sync_app.clone();
+        let redact_account = sync_account.clone();
+        let redact_replies = replies.clone();
         client.add_event_handler(
             move |event: matrix_sdk::ruma::events::room::redaction::SyncRoomRedactionEvent,
                   room: matrix_sdk::Room| {
                 let app = redact_app.clone();
+                let account = redact_account.clone();
+                let replies = redact_replies.clone();
                 async move {
                     if let Some(original) = event.as_original() {
                         let payload = MessageDeletedEvent {
+                            account,
                             room_id: room.room_id().to_string(),
                             event_id: original.redacts.as_ref().map(|e| e.to_string()).unwrap_or_default(),
                         };
                         if !payload.event_id.is_empty() {
+                            replies.lock().unwrap().remove(&payload.room_id, &payload.event_id);
                             let _ = app.emit("message_deleted", &payload);
                         }
                     }
@@ -1742,212 +3671,982 @@ pub async fn start_sync(
             },
         );
 
-        // Reaction event handler
-        let react_app = sync_app.clone();
-        client.add_event_handler(
-            move |event: matrix_sdk::ruma::events::reaction::SyncReactionEvent,
-                  room: matrix_sdk::Room| {
-                let app = react_app.clone();
-                async move {
-                    if let Some(original) = event.as_original() {
-                        let payload = ReactionEvent {
-                            room_id: room.room_id().to_string(),
-                            event_id: event.event_id().to_string(),
-                            reaction_key: original.content.relates_to.key.clone(),
-                            sender: event.sender().to_string(),
-                            sender_name: event.sender().localpart().to_string(),
-                            relates_to: original.content.relates_to.event_id.to_string(),
-                        };
-                        let _ = app.emit("reaction", &payload);
-                    }
-                }
-            },
-        );
+        // Reaction event handler
+        let react_app = sync_app.clone();
+        let react_account = sync_account.clone();
+        client.add_event_handler(
+            move |event: matrix_sdk::ruma::events::reaction::SyncReactionEvent,
+                  room: matrix_sdk::Room| {
+                let app = react_app.clone();
+                let account = react_account.clone();
+                async move {
+                    if let Some(original) = event.as_original() {
+                        let payload = ReactionEvent {
+                            account,
+                            room_id: room.room_id().to_string(),
+                            event_id: event.event_id().to_string(),
+                            reaction_key: original.content.relates_to.key.clone(),
+                            sender: event.sender().to_string(),
+                            sender_name: event.sender().localpart().to_string(),
+                            relates_to: original.content.relates_to.event_id.to_string(),
+                        };
+                        let _ = app.emit("reaction", &payload);
+                    }
+                }
+            },
+        );
+
+        // Room invite handler — notify frontend when someone invites us
+        let invite_app = sync_app.clone();
+        let invite_account = sync_account.clone();
+        client.add_event_handler(
+            move |event: matrix_sdk::ruma::events::room::member::StrippedRoomMemberEvent,
+                  room: matrix_sdk::Room| {
+                let app = invite_app.clone();
+                let account = invite_account.clone();
+                async move {
+                    // Only handle events targeting us (our membership changed to invite)
+                    if event.content.membership == matrix_sdk::ruma::events::room::member::MembershipState::Invite {
+                        let payload = InviteInfo {
+                            account,
+                            room_id: room.room_id().to_string(),
+                            room_name: room.display_name().await.map(|n| n.to_string()).ok(),
+                            inviter: Some(event.sender.to_string()),
+                            inviter_name: Some(event.sender.localpart().to_string()),
+                        };
+                        let _ = app.emit("room_invite", &payload);
+                    }
+                }
+            },
+        );
+
+        slog(&sync_app, &sync_log, "info", "Sync loop starting...".into());
+        let _ = sync_app.emit("sync_status", SyncStatusEvent {
+            account: sync_account.clone(),
+            status: "syncing".to_string(),
+        });
+
+        let synced_flag = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let flag = synced_flag.clone();
+        let cb_app = sync_app.clone();
+        let cb_log = sync_log.clone();
+        let cb_account = sync_account.clone();
+
+        let settings = matrix_sdk::config::SyncSettings::default();
+        match client.sync_with_result_callback(settings, move |result| {
+            let flag = flag.clone();
+            let app = cb_app.clone();
+            let log = cb_log.clone();
+            let account = cb_account.clone();
+            async move {
+                match result {
+                    Ok(_) => {
+                        if !flag.swap(true, std::sync::atomic::Ordering::Relaxed) {
+                            slog_buf(&log, "info", format!("Initial sync complete for {}", account));
+                            let _ = app.emit("sync_status", SyncStatusEvent {
+                                account,
+                                status: "synced".to_string(),
+                            });
+                        }
+                    }
+                    Err(matrix_sdk::Error::Http(ref e)) if token_rejection(e).is_some() => {
+                        // Retrying can't help; the session task reports it to the frontend
+                        slog_buf(&log, "error", format!("Sync stopped for {}: access token rejected", account));
+                        return Ok(matrix_sdk::LoopCtrl::Break);
+                    }
+                    Err(ref e) => {
+                        slog_buf(&log, "error", format!("Sync error (retrying): {}", e));
+                    }
+                }
+                Ok(matrix_sdk::LoopCtrl::Continue)
+            }
+        }).await {
+            Ok(_) => slog_buf(&sync_log, "info", "Sync loop ended".into()),
+            Err(e) => slog_buf(&sync_log, "error", format!("Sync loop error: {}", e)),
+        }
+    });
+
+    // Store task handles so we can abort them on disconnect/logout
+    let tasks = vec![poll_task, session_task, keys_task, identity_task, sync_task];
+    match state.accounts.lock().await.get(&account) {
+        Some(entry) => entry.set_sync_tasks(tasks),
+        // Signed out while we were setting up
+        None => tasks.iter().for_each(|t| t.abort()),
+    }
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn upload_file(
+    account: String,
+    room_id: String,
+    file_path: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<(), String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("upload_file: {} to room {}", file_path, room_id));
+
+    let client = state.client(&account).await?;
+
+    let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let data = std::fs::read(&file_path)
+        .map_err(|e| format!("Failed to read file: {}", e))?;
+
+    let filename = std::path::Path::new(&file_path)
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+
+    let mime = mime_guess::from_path(&file_path).first_or_octet_stream();
+
+    let encrypted = room
+        .latest_encryption_state()
+        .await
+        .map_err(|e| format!("Failed to check room encryption: {}", e))?
+        .is_encrypted();
+
+    slog(&app, &log, "info", format!("Uploading {} ({} bytes, {}, encrypted={})", filename, data.len(), mime, encrypted));
+    let file_content = if encrypted {
+        let file = client
+            .upload_encrypted_file(&mut data.as_slice())
+            .await
+            .map_err(|e| {
+                slog(&app, &log, "error", format!("Upload failed: {}", e));
+                format!("Upload failed: {}", e)
+            })?;
+        matrix_sdk::ruma::events::room::message::FileMessageEventContent::encrypted(filename, file)
+    } else {
+        let response = client
+            .media()
+            .upload(&mime, data, None)
+            .await
+            .map_err(|e| {
+                slog(&app, &log, "error", format!("Upload failed: {}", e));
+                format!("Upload failed: {}", e)
+            })?;
+        matrix_sdk::ruma::events::room::message::FileMessageEventContent::plain(filename, response.content_uri)
+    };
+
+    let content = matrix_sdk::ruma::events::room::message::RoomMessageEventContent::new(
+        matrix_sdk::ruma::events::room::message::MessageType::File(file_content),
+    );
+    room.send(content).await.map_err(|e| format!("Send failed: {}", e))?;
+
+    slog(&app, &log, "info", "File sent OK".into());
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn fetch_media(
+    account: String,
+    mxc_url: String,
+    room_id: Option<String>,
+    event_id: Option<String>,
+    state: State<'_, MatrixState>,
+) -> Result<String, String> {
+    let client = state.client(&account).await?;
+
+    // Encrypted media: the key comes from the event and never leaves this process
+    if let (Some(room_id), Some(event_id)) = (room_id, event_id) {
+        return fetch_encrypted_media(&client, &mxc_url, &room_id, &event_id).await;
+    }
+
+    let path = mxc_url.strip_prefix("mxc://")
+        .ok_or("Invalid mxc:// URL")?;
+    let (server_name, media_id) = path.split_once('/')
+        .ok_or("Invalid mxc URL format")?;
+
+    let hs = client.homeserver().to_string();
+    let hs = hs.trim_end_matches('/');
+    let access_token = client.access_token()
+        .ok_or("No access token available")?;
+
+    // Try authenticated endpoint first, then unauthenticated fallback
+    let urls = [
+        format!("{}/_matrix/client/v1/media/download/{}/{}", hs, server_name, media_id),
+        format!("{}/_matrix/media/v3/download/{}/{}", hs, server_name, media_id),
+    ];
+
+    let http = reqwest::Client::new();
+    for url in &urls {
+        if let Ok(resp) = http.get(url)
+            .header("Authorization", format!("Bearer {}", access_token))
+            .send()
+            .await
+        {
+            if resp.status().is_success() {
+                if let Ok(bytes) = resp.bytes().await {
+                    if bytes.is_empty() { continue; }
+                    return Ok(media_data_url(&bytes));
+                }
+            }
+        }
+    }
+    Err("Failed to fetch media from any endpoint".into())
+}
+
+/// Download and decrypt the media of a message, checking it's the one the
+/// frontend asked for.
+async fn fetch_encrypted_media(client: &Client, mxc_url: &str, room_id: &str, event_id: &str) -> Result<String, String> {
+    let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id)
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let event_id = matrix_sdk::ruma::OwnedEventId::try_from(event_id)
+        .map_err(|e| format!("Invalid event ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let event = room.load_or_fetch_event(&event_id, None).await
+        .map_err(|e| format!("Failed to load event: {}", e))?;
+    let Ok(matrix_sdk::ruma::events::AnySyncTimelineEvent::MessageLike(
+        matrix_sdk::ruma::events::AnySyncMessageLikeEvent::RoomMessage(msg),
+    )) = event.raw().deserialize() else {
+        return Err("Event has no media".into());
+    };
+    let source = msg
+        .as_original()
+        .and_then(|original| {
+            let msgtype = &original.content.msgtype;
+            [message_media_source(msgtype), message_thumbnail_source(msgtype)]
+                .into_iter()
+                .flatten()
+                .find(|source| media_source_to_mxc(source).as_deref() == Some(mxc_url))
+        })
+        .ok_or("Event has no media")?;
+
+    let request = matrix_sdk::media::MediaRequestParameters {
+        source: source.clone(),
+        format: matrix_sdk::media::MediaFormat::File,
+    };
+    let bytes = client.media().get_media_content(&request, true).await
+        .map_err(|e| format!("Failed to download media: {}", e))?;
+    Ok(media_data_url(&bytes))
+}
+
+/// Create and upload cross-signing keys if the account has none. Without a
+/// password this only works on servers that skip UIAA for the first upload.
+async fn bootstrap_cross_signing_if_needed(client: &Client, password: Option<&str>) -> Result<(), ServerError> {
+    uiaa::with_password(client, password, |auth| {
+        let encryption = client.encryption();
+        async move { encryption.bootstrap_cross_signing_if_needed(auth).await }
+    })
+    .await
+}
+
+/// Sent when cross-signing couldn't be set up right after signing in, so the
+/// user can be asked to set it up from the account window.
+const CROSS_SIGNING_NEEDED_EVENT: &str = "cross_signing_needed";
+
+/// Set up cross-signing for a newly added account in the background. This
+/// runs on the account's own client so the private keys land in its store.
+async fn offer_cross_signing(
+    user_id: &str,
+    password: Option<String>,
+    app: &tauri::AppHandle,
+    log: &std::sync::Arc<ServerLog>,
+    state: &State<'_, MatrixState>,
+) {
+    let client = match state.client(user_id).await {
+        Ok(client) => client,
+        Err(e) => {
+            slog(app, log, "warn", format!("Skipping cross-signing setup for {}: {}", user_id, e));
+            return;
+        }
+    };
+    let (app, log, account) = (app.clone(), log.clone(), user_id.to_string());
+    tokio::spawn(async move {
+        match bootstrap_cross_signing_if_needed(&client, password.as_deref()).await {
+            Ok(()) => slog(&app, &log, "info", "Cross-signing is set up".into()),
+            Err(e) => {
+                slog(&app, &log, "warn", format!("Cross-signing bootstrap failed: {}", e.message));
+                let _ = app.emit(CROSS_SIGNING_NEEDED_EVENT, SessionEvent { account });
+            }
+        }
+    });
+}
+
+/// Whether this session holds all three cross-signing private keys.
+#[tauri::command]
+pub async fn get_cross_signing_status(
+    account: String,
+    state: State<'_, MatrixState>,
+) -> Result<bool, String> {
+    let client = state.client(&account).await?;
+    Ok(client
+        .encryption()
+        .cross_signing_status()
+        .await
+        .is_some_and(|s| s.has_master && s.has_self_signing && s.has_user_signing))
+}
+
+#[tauri::command]
+pub async fn bootstrap_cross_signing(
+    account: String,
+    password: Option<String>,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<(), ServerError> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("bootstrap_cross_signing: {}", account));
+
+    let client = state.client(&account).await?;
+    bootstrap_cross_signing_if_needed(&client, password.as_deref()).await.inspect_err(|e| {
+        if e.message != uiaa::PASSWORD_REQUIRED {
+            slog(&app, &log, "error", format!("Cross-signing bootstrap failed: {}", e.message));
+        }
+    })
+}
+
+#[tauri::command]
+pub async fn get_recovery_status(
+    account: String,
+    state: State<'_, MatrixState>,
+) -> Result<RecoveryStatus, String> {
+    use matrix_sdk::encryption::recovery::RecoveryState;
+
+    let client = state.client(&account).await?;
+    let encryption = client.encryption();
+    let backups = encryption.backups();
+    let backup_exists_on_server = backups
+        .fetch_exists_on_server()
+        .await
+        .map_err(|e| format!("Failed to check key backup: {}", e))?;
+    let recovery_state = match encryption.recovery().state() {
+        RecoveryState::Enabled => "enabled",
+        RecoveryState::Disabled => "disabled",
+        RecoveryState::Incomplete => "incomplete",
+        RecoveryState::Unknown => "unknown",
+    };
+    Ok(RecoveryStatus {
+        state: recovery_state.to_string(),
+        backup_enabled: backups.are_enabled().await,
+        backup_exists_on_server,
+    })
+}
+
+/// The `backup_progress` event for a step of `enable_recovery`, if any.
+fn enable_progress_event(
+    account: &str,
+    update: &matrix_sdk::encryption::recovery::EnableProgress,
+) -> Option<BackupProgressEvent> {
+    use matrix_sdk::encryption::recovery::EnableProgress;
+
+    let (stage, counts) = match update {
+        EnableProgress::Starting => return None,
+        EnableProgress::CreatingBackup => ("creating_backup", None),
+        EnableProgress::CreatingRecoveryKey => ("creating_recovery_key", None),
+        EnableProgress::BackingUp(counts) => ("backing_up", Some(counts)),
+        EnableProgress::RoomKeyUploadError => ("upload_error", None),
+        // The command's result carries the key; keep it out of events
+        EnableProgress::Done { .. } => ("done", None),
+    };
+    Some(BackupProgressEvent {
+        account: account.to_string(),
+        stage: stage.to_string(),
+        done: counts.map(|c| c.backed_up as u64),
+        total: counts.map(|c| c.total as u64),
+    })
+}
 
-        // Room invite handler — notify frontend when someone invites us
-        let invite_app = sync_app.clone();
-        client.add_event_handler(
-            move |event: matrix_sdk::ruma::events::room::member::StrippedRoomMemberEvent,
-                  room: matrix_sdk::Room| {
-                let app = invite_app.clone();
-                async move {
-                    // Only handle events targeting us (our membership changed to invite)
-                    if event.content.membership == matrix_sdk::ruma::events::room::member::MembershipState::Invite {
-                        let payload = InviteInfo {
-                            room_id: room.room_id().to_string(),
-                            room_name: room.display_name().await.map(|n| n.to_string()).ok(),
-                            inviter: Some(event.sender.to_string()),
-                            inviter_name: Some(event.sender.localpart().to_string()),
-                        };
-                        let _ = app.emit("room_invite", &payload);
-                    }
-                }
-            },
-        );
+/// Create a key backup and secret storage, returning the new recovery key.
+/// Resolves once every room key has been uploaded; progress is sent as
+/// `backup_progress` events.
+#[tauri::command]
+pub async fn enable_recovery(
+    account: String,
+    passphrase: Option<String>,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<String, String> {
+    use matrix_sdk::encryption::recovery::RecoveryError;
 
-        slog(&sync_app, &sync_log, "info", "Sync loop starting...".into());
-        let _ = sync_app.emit("sync_status", "syncing");
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("enable_recovery: setting up key backup for {}", account));
 
-        let synced_flag = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
-        let flag = synced_flag.clone();
-        let cb_app = sync_app.clone();
-        let cb_log = sync_log.clone();
+    let client = state.client(&account).await?;
+    let recovery = client.encryption().recovery();
+    let mut enable = recovery.enable().wait_for_backups_to_upload();
+    if let Some(passphrase) = passphrase.as_deref().filter(|p| !p.is_empty()) {
+        enable = enable.with_passphrase(passphrase);
+    }
 
-        let settings = matrix_sdk::config::SyncSettings::default();
-        match client.sync_with_result_callback(settings, move |result| {
-            let flag = flag.clone();
-            let app = cb_app.clone();
-            let log = cb_log.clone();
-            async move {
-                match result {
-                    Ok(_) => {
-                        if !flag.swap(true, std::sync::atomic::Ordering::Relaxed) {
-                            slog_buf(&log, "info", "Initial sync complete".into());
-                            let _ = app.emit("sync_status", "synced");
-                        }
-                    }
-                    Err(ref e) => {
-                        slog_buf(&log, "error", format!("Sync error (retrying): {}", e));
-                    }
-                }
-                Ok(matrix_sdk::LoopCtrl::Continue)
+    let mut progress = enable.subscribe_to_progress();
+    let progress_app = app.clone();
+    let progress_account = account.clone();
+    let progress_task = tokio::spawn(async move {
+        while let Some(update) = progress.next().await {
+            let Ok(update) = update else { continue };
+            if let Some(event) = enable_progress_event(&progress_account, &update) {
+                let _ = progress_app.emit("backup_progress", event);
             }
-        }).await {
-            Ok(_) => slog_buf(&sync_log, "info", "Sync loop ended".into()),
-            Err(e) => slog_buf(&sync_log, "error", format!("Sync loop error: {}", e)),
         }
     });
 
-    // Store task handles so we can abort them on disconnect/logout
-    {
-        let mut tasks = state.sync_tasks.lock().unwrap();
-        *tasks = vec![poll_task, sync_task];
+    let result = enable.await;
+    progress_task.abort();
+    match result {
+        Ok(recovery_key) => {
+            slog(&app, &log, "info", "Recovery enabled, room keys backed up".into());
+            Ok(recovery_key)
+        }
+        Err(RecoveryError::BackupExistsOnServer) => {
+            slog(&app, &log, "warn", "enable_recovery: a key backup already exists".into());
+            Err("A key backup already exists. Restore it with your recovery key instead.".into())
+        }
+        Err(e) => {
+            slog(&app, &log, "error", format!("Failed to enable recovery: {}", e));
+            Err(format!("Failed to set up recovery: {}", e))
+        }
+    }
+}
+
+/// Unlock secret storage with a recovery key or passphrase, then download the
+/// backed-up room keys of every encrypted room. Messages waiting on those keys
+/// are decrypted by the sync's key task as they arrive.
+#[tauri::command]
+pub async fn recover_keys(
+    account: String,
+    recovery_key: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<(), String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("recover_keys: restoring secrets for {}", account));
+
+    let client = state.client(&account).await?;
+    let emit_progress = |stage: &str, done: Option<u64>, total: Option<u64>| {
+        let _ = app.emit("backup_progress", BackupProgressEvent {
+            account: account.clone(),
+            stage: stage.to_string(),
+            done,
+            total,
+        });
+    };
+
+    emit_progress("recovering", None, None);
+    client.encryption().recovery().recover(recovery_key.trim()).await.map_err(|e| {
+        slog(&app, &log, "warn", format!("Recovery failed: {}", e));
+        "Couldn't unlock your keys. Check the recovery key or passphrase.".to_string()
+    })?;
+
+    let rooms: Vec<_> = client
+        .joined_rooms()
+        .into_iter()
+        .filter(|room| room.encryption_state().is_encrypted())
+        .collect();
+    let total = rooms.len() as u64;
+    let backups = client.encryption().backups();
+    for (i, room) in rooms.iter().enumerate() {
+        emit_progress("downloading", Some(i as u64), Some(total));
+        if let Err(e) = backups.download_room_keys_for_room(room.room_id()).await {
+            slog(&app, &log, "warn", format!("Failed to download keys for {}: {}", room.room_id(), e));
+        }
     }
+    emit_progress("done", Some(total), Some(total));
 
+    slog(&app, &log, "info", format!("Recovered keys for {} encrypted rooms", total));
     Ok(())
 }
 
+/// Write every room key we hold to a passphrase-encrypted file in the
+/// standard `MEGOLM SESSION DATA` format other clients import.
 #[tauri::command]
-pub async fn upload_file(
-    room_id: String,
+pub async fn export_room_keys(
+    account: String,
     file_path: String,
+    passphrase: String,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
-) -> Result<(), String> {
+) -> Result<u64, String> {
     let log = state.log.clone();
-    slog(&app, &log, "info", format!("upload_file: {} to room {}", file_path, room_id));
+    slog(&app, &log, "info", format!("export_room_keys: {} to {}", account, file_path));
+    if passphrase.is_empty() {
+        return Err("Choose a passphrase to protect the exported keys".to_string());
+    }
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    let client = state.client(&account).await?;
+    let emit_progress = |stage: &str, done: Option<u64>, total: Option<u64>| {
+        let _ = app.emit("backup_progress", BackupProgressEvent {
+            account: account.clone(),
+            stage: stage.to_string(),
+            done,
+            total,
+        });
+    };
 
-    let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
-        .map_err(|e| format!("Invalid room ID: {}", e))?;
-    let room = client.get_room(&room_id).ok_or("Room not found")?;
+    // The SDK encrypts the whole export in one go, so count sessions as they
+    // are collected and report the encryption as a single step
+    emit_progress("exporting", None, None);
+    let count = export_all_room_keys(&client, file_path.into(), &passphrase).await.map_err(|e| {
+        slog(&app, &log, "error", format!("Failed to export room keys: {}", e));
+        e
+    })?;
+    emit_progress("done", Some(count), Some(count));
 
-    let data = std::fs::read(&file_path)
-        .map_err(|e| format!("Failed to read file: {}", e))?;
+    slog(&app, &log, "info", format!("Exported {} room keys", count));
+    Ok(count)
+}
 
-    let filename = std::path::Path::new(&file_path)
-        .file_name()
-        .unwrap_or_default()
-        .to_string_lossy()
-        .to_string();
+/// Export every room key of `client` to `path`, returning how many there were.
+async fn export_all_room_keys(client: &Client, path: std::path::PathBuf, passphrase: &str) -> Result<u64, String> {
+    let mut count = 0u64;
+    client
+        .encryption()
+        .export_room_keys(path, passphrase, |_| {
+            count += 1;
+            true
+        })
+        .await
+        .map_err(|e| format!("Failed to export keys: {}", e))?;
+    Ok(count)
+}
 
-    let mime = mime_guess::from_path(&file_path).first_or_octet_stream();
+/// Import a `MEGOLM SESSION DATA` file, e.g. one exported from Element, then
+/// retry the messages that were waiting on the imported keys.
+#[tauri::command]
+pub async fn import_room_keys(
+    account: String,
+    file_path: String,
+    passphrase: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<u64, String> {
+    use matrix_sdk::encryption::RoomKeyImportError;
 
-    slog(&app, &log, "info", format!("Uploading {} ({} bytes, {})", filename, data.len(), mime));
-    let response = client
-        .media()
-        .upload(&mime, data, None)
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("import_room_keys: {} from {}", account, file_path));
+
+    let client = state.client(&account).await?;
+    let emit_progress = |stage: &str, done: Option<u64>, total: Option<u64>| {
+        let _ = app.emit("backup_progress", BackupProgressEvent {
+            account: account.clone(),
+            stage: stage.to_string(),
+            done,
+            total,
+        });
+    };
+
+    emit_progress("importing", None, None);
+    let result = client
+        .encryption()
+        .import_room_keys(file_path.into(), &passphrase)
         .await
         .map_err(|e| {
-            slog(&app, &log, "error", format!("Upload failed: {}", e));
-            format!("Upload failed: {}", e)
+            slog(&app, &log, "warn", format!("Failed to import room keys: {}", e));
+            match e {
+                RoomKeyImportError::Export(_) => "Couldn't read the key file. Check the passphrase.".to_string(),
+                e => format!("Failed to import keys: {}", e),
+            }
         })?;
+    slog(&app, &log, "info", format!("Imported {} of {} room keys", result.imported_count, result.total_count));
+
+    let (pending, replies) =
+        state.accounts.lock().await.get(&account).map(|a| (a.undecrypted.clone(), a.replies.clone())).unzip();
+    if let (Some(pending), Some(replies)) = (pending, replies) {
+        let retry = take_imported(&mut pending.lock().unwrap(), &result.keys);
+        let total = retry.len() as u64;
+        for (i, (session_id, room_id, event_id)) in retry.iter().enumerate() {
+            emit_progress("retrying", Some(i as u64), Some(total));
+            retry_decryption(&client, &app, &log, &account, &pending, &replies, session_id, room_id, event_id).await;
+        }
+    }
+    emit_progress("done", Some(result.imported_count as u64), Some(result.total_count as u64));
 
-    let content = matrix_sdk::ruma::events::room::message::RoomMessageEventContent::new(
-        matrix_sdk::ruma::events::room::message::MessageType::File(
-            matrix_sdk::ruma::events::room::message::FileMessageEventContent::plain(
-                filename,
-                response.content_uri,
-            ),
-        ),
-    );
-    room.send(content).await.map_err(|e| format!("Send failed: {}", e))?;
+    Ok(result.imported_count as u64)
+}
+
+/// Remove the events waiting on any of the imported sessions, as
+/// `(session_id, room_id, event_id)`. `keys` maps rooms to sender keys to
+/// session IDs, as in the SDK's import result.
+fn take_imported(
+    pending: &mut UndecryptedEvents,
+    keys: &std::collections::BTreeMap<
+        matrix_sdk::ruma::OwnedRoomId,
+        std::collections::BTreeMap<String, std::collections::BTreeSet<String>>,
+    >,
+) -> Vec<(String, String, String)> {
+    keys.iter()
+        .flat_map(|(room_id, senders)| senders.values().flatten().map(move |session| (room_id, session)))
+        .flat_map(|(room_id, session)| {
+            pending
+                .take(room_id.as_str(), session)
+                .into_iter()
+                .map(|(room_id, event_id)| (session.clone(), room_id, event_id))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+#[tauri::command]
+pub async fn accept_verification(
+    account: String,
+    user_id: String,
+    flow_id: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<(), String> {
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("Accepting verification from {} (flow={})", user_id, flow_id));
+
+    let client = state.client(&account).await?;
+
+    let user_id = matrix_sdk::ruma::UserId::parse(&user_id)
+        .map_err(|e| format!("Invalid user_id: {}", e))?;
+
+    let request = client
+        .encryption()
+        .get_verification_request(&user_id, &flow_id)
+        .await
+        .ok_or("Verification request not found")?;
+
+    request
+        .accept()
+        .await
+        .map_err(|e| format!("Failed to accept: {}", e))?;
+
+    slog(&app, &log, "info", "Accepted, waiting for the other side".into());
+    spawn_verification_flow(app, log, account, request);
 
-    slog(&app, &log, "info", "File sent OK".into());
     Ok(())
 }
 
+/// Ask another user to verify us. The request goes into our DM with them
+/// (created if needed); the returned flow ID tracks it like an incoming one.
 #[tauri::command]
-pub async fn fetch_media(
-    mxc_url: String,
+pub async fn request_user_verification(
+    account: String,
+    user_id: String,
+    app: tauri::AppHandle,
     state: State<'_, MatrixState>,
 ) -> Result<String, String> {
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    let log = state.log.clone();
+    let client = state.client(&account).await?;
 
-    let path = mxc_url.strip_prefix("mxc://")
-        .ok_or("Invalid mxc:// URL")?;
-    let (server_name, media_id) = path.split_once('/')
-        .ok_or("Invalid mxc URL format")?;
+    let user_id = matrix_sdk::ruma::UserId::parse(&user_id)
+        .map_err(|e| format!("Invalid user_id: {}", e))?;
+    if Some(user_id.as_ref()) == client.user_id() {
+        return Err("Use Manage Sessions to verify your own devices".to_string());
+    }
+
+    let identity = client
+        .encryption()
+        .request_user_identity(&user_id)
+        .await
+        .map_err(|e| format!("Failed to fetch identity: {}", e))?
+        .ok_or("This user hasn't set up cross-signing")?;
+    let request = identity
+        .request_verification()
+        .await
+        .map_err(|e| format!("Failed to request verification: {}", e))?;
+
+    let flow_id = request.flow_id().to_string();
+    slog(&app, &log, "info", format!("Requested verification of {} (flow={})", user_id, flow_id));
+    start_outgoing_verification(app, log, account, request);
+    Ok(flow_id)
+}
+
+/// Ask one of our other sessions to verify this one over to-device messages.
+#[tauri::command]
+pub async fn request_device_verification(
+    account: String,
+    device_id: String,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<String, String> {
+    let log = state.log.clone();
+    let client = state.client(&account).await?;
+    let own_user = client.user_id().ok_or("Not logged in")?.to_owned();
+
+    let device = client
+        .encryption()
+        .get_device(&own_user, device_id.as_str().into())
+        .await
+        .map_err(|e| format!("Failed to load device: {}", e))?
+        .ok_or("This session has no encryption keys")?;
+    let request = device
+        .request_verification()
+        .await
+        .map_err(|e| format!("Failed to request verification: {}", e))?;
+
+    let flow_id = request.flow_id().to_string();
+    slog(&app, &log, "info", format!("Requested verification of device {} (flow={})", device_id, flow_id));
+    start_outgoing_verification(app, log, account, request);
+    Ok(flow_id)
+}
+
+/// Open the waiting dialog for a request we sent and drive it once the other
+/// side answers.
+fn start_outgoing_verification(
+    app: tauri::AppHandle,
+    log: std::sync::Arc<ServerLog>,
+    account: String,
+    request: matrix_sdk::encryption::verification::VerificationRequest,
+) {
+    let payload = VerificationEvent {
+        account: account.clone(),
+        flow_id: request.flow_id().to_string(),
+        user_id: request.other_user_id().to_string(),
+        is_self_verification: request.is_self_verification(),
+    };
+    let _ = app.emit("verification_outgoing", &payload);
+    spawn_verification_flow(app, log, account, request);
+}
+
+/// How long we follow a verification flow; matches the SDK's own timeout.
+const VERIFICATION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(600);
+
+/// Sends one flow's verification_* events. The request and its SAS or QR
+/// flow both report the end, so done/cancelled go out only once.
+#[derive(Clone)]
+struct VerificationReporter {
+    app: tauri::AppHandle,
+    log: std::sync::Arc<ServerLog>,
+    account: String,
+    flow_id: String,
+    user_id: String,
+    finished: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl VerificationReporter {
+    fn is_finished(&self) -> bool {
+        self.finished.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    fn finish(&self) -> bool {
+        !self.finished.swap(true, std::sync::atomic::Ordering::SeqCst)
+    }
+
+    fn done(&self) {
+        if self.finish() {
+            slog(&self.app, &self.log, "info", format!("Verification with {} complete (flow={})", self.user_id, self.flow_id));
+            let _ = self.app.emit(
+                "verification_done",
+                verification_done_event(&self.account, &self.flow_id, &self.user_id),
+            );
+        }
+    }
+
+    fn cancelled(&self, reason: &str) {
+        if self.finish() {
+            slog(&self.app, &self.log, "info", format!("Verification {} ended: {}", self.flow_id, reason));
+            let _ = self.app.emit(
+                "verification_cancelled",
+                verification_cancelled_event(&self.account, &self.flow_id, reason),
+            );
+        }
+    }
+}
+
+fn verification_done_event(account: &str, flow_id: &str, user_id: &str) -> serde_json::Value {
+    serde_json::json!({ "account": account, "flow_id": flow_id, "user_id": user_id })
+}
+
+fn verification_cancelled_event(account: &str, flow_id: &str, reason: &str) -> serde_json::Value {
+    serde_json::json!({ "account": account, "flow_id": flow_id, "reason": reason })
+}
+
+/// Follow a verification request's state changes: offer SAS and QR once both
+/// sides are ready, then hand off to the flow that gets started.
+fn spawn_verification_flow(
+    app: tauri::AppHandle,
+    log: std::sync::Arc<ServerLog>,
+    account: String,
+    request: matrix_sdk::encryption::verification::VerificationRequest,
+) {
+    use matrix_sdk::encryption::verification::{Verification, VerificationRequestState};
+
+    let reporter = VerificationReporter {
+        app,
+        log,
+        account,
+        flow_id: request.flow_id().to_string(),
+        user_id: request.other_user_id().to_string(),
+        finished: Default::default(),
+    };
+    tokio::spawn(async move {
+        let follow = async {
+            let mut changes = std::pin::pin!(request.changes());
+            let mut next = Some(request.state());
+            let mut offered = false;
+            let mut watching_sas = false;
+            while let Some(state) = match next.take() {
+                Some(state) => Some(state),
+                None => changes.next().await,
+            } {
+                match state {
+                    VerificationRequestState::Ready { their_methods, .. } if !offered => {
+                        offered = true;
+                        offer_verification_methods(&reporter, &request, &their_methods).await;
+                    }
+                    VerificationRequestState::Transitioned { verification } => match verification {
+                        Verification::SasV1(sas) if !watching_sas => {
+                            watching_sas = true;
+                            tokio::spawn(watch_sas(reporter.clone(), sas));
+                        }
+                        Verification::QrV1(qr) => {
+                            tokio::spawn(watch_qr(reporter.clone(), qr));
+                        }
+                        _ => {}
+                    },
+                    VerificationRequestState::Done => reporter.done(),
+                    VerificationRequestState::Cancelled(info) => reporter.cancelled(info.reason()),
+                    _ => {}
+                }
+                if reporter.is_finished() {
+                    break;
+                }
+            }
+        };
+        if tokio::time::timeout(VERIFICATION_TIMEOUT, follow).await.is_err() {
+            reporter.cancelled("Timed out");
+        }
+    });
+}
+
+/// Tell the dialog which methods both sides support, generating our QR code
+/// if the other side can scan. With only emoji available, start it directly.
+async fn offer_verification_methods(
+    reporter: &VerificationReporter,
+    request: &matrix_sdk::encryption::verification::VerificationRequest,
+    their_methods: &[matrix_sdk::ruma::events::key::verification::VerificationMethod],
+) {
+    use matrix_sdk::ruma::events::key::verification::VerificationMethod;
+
+    let can_sas = their_methods.contains(&VerificationMethod::SasV1);
+    let can_scan = their_methods.contains(&VerificationMethod::QrCodeShowV1);
+    let qr_code = if their_methods.contains(&VerificationMethod::QrCodeScanV1) {
+        match request.generate_qr_code().await {
+            Ok(Some(qr)) => match qr.to_qr_code() {
+                Ok(code) => {
+                    let modules: Vec<bool> = code.to_colors().into_iter().map(|c| c.select(true, false)).collect();
+                    Some(crate::qr::svg_data_url(code.width(), &modules))
+                }
+                Err(e) => {
+                    slog(&reporter.app, &reporter.log, "warn", format!("Failed to encode QR code: {}", e));
+                    None
+                }
+            },
+            Ok(None) => None,
+            Err(e) => {
+                slog(&reporter.app, &reporter.log, "warn", format!("Failed to generate QR code: {}", e));
+                None
+            }
+        }
+    } else {
+        None
+    };
 
-    let hs = client.homeserver().to_string();
-    let hs = hs.trim_end_matches('/');
-    let access_token = client.access_token()
-        .ok_or("No access token available")?;
+    if qr_code.is_none() && !can_scan {
+        if let Err(e) = request.start_sas().await {
+            slog(&reporter.app, &reporter.log, "warn", format!("Failed to start SAS: {}", e));
+        }
+        return;
+    }
 
-    // Try authenticated endpoint first, then unauthenticated fallback
-    let urls = [
-        format!("{}/_matrix/client/v1/media/download/{}/{}", hs, server_name, media_id),
-        format!("{}/_matrix/media/v3/download/{}/{}", hs, server_name, media_id),
-    ];
+    let payload = VerificationReadyEvent {
+        account: reporter.account.clone(),
+        flow_id: reporter.flow_id.clone(),
+        user_id: reporter.user_id.clone(),
+        qr_code,
+        can_scan,
+        can_sas,
+    };
+    let _ = reporter.app.emit("verification_ready", &payload);
+}
 
-    let http = reqwest::Client::new();
-    for url in &urls {
-        if let Ok(resp) = http.get(url)
-            .header("Authorization", format!("Bearer {}", access_token))
-            .send()
-            .await
-        {
-            if resp.status().is_success() {
-                if let Ok(bytes) = resp.bytes().await {
-                    if bytes.is_empty() { continue; }
-                    let content_type = if bytes.starts_with(&[0x89, 0x50, 0x4E, 0x47]) {
-                        "image/png"
-                    } else if bytes.starts_with(&[0xFF, 0xD8]) {
-                        "image/jpeg"
-                    } else if bytes.starts_with(b"GIF") {
-                        "image/gif"
-                    } else if bytes.starts_with(b"RIFF") {
-                        "image/webp"
-                    } else {
-                        "application/octet-stream"
+/// Accept a SAS the other side started and show the emojis once keys are
+/// exchanged.
+async fn watch_sas(reporter: VerificationReporter, sas: matrix_sdk::encryption::verification::SasVerification) {
+    use matrix_sdk::encryption::verification::SasState;
+
+    let follow = async {
+        let mut changes = std::pin::pin!(sas.changes());
+        let mut next = Some(sas.state());
+        while let Some(state) = match next.take() {
+            Some(state) => Some(state),
+            None => changes.next().await,
+        } {
+            match state {
+                SasState::Started { .. } if !sas.we_started() => {
+                    if let Err(e) = sas.accept().await {
+                        slog(&reporter.app, &reporter.log, "warn", format!("Failed to accept SAS: {}", e));
+                    }
+                }
+                SasState::KeysExchanged { .. } => {
+                    let Some(emojis) = sas.emoji() else { continue };
+                    let payload = VerificationEmojisEvent {
+                        account: reporter.account.clone(),
+                        flow_id: reporter.flow_id.clone(),
+                        user_id: reporter.user_id.clone(),
+                        emojis: emojis
+                            .iter()
+                            .map(|e| VerificationEmoji {
+                                symbol: e.symbol.to_string(),
+                                description: e.description.to_string(),
+                            })
+                            .collect(),
                     };
-                    use base64::Engine;
-                    let b64 = base64::engine::general_purpose::STANDARD.encode(&bytes);
-                    return Ok(format!("data:{};base64,{}", content_type, b64));
+                    let _ = reporter.app.emit("verification_emojis", &payload);
                 }
+                SasState::Done { .. } => reporter.done(),
+                SasState::Cancelled(info) => reporter.cancelled(info.reason()),
+                _ => {}
+            }
+            if reporter.is_finished() {
+                break;
             }
         }
-    }
-    Err("Failed to fetch media from any endpoint".into())
+    };
+    let _ = tokio::time::timeout(VERIFICATION_TIMEOUT, follow).await;
+}
+
+/// Ask the user to confirm once the other side has scanned our code.
+async fn watch_qr(reporter: VerificationReporter, qr: matrix_sdk::encryption::verification::QrVerification) {
+    use matrix_sdk::encryption::verification::QrVerificationState;
+
+    let follow = async {
+        let mut changes = std::pin::pin!(qr.changes());
+        let mut next = Some(qr.state());
+        while let Some(state) = match next.take() {
+            Some(state) => Some(state),
+            None => changes.next().await,
+        } {
+            match state {
+                QrVerificationState::Scanned => {
+                    let _ = reporter.app.emit(
+                        "verification_qr_scanned",
+                        serde_json::json!({ "account": reporter.account, "flow_id": reporter.flow_id }),
+                    );
+                }
+                QrVerificationState::Done { .. } => reporter.done(),
+                QrVerificationState::Cancelled(info) => reporter.cancelled(info.reason()),
+                _ => {}
+            }
+            if reporter.is_finished() {
+                break;
+            }
+        }
+    };
+    let _ = tokio::time::timeout(VERIFICATION_TIMEOUT, follow).await;
 }
 
+/// Compare emojis instead of using QR codes.
 #[tauri::command]
-pub async fn accept_verification(
+pub async fn start_sas_verification(
+    account: String,
     user_id: String,
     flow_id: String,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
 ) -> Result<(), String> {
     let log = state.log.clone();
-    slog(&app, &log, "info", format!("Accepting verification from {} (flow={})", user_id, flow_id));
-
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?.clone();
-    drop(client_lock);
+    slog(&app, &log, "info", format!("Starting emoji verification (flow={})", flow_id));
 
+    let client = state.client(&account).await?;
     let user_id = matrix_sdk::ruma::UserId::parse(&user_id)
         .map_err(|e| format!("Invalid user_id: {}", e))?;
 
@@ -1956,103 +4655,62 @@ pub async fn accept_verification(
         .get_verification_request(&user_id, &flow_id)
         .await
         .ok_or("Verification request not found")?;
-
     request
-        .accept()
+        .start_sas()
         .await
-        .map_err(|e| format!("Failed to accept: {}", e))?;
-
-    slog(&app, &log, "info", "Accepted, waiting to start SAS...".into());
-
-    // Spawn a task to start SAS (with retries) and wait for emojis.
-    // After accept(), the ready event needs to propagate via sync before
-    // start_sas() will succeed. The other side may also start SAS first.
-    let uid = user_id.to_owned();
-    let fid = flow_id.clone();
-    let poll_log = log.clone();
-    let poll_client = client.clone();
-    tokio::spawn(async move {
-        let mut sas_opt = None;
-
-        // Phase 1: Get SAS started (up to 20s — either we start it or the other side does)
-        for i in 0..40 {
-            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        .map_err(|e| format!("Failed to start emoji verification: {}", e))?
+        .ok_or("Emoji verification isn't available for this request")?;
+    Ok(())
+}
 
-            // Try to start SAS ourselves
-            match request.start_sas().await {
-                Ok(Some(s)) => {
-                    slog(&app, &poll_log, "info", format!("SAS started by us after {}ms", (i + 1) * 500));
-                    sas_opt = Some(s);
-                    break;
-                }
-                Ok(None) => {
-                    // Check if the other side started SAS
-                    if let Some(verification) = poll_client
-                        .encryption()
-                        .get_verification(&uid, &fid)
-                        .await
-                    {
-                        if let Some(s) = verification.sas() {
-                            slog(&app, &poll_log, "info", format!("SAS started by other side after {}ms", (i + 1) * 500));
-                            sas_opt = Some(s);
-                            break;
-                        }
-                    }
-                }
-                Err(e) => {
-                    slog(&app, &poll_log, "warn", format!("start_sas attempt failed: {}", e));
-                }
-            }
+/// Verify by scanning the other side's QR code, from an image file or from
+/// pasted data (an image data URL or the payload itself).
+#[tauri::command]
+pub async fn scan_verification_qr(
+    account: String,
+    user_id: String,
+    flow_id: String,
+    code: Option<String>,
+    image_path: Option<String>,
+    app: tauri::AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<(), String> {
+    use matrix_sdk::encryption::verification::QrVerificationData;
 
-            if i % 6 == 5 {
-                slog(&app, &poll_log, "info", format!("Still waiting to start SAS ({}s)...", (i + 1) / 2));
-            }
-        }
+    let log = state.log.clone();
+    slog(&app, &log, "info", format!("Scanning QR code (flow={})", flow_id));
 
-        let sas = match sas_opt {
-            Some(s) => s,
-            None => {
-                slog(&app, &poll_log, "warn", "Timed out waiting for SAS to start".into());
-                let _ = app.emit(
-                    "verification_cancelled",
-                    serde_json::json!({ "flow_id": fid, "reason": "Timed out starting SAS" }),
-                );
-                return;
-            }
-        };
+    let client = state.client(&account).await?;
+    let user_id = matrix_sdk::ruma::UserId::parse(&user_id)
+        .map_err(|e| format!("Invalid user_id: {}", e))?;
 
-        // Phase 2: Wait for emojis (up to 30s — key exchange needs to complete via sync)
-        for i in 0..60 {
-            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-            if let Some(emojis) = sas.emoji() {
-                slog(&app, &poll_log, "info", format!("SAS emojis ready after {}ms", (i + 1) * 500));
-                let payload = VerificationEmojisEvent {
-                    flow_id: fid,
-                    user_id: uid.to_string(),
-                    emojis: emojis
-                        .iter()
-                        .map(|e| VerificationEmoji {
-                            symbol: e.symbol.to_string(),
-                            description: e.description.to_string(),
-                        })
-                        .collect(),
-                };
-                let _ = app.emit("verification_emojis", &payload);
-                return;
-            }
+    let payload = match (image_path, code) {
+        (Some(path), _) => {
+            let bytes = std::fs::read(&path).map_err(|e| format!("Failed to read image: {}", e))?;
+            crate::qr::payload_from_image(&bytes)?
         }
-        slog(&app, &poll_log, "warn", "SAS emoji timeout after 30s".into());
-        let _ = app.emit(
-            "verification_cancelled",
-            serde_json::json!({ "flow_id": fid, "reason": "Timed out waiting for emojis" }),
-        );
-    });
+        (None, Some(code)) => crate::qr::payload_from_input(&code)?,
+        (None, None) => return Err("No QR code given".to_string()),
+    };
+    let data = QrVerificationData::from_bytes(payload)
+        .map_err(|e| format!("Invalid verification code: {}", e))?;
 
+    let request = client
+        .encryption()
+        .get_verification_request(&user_id, &flow_id)
+        .await
+        .ok_or("Verification request not found")?;
+    request
+        .scan_qr_code(data)
+        .await
+        .map_err(|e| format!("Code doesn't match: {}", e))?
+        .ok_or("QR verification isn't available for this request")?;
     Ok(())
 }
 
 #[tauri::command]
 pub async fn confirm_verification(
+    account: String,
     user_id: String,
     flow_id: String,
     app: tauri::AppHandle,
@@ -2061,8 +4719,7 @@ pub async fn confirm_verification(
     let log = state.log.clone();
     slog(&app, &log, "info", format!("Confirming verification (flow={})", flow_id));
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    let client = state.client(&account).await?;
 
     let user_id = matrix_sdk::ruma::UserId::parse(&user_id)
         .map_err(|e| format!("Invalid user_id: {}", e))?;
@@ -2073,22 +4730,23 @@ pub async fn confirm_verification(
         .await
         .ok_or("Verification not found")?;
 
-    let sas = verification.sas().ok_or("Not a SAS verification")?;
-    sas.confirm()
-        .await
-        .map_err(|e| format!("Failed to confirm: {}", e))?;
+    // Either the emojis matched or the other side scanned our QR code
+    match verification {
+        matrix_sdk::encryption::verification::Verification::SasV1(sas) => sas.confirm().await,
+        matrix_sdk::encryption::verification::Verification::QrV1(qr) => qr.confirm().await,
+        _ => return Err("Unsupported verification method".to_string()),
+    }
+    .map_err(|e| format!("Failed to confirm: {}", e))?;
 
-    slog(&app, &log, "info", "Verification confirmed!".into());
-    let _ = app.emit(
-        "verification_done",
-        serde_json::json!({ "flow_id": flow_id, "user_id": user_id.to_string() }),
-    );
+    // verification_done follows once the other side confirms too
+    slog(&app, &log, "info", "Verification confirmed, waiting for the other side".into());
 
     Ok(())
 }
 
 #[tauri::command]
 pub async fn cancel_verification(
+    account: String,
     user_id: String,
     flow_id: String,
     app: tauri::AppHandle,
@@ -2097,43 +4755,28 @@ pub async fn cancel_verification(
     let log = state.log.clone();
     slog(&app, &log, "info", format!("Cancelling verification (flow={})", flow_id));
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    let client = state.client(&account).await?;
 
     let user_id = matrix_sdk::ruma::UserId::parse(&user_id)
         .map_err(|e| format!("Invalid user_id: {}", e))?;
 
-    if let Some(request) = client
-        .encryption()
-        .get_verification_request(&user_id, &flow_id)
-        .await
-    {
-        request
-            .cancel()
-            .await
-            .map_err(|e| format!("Failed to cancel: {}", e))?;
-    } else if let Some(verification) = client
-        .encryption()
-        .get_verification(&user_id, &flow_id)
-        .await
-    {
-        if let Some(sas) = verification.sas() {
-            sas.mismatch()
-                .await
-                .map_err(|e| format!("Failed to cancel: {}", e))?;
+    // The flow's state watcher reports the cancellation to the dialog
+    let encryption = client.encryption();
+    let result = if let Some(request) = encryption.get_verification_request(&user_id, &flow_id).await {
+        request.cancel().await
+    } else {
+        match encryption.get_verification(&user_id, &flow_id).await {
+            Some(matrix_sdk::encryption::verification::Verification::SasV1(sas)) => sas.cancel().await,
+            Some(matrix_sdk::encryption::verification::Verification::QrV1(qr)) => qr.cancel().await,
+            _ => return Err("Verification not found".to_string()),
         }
-    }
-
-    let _ = app.emit(
-        "verification_cancelled",
-        serde_json::json!({ "flow_id": flow_id }),
-    );
-
-    Ok(())
+    };
+    result.map_err(|e| format!("Failed to cancel: {}", e))
 }
 
 #[tauri::command]
 pub async fn search_users(
+    account: String,
     query: String,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
@@ -2141,8 +4784,7 @@ pub async fn search_users(
     let log = state.log.clone();
     slog(&app, &log, "info", format!("search_users: {}", query));
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    let client = state.client(&account).await?;
 
     use matrix_sdk::ruma::api::client::user_directory::search_users;
 
@@ -2155,6 +4797,7 @@ pub async fn search_users(
     let hs = client.homeserver().to_string();
     let results: Vec<Buddy> = response.results.iter().map(|user| {
         Buddy {
+            account: account.clone(),
             user_id: user.user_id.to_string(),
             display_name: user.display_name.clone().unwrap_or_else(|| user.user_id.to_string()),
             avatar_url: user.avatar_url.as_ref().and_then(|u| mxc_to_http(&hs, &u.to_string())),
@@ -2168,6 +4811,7 @@ pub async fn search_users(
 
 #[tauri::command]
 pub async fn join_room(
+    account: String,
     room_id_or_alias: String,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
@@ -2175,8 +4819,7 @@ pub async fn join_room(
     let log = state.log.clone();
     slog(&app, &log, "info", format!("join_room: {}", room_id_or_alias));
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    let client = state.client(&account).await?;
 
     let id = matrix_sdk::ruma::OwnedRoomOrAliasId::try_from(room_id_or_alias.as_str())
         .map_err(|e| format!("Invalid room ID or alias: {}", e))?;
@@ -2194,11 +4837,14 @@ pub async fn join_room(
         .unwrap_or_else(|_| room_id_str.clone());
 
     slog(&app, &log, "info", format!("Joined room: {} ({})", name, room_id_str));
+    let is_encrypted = room.latest_encryption_state().await.is_ok_and(|s| s.is_encrypted());
 
     Ok(Room {
+        account: account.clone(),
         room_id: room_id_str,
         name,
         is_direct: false,
+        is_encrypted,
         last_message: None,
         unread_count: 0,
     })
@@ -2206,18 +4852,20 @@ pub async fn join_room(
 
 #[tauri::command]
 pub async fn create_room(
+    account: String,
     room_alias: String,
+    private: Option<bool>,
+    encrypted: Option<bool>,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
 ) -> Result<Room, String> {
     let log = state.log.clone();
     slog(&app, &log, "info", format!("create_room: {}", room_alias));
+    let (preset, encrypted) = new_room_settings(private, encrypted);
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    let client = state.client(&account).await?;
 
     use matrix_sdk::ruma::api::client::room::create_room::v3::Request as CreateRoomRequest;
-    use matrix_sdk::ruma::api::client::room::create_room::v3::RoomPreset;
 
     // Extract local alias from #alias:server → alias
     let local_alias = room_alias
@@ -2230,7 +4878,10 @@ pub async fn create_room(
     let mut request = CreateRoomRequest::new();
     request.room_alias_name = Some(local_alias.clone());
     request.name = Some(local_alias.clone());
-    request.preset = Some(RoomPreset::PublicChat);
+    request.preset = Some(preset);
+    if encrypted {
+        request.initial_state = vec![encryption_initial_state()];
+    }
 
     let response = client.create_room(request).await.map_err(|e| {
         slog(&app, &log, "error", format!("Failed to create room: {}", e));
@@ -2250,16 +4901,32 @@ pub async fn create_room(
     slog(&app, &log, "info", format!("Created room: {} ({})", name, room_id_str));
 
     Ok(Room {
+        account: account.clone(),
         room_id: room_id_str,
         name,
         is_direct: false,
+        is_encrypted: encrypted,
         last_message: None,
         unread_count: 0,
     })
 }
 
+/// The preset and encryption for a new chat room. Public rooms stay readable
+/// to anyone who joins; private ones are encrypted unless asked otherwise.
+fn new_room_settings(
+    private: Option<bool>,
+    encrypted: Option<bool>,
+) -> (matrix_sdk::ruma::api::client::room::create_room::v3::RoomPreset, bool) {
+    use matrix_sdk::ruma::api::client::room::create_room::v3::RoomPreset;
+
+    let private = private.unwrap_or(false);
+    let preset = if private { RoomPreset::PrivateChat } else { RoomPreset::PublicChat };
+    (preset, encrypted.unwrap_or(private))
+}
+
 #[tauri::command]
 pub async fn leave_room(
+    account: String,
     room_id: String,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
@@ -2267,8 +4934,7 @@ pub async fn leave_room(
     let log = state.log.clone();
     slog(&app, &log, "info", format!("leave_room: {}", room_id));
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    let client = state.client(&account).await?;
 
     let room_id = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
         .map_err(|e| format!("Invalid room ID: {}", e))?;
@@ -2285,6 +4951,7 @@ pub async fn leave_room(
 
 #[tauri::command]
 pub async fn remove_buddy(
+    account: String,
     user_id: String,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
@@ -2292,8 +4959,7 @@ pub async fn remove_buddy(
     let log = state.log.clone();
     slog(&app, &log, "info", format!("remove_buddy: {}", user_id));
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    let client = state.client(&account).await?;
 
     let target_id = matrix_sdk::ruma::UserId::parse(&user_id)
         .map_err(|e| format!("Invalid user ID: {}", e))?;
@@ -2323,14 +4989,14 @@ pub async fn remove_buddy(
 
 #[tauri::command]
 pub async fn get_pending_invites(
+    account: String,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
 ) -> Result<Vec<InviteInfo>, String> {
     let log = state.log.clone();
     slog(&app, &log, "info", "get_pending_invites".into());
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    let client = state.client(&account).await?;
 
     let mut invites = Vec::new();
     for room in client.invited_rooms() {
@@ -2351,6 +5017,7 @@ pub async fn get_pending_invites(
         }
 
         invites.push(InviteInfo {
+            account: account.clone(),
             room_id,
             room_name,
             inviter,
@@ -2364,6 +5031,7 @@ pub async fn get_pending_invites(
 
 #[tauri::command]
 pub async fn accept_invite(
+    account: String,
     room_id: String,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
@@ -2371,8 +5039,7 @@ pub async fn accept_invite(
     let log = state.log.clone();
     slog(&app, &log, "info", format!("accept_invite: {}", room_id));
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    let client = state.client(&account).await?;
 
     let room_id_parsed = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
         .map_err(|e| format!("Invalid room ID: {}", e))?;
@@ -2392,11 +5059,14 @@ pub async fn accept_invite(
     let is_direct = room.is_direct().await.unwrap_or(false);
 
     slog(&app, &log, "info", format!("Accepted invite to: {}", name));
+    let is_encrypted = room.latest_encryption_state().await.is_ok_and(|s| s.is_encrypted());
 
     Ok(Room {
+        account: account.clone(),
         room_id,
         name,
         is_direct,
+        is_encrypted,
         last_message: None,
         unread_count: 0,
     })
@@ -2404,6 +5074,7 @@ pub async fn accept_invite(
 
 #[tauri::command]
 pub async fn reject_invite(
+    account: String,
     room_id: String,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
@@ -2411,8 +5082,7 @@ pub async fn reject_invite(
     let log = state.log.clone();
     slog(&app, &log, "info", format!("reject_invite: {}", room_id));
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    let client = state.client(&account).await?;
 
     let room_id_parsed = matrix_sdk::ruma::OwnedRoomId::try_from(room_id.as_str())
         .map_err(|e| format!("Invalid room ID: {}", e))?;
@@ -2430,14 +5100,14 @@ pub async fn reject_invite(
 
 #[tauri::command]
 pub async fn get_room_tags(
+    account: String,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
 ) -> Result<std::collections::HashMap<String, Vec<String>>, String> {
     let log = state.log.clone();
     slog(&app, &log, "info", "get_room_tags: fetching tags for all joined rooms...".into());
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    let client = state.client(&account).await?;
 
     use matrix_sdk::ruma::api::client::tag::get_tags;
     use matrix_sdk::ruma::events::tag::TagName;
@@ -2480,6 +5150,7 @@ pub async fn get_room_tags(
 
 #[tauri::command]
 pub async fn set_room_tag(
+    account: String,
     room_id: String,
     tag: String,
     app: tauri::AppHandle,
@@ -2488,8 +5159,7 @@ pub async fn set_room_tag(
     let log = state.log.clone();
     slog(&app, &log, "info", format!("set_room_tag: {} -> u.{}", room_id, tag));
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    let client = state.client(&account).await?;
 
     use matrix_sdk::ruma::api::client::tag::create_tag;
     use matrix_sdk::ruma::events::tag::TagInfo;
@@ -2511,6 +5181,7 @@ pub async fn set_room_tag(
 
 #[tauri::command]
 pub async fn remove_room_tag(
+    account: String,
     room_id: String,
     tag: String,
     app: tauri::AppHandle,
@@ -2519,8 +5190,7 @@ pub async fn remove_room_tag(
     let log = state.log.clone();
     slog(&app, &log, "info", format!("remove_room_tag: {} -> u.{}", room_id, tag));
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    let client = state.client(&account).await?;
 
     use matrix_sdk::ruma::api::client::tag::delete_tag;
 
@@ -2562,6 +5232,7 @@ pub async fn set_dock_badge(count: u32) -> Result<(), String> {
 
 #[tauri::command]
 pub async fn search_spaces(
+    account: String,
     query: String,
     limit: Option<u32>,
     server: Option<String>,
@@ -2576,8 +5247,7 @@ pub async fn search_spaces(
         format!("search_spaces: query={} server={:?}", query, server),
     );
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    let client = state.client(&account).await?;
 
     // If query looks like a room alias, resolve it directly via federation
     // Supports: #space:server.org or #space (with server field filled in)
@@ -2700,6 +5370,7 @@ pub async fn search_spaces(
 
 #[tauri::command]
 pub async fn get_space_hierarchy(
+    account: String,
     space_id: String,
     app: tauri::AppHandle,
     state: State<'_, MatrixState>,
@@ -2712,8 +5383,7 @@ pub async fn get_space_hierarchy(
         format!("get_space_hierarchy: {}", space_id),
     );
 
-    let client_lock = state.client.lock().await;
-    let client = client_lock.as_ref().ok_or("Not logged in")?;
+    let client = state.client(&account).await?;
 
     use matrix_sdk::ruma::api::client::space::get_hierarchy;
     use matrix_sdk::ruma::uint;
@@ -2808,6 +5478,177 @@ mod tests {
         assert!(mxc_to_http("https://matrix.org", "mxc://noslash").is_none());
     }
 
+    // ── media_source_to_mxc ──────────────────────────────────
+
+    #[test]
+    fn media_source_to_mxc_plain_and_encrypted() {
+        let plain: matrix_sdk::ruma::events::room::MediaSource =
+            serde_json::from_value(serde_json::json!({ "url": "mxc://example.org/plain" })).unwrap();
+        assert_eq!(media_source_to_mxc(&plain).as_deref(), Some("mxc://example.org/plain"));
+
+        let encrypted: matrix_sdk::ruma::events::room::MediaSource = serde_json::from_value(serde_json::json!({
+            "file": {
+                "url": "mxc://example.org/secret",
+                "key": { "kty": "oct", "key_ops": ["encrypt", "decrypt"], "alg": "A256CTR", "k": "qcHVMSgYg-71CauWBezXI5qkaRb0LuIy-Wx5kIaHMIA", "ext": true },
+                "iv": "X85+XgHN+HEAAAAAAAAAAA",
+                "hashes": { "sha256": "5qG4fFnbbVdlAB1Q72JDKwCagV6Dbkx9uds4rSak37c" },
+                "v": "v2"
+            }
+        })).unwrap();
+        assert_eq!(media_source_to_mxc(&encrypted).as_deref(), Some("mxc://example.org/secret"));
+    }
+
+    // ── media_data_url ───────────────────────────────────────
+
+    #[test]
+    fn media_data_url_sniffs_type() {
+        assert!(media_data_url(&[0x89, 0x50, 0x4E, 0x47, 0x0D]).starts_with("data:image/png;base64,"));
+        assert!(media_data_url(&[0xFF, 0xD8, 0xFF]).starts_with("data:image/jpeg;base64,"));
+        assert_eq!(media_data_url(b"hi"), "data:application/octet-stream;base64,aGk=");
+    }
+
+    // ── device_display_name ──────────────────────────────────
+
+    #[test]
+    fn device_display_name_uses_host_or_os() {
+        assert_eq!(device_display_name_for(Some("studio.local\n"), "macos"), "ICQ26a on studio.local");
+        assert_eq!(device_display_name_for(Some("  "), "linux"), "ICQ26a (linux)");
+        assert_eq!(device_display_name_for(None, "windows"), "ICQ26a (windows)");
+    }
+
+    // ── oauth_client_metadata ────────────────────────────────
+
+    #[test]
+    fn oauth_client_metadata_is_native_with_loopback_redirect() {
+        let redirect = reqwest::Url::parse("http://127.0.0.1:43210/").unwrap();
+        let metadata = oauth_client_metadata(&redirect).unwrap();
+        let json: serde_json::Value = serde_json::from_str(metadata.json().get()).unwrap();
+        assert_eq!(json["application_type"], "native");
+        assert_eq!(json["redirect_uris"][0], "http://127.0.0.1:43210/");
+        assert_eq!(json["client_name"], "ICQ26a");
+        assert!(json["grant_types"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|g| g == "authorization_code"));
+    }
+
+    /// Runs the OAuth login against a mock homeserver and authorization
+    /// server: dynamic registration, the code exchange and restoring the
+    /// persisted session on a new client.
+    #[tokio::test]
+    async fn oauth_login_against_mock_server() {
+        use crate::test_support::{mock_homeserver, mount_oauth_metadata};
+        use wiremock::matchers::{body_string_contains, method, path};
+        use wiremock::{Mock, ResponseTemplate};
+
+        let server = mock_homeserver().await;
+        let issuer = mount_oauth_metadata(&server).await;
+        Mock::given(method("POST"))
+            .and(path("/oauth2/registration"))
+            .and(body_string_contains("ICQ26a"))
+            .respond_with(ResponseTemplate::new(201).set_body_json(serde_json::json!({
+                "client_id": "icq26a-client"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/oauth2/token"))
+            .and(body_string_contains("code=auth-code"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "mat_access",
+                "refresh_token": "mar_refresh",
+                "token_type": "Bearer",
+                "expires_in": 300
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = Client::builder()
+            .homeserver_url(server.uri())
+            .insecure_rewrite_https_to_http()
+            .build()
+            .await
+            .unwrap();
+        let redirect = reqwest::Url::parse("http://127.0.0.1:43210/").unwrap();
+        let auth_data = start_oauth_login(&client, redirect).await.unwrap();
+        assert!(auth_data.url.as_str().starts_with(&format!("{}/oauth2/authorize", issuer)));
+
+        let param = |name: &str| {
+            auth_data
+                .url
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+                .unwrap()
+        };
+        assert_eq!(param("client_id"), "icq26a-client");
+        assert_eq!(param("code_challenge_method"), "S256");
+        // The device ID is picked by the client and requested as a scope
+        let device_id = param("scope")
+            .split(' ')
+            .find_map(|scope| scope.rsplit_once(":device:").map(|(_, id)| id.to_string()))
+            .unwrap();
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/v3/account/whoami"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "user_id": "@alice:localhost",
+                "device_id": device_id
+            })))
+            .mount(&server)
+            .await;
+
+        let query = format!("code=auth-code&state={}", param("state"));
+        let session = finish_oauth_login(&client, query).await.unwrap();
+        assert_eq!(session.user_id, "@alice:localhost");
+        assert_eq!(session.device_id, device_id);
+        assert_eq!(session.access_token, "mat_access");
+        assert_eq!(session.refresh_token.as_deref(), Some("mar_refresh"));
+        assert_eq!(session.oauth_client_id.as_deref(), Some("icq26a-client"));
+
+        let json = serde_json::to_string(&session).unwrap();
+        let saved: PersistedSession = serde_json::from_str(&json).unwrap();
+        let restored = Client::builder()
+            .homeserver_url(&saved.homeserver_url)
+            .insecure_rewrite_https_to_http()
+            .build()
+            .await
+            .unwrap();
+        restored
+            .restore_session(auth_session_from_persisted(saved).unwrap())
+            .await
+            .unwrap();
+        let restored_session = persisted_session_from_client(&restored, None).unwrap();
+        assert_eq!(restored_session.user_id, "@alice:localhost");
+        assert_eq!(restored_session.device_id, device_id);
+        assert_eq!(restored_session.access_token, "mat_access");
+        assert_eq!(restored_session.refresh_token.as_deref(), Some("mar_refresh"));
+        assert_eq!(restored_session.oauth_client_id.as_deref(), Some("icq26a-client"));
+    }
+
+    // ── registration_flows ───────────────────────────────────
+
+    #[test]
+    fn registration_flows_from_uiaa_challenge() {
+        let body = serde_json::json!({
+            "flows": [{ "stages": ["m.login.recaptcha", "m.login.terms"] }, { "stages": ["m.login.dummy"] }],
+            "session": "abc"
+        });
+        assert_eq!(
+            registration_flows(reqwest::StatusCode::UNAUTHORIZED, &body),
+            Some(vec![vec!["m.login.recaptcha".to_string(), "m.login.terms".to_string()], vec!["m.login.dummy".to_string()]])
+        );
+    }
+
+    #[test]
+    fn registration_flows_none_when_closed() {
+        let body = serde_json::json!({ "errcode": "M_FORBIDDEN", "error": "Registration has been disabled" });
+        assert_eq!(registration_flows(reqwest::StatusCode::FORBIDDEN, &body), None);
+        assert_eq!(registration_flows(reqwest::StatusCode::UNAUTHORIZED, &body), None);
+    }
+
     // ── extract_reply_fallback ───────────────────────────────
 
     #[test]
@@ -2860,4 +5701,378 @@ mod tests {
         let body = "> <@user:host> quoted";
         assert_eq!(strip_reply_fallback(body), "");
     }
+
+    fn parse_message(content: serde_json::Value) -> Message {
+        let event = serde_json::from_value(serde_json::json!({
+            "type": "m.room.message",
+            "event_id": "$reply:host",
+            "sender": "@user:host",
+            "origin_server_ts": 1_700_000_000_000u64,
+            "content": content,
+        }))
+        .unwrap();
+        match message_update("@me:host", "!room:host", &event, None) {
+            Some(MessageUpdate::New(message)) => *message,
+            _ => panic!("expected a new message"),
+        }
+    }
+
+    fn thread_message(relates_to: serde_json::Value) -> Message {
+        parse_message(serde_json::json!({ "msgtype": "m.text", "body": "in thread", "m.relates_to": relates_to }))
+    }
+
+    #[test]
+    fn reply_fallback_without_relation_keeps_quote_but_no_event() {
+        let message = parse_message(serde_json::json!({
+            "msgtype": "m.text",
+            "body": "> <@bob:host> original\n\nanswer",
+        }));
+        assert_eq!(message.body, "answer");
+        assert_eq!(message.in_reply_to, None);
+        assert_eq!(message.reply_sender_name.as_deref(), Some("@bob:host"));
+        assert_eq!(message.reply_body.as_deref(), Some("original"));
+    }
+
+    #[test]
+    fn thread_message_keeps_root_and_drops_fallback_reply() {
+        let message = thread_message(serde_json::json!({
+            "rel_type": "m.thread",
+            "event_id": "$root:host",
+            "is_falling_back": true,
+            "m.in_reply_to": { "event_id": "$previous:host" },
+        }));
+        assert_eq!(message.thread_root.as_deref(), Some("$root:host"));
+        assert_eq!(message.in_reply_to, None);
+    }
+
+    #[test]
+    fn thread_message_with_real_reply() {
+        let message = thread_message(serde_json::json!({
+            "rel_type": "m.thread",
+            "event_id": "$root:host",
+            "m.in_reply_to": { "event_id": "$quoted:host" },
+        }));
+        assert_eq!(message.thread_root.as_deref(), Some("$root:host"));
+        assert_eq!(message.in_reply_to.as_deref(), Some("$quoted:host"));
+    }
+
+    // ── session_change_event ─────────────────────────────────
+
+    #[test]
+    fn refreshed_tokens_only_get_saved() {
+        assert_eq!(session_change_event(&matrix_sdk::SessionChange::TokensRefreshed), None);
+    }
+
+    #[test]
+    fn rejected_token_ends_the_session() {
+        let change = matrix_sdk::SessionChange::UnknownToken { soft_logout: false };
+        assert_eq!(session_change_event(&change), Some("session_ended"));
+        let payload = serde_json::to_value(SessionEvent { account: "@alice:host".into() }).unwrap();
+        assert_eq!(payload, serde_json::json!({ "account": "@alice:host" }));
+    }
+
+    #[test]
+    fn soft_logout_expires_the_session() {
+        let change = matrix_sdk::SessionChange::UnknownToken { soft_logout: true };
+        assert_eq!(session_change_event(&change), Some("session_expired"));
+    }
+
+    // ── replace_reauthenticated ──────────────────────────────
+
+    async fn test_client() -> Client {
+        Client::builder().homeserver_url("http://localhost:8008").build().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn failed_reauthentication_keeps_the_expired_account() {
+        let old = Account::new(test_client().await, Some("passphrase".into()));
+        old.mark_expired();
+        let mut accounts = std::collections::HashMap::new();
+
+        let result = replace_reauthenticated(&mut accounts, "@alice:host".into(), old, Err("Login failed".into()));
+
+        assert_eq!(result, Err("Login failed".to_string()));
+        let entry = accounts.get("@alice:host").unwrap();
+        assert!(entry.is_expired());
+        assert_eq!(entry.store_passphrase.as_deref(), Some("passphrase"));
+    }
+
+    #[tokio::test]
+    async fn reauthentication_replaces_the_expired_account() {
+        let old = Account::new(test_client().await, None);
+        old.mark_expired();
+        let new = Account::new(test_client().await, None);
+        let mut accounts = std::collections::HashMap::new();
+
+        let result = replace_reauthenticated(&mut accounts, "@alice:host".into(), old, Ok(new));
+
+        assert_eq!(result, Ok("@alice:host".to_string()));
+        assert!(!accounts.get("@alice:host").unwrap().is_expired());
+    }
+
+    // ── verify_session / token_rejection ─────────────────────
+
+    const WHOAMI: &str = "/_matrix/client/v3/account/whoami";
+
+    async fn mount_whoami(server: &wiremock::MockServer, response: wiremock::ResponseTemplate) {
+        wiremock::Mock::given(wiremock::matchers::method("GET"))
+            .and(wiremock::matchers::path(WHOAMI))
+            .respond_with(response)
+            .mount(server)
+            .await;
+    }
+
+    fn unknown_token(soft_logout: bool) -> wiremock::ResponseTemplate {
+        wiremock::ResponseTemplate::new(401).set_body_json(serde_json::json!({
+            "errcode": "M_UNKNOWN_TOKEN",
+            "error": "Invalid access token",
+            "soft_logout": soft_logout
+        }))
+    }
+
+    #[tokio::test]
+    async fn verify_session_accepts_matching_user() {
+        let server = crate::test_support::mock_homeserver().await;
+        mount_whoami(&server, wiremock::ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "user_id": crate::test_support::USER_ID,
+            "device_id": "ALICEDEVICE"
+        })))
+        .await;
+        let client = crate::test_support::logged_in_client(&server).await;
+        assert!(verify_session(&client, crate::test_support::USER_ID).await.is_ok());
+        assert!(matches!(
+            verify_session(&client, "@bob:localhost").await,
+            Err(SessionCheck::Rejected { soft_logout: false })
+        ));
+    }
+
+    #[tokio::test]
+    async fn verify_session_reports_soft_and_hard_logout() {
+        for soft_logout in [true, false] {
+            let server = crate::test_support::mock_homeserver().await;
+            mount_whoami(&server, unknown_token(soft_logout)).await;
+            let client = crate::test_support::logged_in_client(&server).await;
+            let check = verify_session(&client, crate::test_support::USER_ID).await;
+            assert!(matches!(check, Err(SessionCheck::Rejected { soft_logout: s }) if s == soft_logout));
+        }
+    }
+
+    #[tokio::test]
+    async fn verify_session_treats_other_failures_as_unreachable() {
+        let server = crate::test_support::mock_homeserver().await;
+        mount_whoami(&server, wiremock::ResponseTemplate::new(502)).await;
+        let client = crate::test_support::logged_in_client(&server).await;
+        assert!(matches!(
+            verify_session(&client, crate::test_support::USER_ID).await,
+            Err(SessionCheck::Unreachable(_))
+        ));
+
+        // Nothing listening any more
+        drop(server);
+        assert!(matches!(
+            verify_session(&client, crate::test_support::USER_ID).await,
+            Err(SessionCheck::Unreachable(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn verify_session_rejects_revoked_oauth_refresh_token() {
+        let server = crate::test_support::mock_homeserver().await;
+        crate::test_support::mount_oauth_metadata(&server).await;
+        mount_whoami(&server, unknown_token(false)).await;
+        wiremock::Mock::given(wiremock::matchers::method("POST"))
+            .and(wiremock::matchers::path("/oauth2/token"))
+            .respond_with(wiremock::ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "error": "invalid_grant",
+                "error_description": "The refresh token was revoked"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = Client::builder()
+            .homeserver_url(server.uri())
+            .insecure_rewrite_https_to_http()
+            .handle_refresh_tokens()
+            .request_config(matrix_sdk::config::RequestConfig::new().disable_retry())
+            .build()
+            .await
+            .unwrap();
+        let session = PersistedSession {
+            homeserver_url: server.uri(),
+            user_id: crate::test_support::USER_ID.into(),
+            device_id: "ALICEDEVICE".into(),
+            access_token: "mat_expired".into(),
+            refresh_token: Some("mar_revoked".into()),
+            secret_backend: None,
+            store_passphrase: None,
+            oauth_client_id: Some("icq26a-client".into()),
+        };
+        client.restore_session(auth_session_from_persisted(session).unwrap()).await.unwrap();
+
+        assert!(matches!(
+            verify_session(&client, crate::test_support::USER_ID).await,
+            Err(SessionCheck::Rejected { soft_logout: false })
+        ));
+    }
+
+    #[test]
+    fn oauth_rejections_are_told_apart_from_outages() {
+        assert!(oauth_refresh_rejected("Server returned error response: invalid_grant: revoked"));
+        assert!(oauth_refresh_rejected("invalid_client"));
+        assert!(!oauth_refresh_rejected("Request failed: connection refused"));
+        assert!(!oauth_refresh_rejected("Server returned error response: temporarily_unavailable"));
+    }
+
+    // ── enable_progress_event ────────────────────────────────
+
+    #[test]
+    fn enable_progress_maps_to_backup_events() {
+        use matrix_sdk::crypto::store::types::RoomKeyCounts;
+        use matrix_sdk::encryption::recovery::EnableProgress;
+
+        assert!(enable_progress_event("@alice:localhost", &EnableProgress::Starting).is_none());
+
+        let event = enable_progress_event("@alice:localhost", &EnableProgress::CreatingBackup).unwrap();
+        assert_eq!(event.account, "@alice:localhost");
+        assert_eq!(event.stage, "creating_backup");
+        assert_eq!((event.done, event.total), (None, None));
+
+        let counts = RoomKeyCounts { total: 40, backed_up: 15 };
+        let event = enable_progress_event("@alice:localhost", &EnableProgress::BackingUp(counts)).unwrap();
+        assert_eq!(event.stage, "backing_up");
+        assert_eq!((event.done, event.total), (Some(15), Some(40)));
+
+        let event = enable_progress_event("@alice:localhost", &EnableProgress::RoomKeyUploadError).unwrap();
+        assert_eq!(event.stage, "upload_error");
+    }
+
+    #[test]
+    fn enable_progress_done_keeps_the_recovery_key_out() {
+        use matrix_sdk::encryption::recovery::EnableProgress;
+
+        let done = EnableProgress::Done { recovery_key: "EsTc LW2K PGiF".into() };
+        let event = enable_progress_event("@alice:localhost", &done).unwrap();
+        assert_eq!(event.stage, "done");
+        let json = serde_json::to_string(&event).unwrap();
+        assert!(!json.contains("EsTc"));
+    }
+
+    // ── verification events ──────────────────────────────────
+
+    #[test]
+    fn verification_done_names_the_flow_and_user() {
+        let event = verification_done_event("@alice:localhost", "flow1", "@bob:localhost");
+        assert_eq!(event, serde_json::json!({
+            "account": "@alice:localhost",
+            "flow_id": "flow1",
+            "user_id": "@bob:localhost"
+        }));
+    }
+
+    #[test]
+    fn verification_cancelled_carries_the_reason() {
+        let event = verification_cancelled_event("@alice:localhost", "flow1", "No response");
+        assert_eq!(event["account"], "@alice:localhost");
+        assert_eq!(event["flow_id"], "flow1");
+        assert_eq!(event["reason"], "No response");
+    }
+
+    // ── room key export / import ─────────────────────────────
+
+    /// A passphrase-protected key file holding one session for `room`.
+    async fn room_key_file(name: &str, room: &matrix_sdk::ruma::RoomId) -> (std::path::PathBuf, String) {
+        use matrix_sdk::ruma::{device_id, user_id};
+
+        let account = matrix_sdk_crypto::olm::Account::with_device_id(user_id!("@bob:localhost"), device_id!("BOBDEVICE"));
+        let (_, inbound) = account.create_group_session_pair_with_defaults(room).await;
+        let export = matrix_sdk_crypto::encrypt_room_key_export(&[inbound.export().await], "hunter2", 1).unwrap();
+        let path = std::env::temp_dir().join(format!("icq26a-{}-{}.txt", name, std::process::id()));
+        std::fs::write(&path, export).unwrap();
+        (path, inbound.session_id().to_owned())
+    }
+
+    #[tokio::test]
+    async fn imported_room_keys_are_counted_and_exported() {
+        let room = matrix_sdk::ruma::room_id!("!room:localhost");
+        let (key_file, session_id) = room_key_file("import", room).await;
+        let server = crate::test_support::mock_homeserver().await;
+        let client = crate::test_support::logged_in_client(&server).await;
+
+        let result = client.encryption().import_room_keys(key_file.clone(), "hunter2").await.unwrap();
+        assert_eq!((result.imported_count, result.total_count), (1, 1));
+        assert!(result.keys[room].values().any(|sessions| sessions.contains(&session_id)));
+
+        // Importing the same file again adds nothing new
+        let result = client.encryption().import_room_keys(key_file.clone(), "hunter2").await.unwrap();
+        assert_eq!((result.imported_count, result.total_count), (0, 1));
+
+        let export_file = key_file.with_extension("export");
+        assert_eq!(export_all_room_keys(&client, export_file.clone(), "s3cret").await, Ok(1));
+        let exported = std::fs::read_to_string(&export_file).unwrap();
+        assert!(exported.starts_with("-----BEGIN MEGOLM SESSION DATA-----"));
+
+        let _ = std::fs::remove_file(key_file);
+        let _ = std::fs::remove_file(export_file);
+    }
+
+    #[tokio::test]
+    async fn wrong_passphrase_is_an_export_error() {
+        use matrix_sdk::encryption::RoomKeyImportError;
+
+        let (key_file, _) = room_key_file("wrong-pass", matrix_sdk::ruma::room_id!("!room:localhost")).await;
+        let server = crate::test_support::mock_homeserver().await;
+        let client = crate::test_support::logged_in_client(&server).await;
+
+        let result = client.encryption().import_room_keys(key_file.clone(), "wrong").await;
+        assert!(matches!(result, Err(RoomKeyImportError::Export(_))));
+        let _ = std::fs::remove_file(key_file);
+    }
+
+    #[test]
+    fn imported_sessions_release_only_their_waiting_events() {
+        use std::collections::{BTreeMap, BTreeSet};
+
+        let mut pending = UndecryptedEvents::default();
+        pending.insert("s1", "!a:localhost", "$1");
+        pending.insert("s1", "!b:localhost", "$2");
+        pending.insert("s2", "!a:localhost", "$3");
+
+        let keys = BTreeMap::from([(
+            matrix_sdk::ruma::owned_room_id!("!a:localhost"),
+            BTreeMap::from([("sender".to_string(), BTreeSet::from(["s1".to_string()]))]),
+        )]);
+        assert_eq!(
+            take_imported(&mut pending, &keys),
+            vec![("s1".to_string(), "!a:localhost".to_string(), "$1".to_string())]
+        );
+        assert!(take_imported(&mut pending, &keys).is_empty());
+
+        let mut rest = pending.take_all();
+        rest.sort();
+        assert_eq!(rest, vec![
+            ("s1".to_string(), "!b:localhost".to_string(), "$2".to_string()),
+            ("s2".to_string(), "!a:localhost".to_string(), "$3".to_string()),
+        ]);
+    }
+
+    // ── room encryption ──────────────────────────────────────
+
+    #[test]
+    fn encryption_initial_state_enables_megolm() {
+        let event: serde_json::Value = encryption_initial_state().deserialize_as().unwrap();
+        assert_eq!(event["type"], "m.room.encryption");
+        assert_eq!(event["state_key"], "");
+        assert_eq!(event["content"]["algorithm"], "m.megolm.v1.aes-sha2");
+    }
+
+    #[test]
+    fn private_rooms_are_encrypted_by_default() {
+        use matrix_sdk::ruma::api::client::room::create_room::v3::RoomPreset;
+
+        assert_eq!(new_room_settings(None, None), (RoomPreset::PublicChat, false));
+        assert_eq!(new_room_settings(Some(true), None), (RoomPreset::PrivateChat, true));
+        assert_eq!(new_room_settings(Some(true), Some(false)), (RoomPreset::PrivateChat, false));
+        assert_eq!(new_room_settings(Some(false), Some(true)), (RoomPreset::PublicChat, true));
+    }
 }
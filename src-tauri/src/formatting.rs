@@ -0,0 +1,96 @@
+use matrix_sdk::ruma::events::room::message::{FormattedBody, MessageFormat, MessageType};
+use ruma_html::{Html, ListBehavior, SanitizerConfig};
+
+/// Tags kept in a message's `formatted_body`. Anything else is dropped but
+/// its text kept; attributes and link schemes follow the spec's strict rules.
+const ALLOWED_TAGS: &[&str] = &[
+    "a", "b", "strong", "i", "em", "u", "s", "del", "strike", "code", "pre", "blockquote", "p", "br", "hr",
+    "ul", "ol", "li", "h1", "h2", "h3", "h4", "h5", "h6", "sup", "sub", "span", "font", "table", "thead",
+    "tbody", "tr", "th", "td", "caption", "details", "summary",
+];
+
+/// Tags dropped together with their content, which isn't meant to be read.
+const REMOVED_TAGS: &[&str] = &["script", "style", "iframe", "object", "embed", "noscript", "template", "title"];
+
+/// Reduce untrusted HTML to the allowed tags, dropping the rich reply
+/// fallback we show separately.
+pub fn sanitize_html(html: &str) -> String {
+    let config = SanitizerConfig::strict()
+        .allow_elements(ALLOWED_TAGS.iter().copied(), ListBehavior::Override)
+        .remove_elements(REMOVED_TAGS.iter().copied())
+        .remove_reply_fallback();
+    let html = Html::parse(html);
+    html.sanitize_with(&config);
+    html.to_string()
+}
+
+/// The sanitized HTML of a text, notice or emote message, if it has any.
+/// Emotes get the same `* ` prefix as their plain body.
+pub fn message_html(msgtype: &MessageType) -> Option<String> {
+    let (formatted, prefix) = match msgtype {
+        MessageType::Text(text) => (text.formatted.as_ref(), ""),
+        MessageType::Notice(notice) => (notice.formatted.as_ref(), ""),
+        MessageType::Emote(emote) => (emote.formatted.as_ref(), "* "),
+        _ => return None,
+    };
+    formatted_html(formatted?).map(|html| format!("{}{}", prefix, html))
+}
+
+fn formatted_html(formatted: &FormattedBody) -> Option<String> {
+    if formatted.format != MessageFormat::Html {
+        return None;
+    }
+    Some(sanitize_html(&formatted.body)).filter(|html| !html.trim().is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_keeps_formatting_and_safe_links() {
+        let html = sanitize_html(
+            "<b>bold</b> <code>x</code> <a href=\"https://example.org\">link</a><blockquote>quote</blockquote>",
+        );
+        assert_eq!(
+            html,
+            "<b>bold</b> <code>x</code> <a href=\"https://example.org\">link</a><blockquote>quote</blockquote>"
+        );
+    }
+
+    #[test]
+    fn sanitize_drops_scripts_handlers_and_unsafe_schemes() {
+        let html = sanitize_html(
+            "<script>alert(1)</script><p onclick=\"x()\" style=\"color:red\">hi</p>\
+             <a href=\"javascript:alert(1)\">bad</a><img src=\"mxc://a/b\"><iframe>frame</iframe>",
+        );
+        assert!(!html.contains("script"));
+        assert!(!html.contains("alert"));
+        assert!(!html.contains("onclick"));
+        assert!(!html.contains("style"));
+        assert!(!html.contains("javascript"));
+        assert!(!html.contains("<img"));
+        assert!(!html.contains("frame"));
+        assert!(html.contains("<p>hi</p>"));
+    }
+
+    #[test]
+    fn sanitize_removes_reply_fallback() {
+        let html = sanitize_html("<mx-reply><blockquote>quoted</blockquote></mx-reply>answer");
+        assert_eq!(html, "answer");
+    }
+
+    #[test]
+    fn message_html_only_for_html_formatted_text() {
+        use matrix_sdk::ruma::events::room::message::{EmoteMessageEventContent, TextMessageEventContent};
+
+        let plain = MessageType::Text(TextMessageEventContent::plain("hi"));
+        assert_eq!(message_html(&plain), None);
+
+        let rich = MessageType::Text(TextMessageEventContent::html("*hi*", "<em>hi</em>"));
+        assert_eq!(message_html(&rich).as_deref(), Some("<em>hi</em>"));
+
+        let emote = MessageType::Emote(EmoteMessageEventContent::html("waves", "<b>waves</b>"));
+        assert_eq!(message_html(&emote).as_deref(), Some("* <b>waves</b>"));
+    }
+}
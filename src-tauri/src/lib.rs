@@ -1,7 +1,15 @@
 #![recursion_limit = "512"]
 
 mod commands;
+mod formatting;
 mod matrix_client;
+mod qr;
+mod secret_store;
+mod slash_commands;
+mod store_encryption;
+#[cfg(test)]
+mod test_support;
+mod uiaa;
 
 use matrix_client::MatrixState;
 
@@ -37,27 +45,60 @@ pub fn run() {
         })
         .invoke_handler(tauri::generate_handler![
             commands::matrix_login,
+            commands::matrix_login_sso,
+            commands::matrix_login_oauth,
             commands::matrix_register,
+            commands::matrix_register_stage,
+            commands::matrix_register_cancel,
+            commands::discover_server,
             commands::matrix_logout,
+            commands::matrix_reauthenticate,
             commands::matrix_disconnect,
             commands::try_restore_session,
+            commands::get_accounts,
+            commands::list_stores,
+            commands::delete_store,
+            commands::list_devices,
+            commands::rename_device,
+            commands::delete_devices,
+            commands::change_password,
+            commands::deactivate_account,
+            commands::get_secret_storage,
+            commands::unlock_secret_storage,
+            commands::set_secret_storage,
+            commands::set_store_encryption,
             commands::get_buddy_list,
             commands::get_room_members,
             commands::get_rooms,
             commands::get_spaces,
             commands::get_room_messages,
+            commands::get_room_threads,
+            commands::get_thread_messages,
             commands::send_message,
             commands::set_presence,
             commands::start_sync,
             commands::upload_file,
             commands::fetch_media,
+            commands::get_cross_signing_status,
+            commands::bootstrap_cross_signing,
+            commands::accept_identity_change,
+            commands::get_recovery_status,
+            commands::enable_recovery,
+            commands::recover_keys,
+            commands::export_room_keys,
+            commands::import_room_keys,
             commands::get_server_log,
             commands::accept_verification,
             commands::confirm_verification,
             commands::cancel_verification,
+            commands::request_user_verification,
+            commands::request_device_verification,
+            commands::start_sas_verification,
+            commands::scan_verification_qr,
             commands::get_user_profile,
             commands::get_room_info,
             commands::create_dm_room,
+            commands::enable_room_encryption,
             commands::search_users,
             commands::join_room,
             commands::create_room,
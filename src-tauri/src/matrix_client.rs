@@ -1,11 +1,16 @@
+use crate::secret_store::{SecretBackend, SecretStore};
+use crate::uiaa::{TermsPolicy, UiaaInfo};
 use matrix_sdk::Client;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Buddy {
+    pub account: String,
     pub user_id: String,
     pub display_name: String,
     pub avatar_url: Option<String>,
@@ -14,15 +19,30 @@ pub struct Buddy {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Room {
+    pub account: String,
     pub room_id: String,
     pub name: String,
     pub is_direct: bool,
+    pub is_encrypted: bool,
     pub last_message: Option<String>,
     pub unread_count: u64,
 }
 
+/// What `send_message` did with the message box contents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SendOutcome {
+    /// A message went out, or a command finished in place.
+    Sent,
+    /// A command joined or opened a room the UI should show.
+    OpenRoom { room: Room },
+    /// `/part` left this room.
+    Left { room_id: String },
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Space {
+    pub account: String,
     pub room_id: String,
     pub name: String,
     pub child_room_ids: Vec<String>,
@@ -30,22 +50,38 @@ pub struct Space {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
+    pub account: String,
     pub room_id: String,
     pub event_id: String,
     pub sender: String,
     pub sender_name: String,
     pub body: String,
+    /// Sanitized HTML for rich text messages; safe to render as-is.
+    pub formatted_body: Option<String>,
     pub timestamp: u64,
     pub msg_type: String,
     pub media_url: Option<String>,
+    /// The media at `media_url` is encrypted; fetch it with this event's ID.
+    pub media_encrypted: bool,
     pub filename: Option<String>,
     pub in_reply_to: Option<String>,
     pub reply_sender_name: Option<String>,
     pub reply_body: Option<String>,
+    /// `msg_type` of the replied-to message.
+    pub reply_msg_type: Option<String>,
+    /// Thumbnail (or the image itself) of a replied-to media message.
+    pub reply_thumbnail: Option<String>,
+    /// `reply_thumbnail` is encrypted; fetch it with `in_reply_to`.
+    pub reply_media_encrypted: bool,
+    /// Root event of the thread this message was sent in.
+    pub thread_root: Option<String>,
+    /// Megolm session of an `undecryptable` message, whose key we're waiting for.
+    pub session_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TypingEvent {
+    pub account: String,
     pub room_id: String,
     pub user_ids: Vec<String>,
     pub display_names: Vec<String>,
@@ -53,21 +89,25 @@ pub struct TypingEvent {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageEditEvent {
+    pub account: String,
     pub room_id: String,
     pub original_event_id: String,
     pub new_body: String,
+    pub new_formatted_body: Option<String>,
     pub sender: String,
     pub sender_name: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageDeletedEvent {
+    pub account: String,
     pub room_id: String,
     pub event_id: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReactionEvent {
+    pub account: String,
     pub room_id: String,
     pub event_id: String,
     pub reaction_key: String,
@@ -78,6 +118,7 @@ pub struct ReactionEvent {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InviteInfo {
+    pub account: String,
     pub room_id: String,
     pub room_name: Option<String>,
     pub inviter: Option<String>,
@@ -90,6 +131,20 @@ pub struct MessagesPage {
     pub end_token: Option<String>,
 }
 
+/// A thread in a room: its root message and a summary of the replies.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomThread {
+    pub root: Message,
+    pub reply_count: u32,
+    pub latest_reply: Option<Message>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreadsPage {
+    pub threads: Vec<RoomThread>,
+    pub end_token: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LoginCredentials {
     pub homeserver: String,
@@ -97,13 +152,167 @@ pub struct LoginCredentials {
     pub password: String,
 }
 
+/// What a homeserver offers, gathered up front so the UI can hide what it
+/// doesn't support. Fields that need an access token are `None` when
+/// discovering for the login screen.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServerCapabilities {
+    /// Client-server API base URL after `.well-known` discovery.
+    pub homeserver_url: String,
+    /// Spec versions from `/versions`, e.g. `v1.11`.
+    pub versions: Vec<String>,
+    /// Unstable features the server reports as enabled.
+    pub unstable_features: Vec<String>,
+    /// `/login` flow types, e.g. `m.login.password` or `m.login.sso`.
+    pub login_flows: Vec<String>,
+    pub identity_providers: Vec<IdentityProviderInfo>,
+    /// The server delegates auth to an OAuth 2.0 provider.
+    pub oauth: bool,
+    /// Stage lists of the registration flows, or `None` if registration is closed.
+    pub registration_flows: Option<Vec<Vec<String>>>,
+    pub change_password: Option<bool>,
+    pub set_displayname: Option<bool>,
+    pub set_avatar_url: Option<bool>,
+    pub default_room_version: Option<String>,
+    /// Largest upload the media repository accepts, in bytes.
+    pub max_upload_size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdentityProviderInfo {
+    pub id: String,
+    pub name: String,
+    pub brand: Option<String>,
+}
+
+/// A failed server call, with the Matrix error code when the server sent one
+/// so the UI can tell a wrong password from a weak one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerError {
+    /// Matrix `errcode`, e.g. `M_FORBIDDEN`; `None` for local and network errors.
+    pub errcode: Option<String>,
+    pub message: String,
+    /// HTTP status of the response, if there was one.
+    pub status: Option<u16>,
+}
+
+impl ServerError {
+    pub fn from_http(e: &matrix_sdk::HttpError) -> Self {
+        use matrix_sdk::ruma::api::client::error::ErrorBody;
+
+        // A rejected UIAA stage comes back as a new challenge carrying the error
+        if let Some(error) = e.as_uiaa_response().and_then(|info| info.auth_error.as_ref()) {
+            return Self {
+                errcode: Some(error.kind.errcode().to_string()),
+                message: error.message.clone(),
+                status: Some(401),
+            };
+        }
+        match e.as_client_api_error() {
+            Some(api) => match &api.body {
+                ErrorBody::Standard(body) => Self {
+                    errcode: Some(body.kind.errcode().to_string()),
+                    message: body.message.clone(),
+                    status: Some(api.status_code.as_u16()),
+                },
+                _ => Self { errcode: None, message: e.to_string(), status: Some(api.status_code.as_u16()) },
+            },
+            None => e.to_string().into(),
+        }
+    }
+}
+
+impl From<String> for ServerError {
+    fn from(message: String) -> Self {
+        Self { errcode: None, message, status: None }
+    }
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.errcode {
+            Some(errcode) => write!(f, "{} ({})", self.message, errcode),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+/// Result of a registration call: either the new account, or the next
+/// user-interactive auth stage the homeserver wants completed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum RegistrationStatus {
+    Complete { user_id: String },
+    Stage(RegistrationStage),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistrationStage {
+    /// Stage type, e.g. `m.login.terms`.
+    pub stage: String,
+    pub completed: Vec<String>,
+    /// Stages left in the chosen flow, this one included.
+    pub remaining: Vec<String>,
+    /// Policies to accept for `m.login.terms`.
+    pub policies: Vec<TermsPolicy>,
+    /// Address the verification mail went to for `m.login.email.identity`.
+    pub email_sent_to: Option<String>,
+    /// Browser page for stages the app can't do itself, like reCAPTCHA.
+    pub fallback_url: Option<String>,
+    /// Why the last attempt at this stage was rejected.
+    pub error: Option<String>,
+}
+
+/// The user's answer to a registration stage.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RegistrationResponse {
+    AcceptTerms,
+    RegistrationToken { token: String },
+    /// Send (or resend) the verification mail.
+    RequestEmail { email: String },
+    /// The user followed the link in the verification mail.
+    EmailConfirmed,
+    /// The user finished the stage in the fallback page.
+    FallbackDone,
+}
+
+/// Registration waiting on the user between UIAA stages.
+pub struct PendingRegistration {
+    pub client: Client,
+    pub register_url: String,
+    pub body: serde_json::Value,
+    pub uiaa: UiaaInfo,
+    pub email: Option<EmailVerification>,
+}
+
+pub struct EmailVerification {
+    pub address: String,
+    pub client_secret: String,
+    pub sid: String,
+    pub send_attempt: u32,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PersistedSession {
     pub homeserver_url: String,
     pub user_id: String,
     pub device_id: String,
+    /// Empty on disk once the tokens live in the secret store.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub access_token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub refresh_token: Option<String>,
+    /// Where the tokens are kept; `None` for plaintext files from older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_backend: Option<SecretBackend>,
+    /// Sqlite store passphrase; kept with the tokens, never written here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub store_passphrase: Option<String>,
+    /// Client ID from OAuth 2.0 dynamic registration; `None` for sessions
+    /// created through the legacy Matrix login API.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oauth_client_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -114,6 +323,7 @@ pub struct VerificationEmoji {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VerificationEvent {
+    pub account: String,
     pub flow_id: String,
     pub user_id: String,
     pub is_self_verification: bool,
@@ -121,11 +331,63 @@ pub struct VerificationEvent {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VerificationEmojisEvent {
+    pub account: String,
     pub flow_id: String,
     pub user_id: String,
     pub emojis: Vec<VerificationEmoji>,
 }
 
+/// Emitted once both sides have agreed to verify, with the methods on offer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerificationReadyEvent {
+    pub account: String,
+    pub flow_id: String,
+    pub user_id: String,
+    /// Our QR code as an image data URL, if the other side can scan it
+    pub qr_code: Option<String>,
+    /// The other side can show a QR code for us to scan
+    pub can_scan: bool,
+    pub can_sas: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncStatusEvent {
+    pub account: String,
+    pub status: String,
+}
+
+/// Emitted as `session_expired` on soft logout, when signing back in keeps
+/// the device, and as `session_ended` when the token is rejected for good.
+/// Also the payload of `cross_signing_needed` after a login.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionEvent {
+    pub account: String,
+}
+
+/// Whether the account's encryption keys can be recovered on a new login.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecoveryStatus {
+    /// `enabled`, `disabled`, `incomplete` (set up, but this session lacks the
+    /// secrets) or `unknown`.
+    pub state: String,
+    /// Room keys from this session are being backed up.
+    pub backup_enabled: bool,
+    pub backup_exists_on_server: bool,
+}
+
+/// Progress of setting up recovery, restoring keys from the backup, or
+/// exporting and importing a key file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupProgressEvent {
+    pub account: String,
+    /// `creating_backup`, `creating_recovery_key`, `backing_up`,
+    /// `upload_error`, `recovering`, `downloading`, `exporting`, `importing`,
+    /// `retrying` or `done`.
+    pub stage: String,
+    pub done: Option<u64>,
+    pub total: Option<u64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogEntry {
     pub timestamp: u64,
@@ -181,6 +443,43 @@ pub struct UserProfile {
     pub presence: String,
     pub last_seen_ago: Option<u64>,
     pub shared_rooms: Vec<SharedRoom>,
+    /// We've verified the user's cross-signing identity.
+    pub verified: bool,
+    /// The user's master key differs from the one we saw before and hasn't
+    /// been accepted yet.
+    pub identity_changed: bool,
+    pub devices: Vec<UserDevice>,
+}
+
+/// One of another user's devices, as known to our crypto store.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserDevice {
+    pub device_id: String,
+    pub display_name: Option<String>,
+    pub verified: bool,
+}
+
+/// Emitted when a contact's cross-signing identity is replaced.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdentityChangedEvent {
+    pub account: String,
+    pub user_id: String,
+    /// `false` once the user has accepted the new identity.
+    pub changed: bool,
+}
+
+/// One of the account's sessions, as listed by `/devices`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub device_id: String,
+    pub display_name: Option<String>,
+    pub last_seen_ip: Option<String>,
+    /// Milliseconds since the epoch.
+    pub last_seen_ts: Option<u64>,
+    /// The session this app is running.
+    pub is_current: bool,
+    /// Signed by the account's self-signing key.
+    pub cross_signed: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -189,6 +488,7 @@ pub struct RoomProfile {
     pub name: String,
     pub topic: Option<String>,
     pub is_direct: bool,
+    pub is_encrypted: bool,
     pub member_count: u64,
 }
 
@@ -216,29 +516,410 @@ pub fn data_dir() -> Result<PathBuf, String> {
     Ok(base.join("icq26a"))
 }
 
-pub fn session_file_path() -> Result<PathBuf, String> {
-    Ok(data_dir()?.join("session.json"))
+/// Parent directory of the per-account stores.
+pub fn accounts_dir() -> Result<PathBuf, String> {
+    Ok(data_dir()?.join("accounts"))
+}
+
+/// Subdirectory of the accounts dir holding stores no session uses any more.
+const STALE_DIR: &str = "stale";
+
+/// Directory name for an account's store: a hash of the user ID and
+/// homeserver, so accounts on different servers never share a store.
+pub(crate) fn account_dir_name(user_id: &str, homeserver_url: &str) -> String {
+    let digest = Sha256::digest(format!("{}\n{}", user_id, homeserver_url.trim_end_matches('/')));
+    digest[..16].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Sqlite stores and session file of a single account.
+pub fn account_dir(user_id: &str, homeserver_url: &str) -> Result<PathBuf, String> {
+    Ok(accounts_dir()?.join(account_dir_name(user_id, homeserver_url)))
+}
+
+/// Move an account dir out of the way into the stale stores, keeping its
+/// data until the user deletes it. Returns the new location.
+pub fn retire_account_dir(dir: &Path) -> Result<PathBuf, String> {
+    let accounts = dir.parent().ok_or("Account dir has no parent")?;
+    let stale = accounts.join(STALE_DIR);
+    std::fs::create_dir_all(&stale)
+        .map_err(|e| format!("Failed to create stale store dir: {}", e))?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let name = dir.file_name().ok_or("Account dir has no name")?.to_string_lossy();
+    let target = stale.join(format!("{}-{}", name, now));
+    std::fs::rename(dir, &target)
+        .map_err(|e| format!("Failed to move old store aside: {}", e))?;
+    Ok(target)
+}
+
+/// A store directory on disk, signed in or not.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreInfo {
+    /// Path relative to the accounts dir; what `delete_store` takes.
+    pub id: String,
+    pub user_id: Option<String>,
+    pub homeserver_url: Option<String>,
+    pub device_id: Option<String>,
+    pub size_bytes: u64,
+    /// Last modification, seconds since the epoch.
+    pub modified: u64,
+    /// No saved session uses this store, so it can be deleted.
+    pub stale: bool,
+}
+
+/// Every store under the accounts dir, stale ones included.
+pub fn list_stores() -> Result<Vec<StoreInfo>, String> {
+    list_stores_in(&accounts_dir()?)
+}
+
+fn list_stores_in(accounts: &Path) -> Result<Vec<StoreInfo>, String> {
+    let mut dirs: Vec<(String, PathBuf)> = Vec::new();
+    for (prefix, parent) in [("", accounts.to_path_buf()), ("stale/", accounts.join(STALE_DIR))] {
+        let Ok(entries) = std::fs::read_dir(&parent) else { continue };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.path().is_dir() && !(prefix.is_empty() && name == STALE_DIR) {
+                dirs.push((format!("{}{}", prefix, name), entry.path()));
+            }
+        }
+    }
+    dirs.sort();
+
+    Ok(dirs
+        .into_iter()
+        .map(|(id, path)| {
+            let session = std::fs::read_to_string(path.join("session.json"))
+                .ok()
+                .and_then(|json| serde_json::from_str::<PersistedSession>(&json).ok());
+            let modified = std::fs::metadata(&path)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            StoreInfo {
+                stale: id.starts_with("stale/") || !path.join("session.json").exists(),
+                id,
+                user_id: session.as_ref().map(|s| s.user_id.clone()),
+                homeserver_url: session.as_ref().map(|s| s.homeserver_url.clone()),
+                device_id: session.as_ref().map(|s| s.device_id.clone()),
+                size_bytes: dir_size(&path),
+                modified,
+            }
+        })
+        .collect())
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else { return 0 };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.metadata() {
+            Ok(m) if m.is_dir() => dir_size(&entry.path()),
+            Ok(m) => m.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Session files of every account saved on disk.
+pub fn saved_session_files() -> Result<Vec<PathBuf>, String> {
+    let dir = accounts_dir()?;
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let entries = std::fs::read_dir(&dir)
+        .map_err(|e| format!("Failed to read accounts dir: {}", e))?;
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name() != STALE_DIR)
+        .map(|entry| entry.path().join("session.json"))
+        .filter(|path| path.exists())
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Bring older layouts up to date: a pre-multi-account store (session.json
+/// and sqlite files directly in the data dir), and account dirs named after
+/// the user ID instead of the hash. Returns the user IDs that were moved.
+pub fn migrate_legacy_store() -> Result<Vec<String>, String> {
+    migrate_legacy_store_in(&data_dir()?)
+}
+
+fn migrate_legacy_store_in(base: &Path) -> Result<Vec<String>, String> {
+    let mut moved = Vec::new();
+    if let Some(user_id) = migrate_root_store(base)? {
+        moved.push(user_id);
+    }
+
+    let Ok(entries) = std::fs::read_dir(base.join("accounts")) else { return Ok(moved) };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let Some(saved) = std::fs::read_to_string(entry.path().join("session.json"))
+            .ok()
+            .and_then(|json| serde_json::from_str::<PersistedSession>(&json).ok())
+        else {
+            continue;
+        };
+        let target = base.join("accounts").join(account_dir_name(&saved.user_id, &saved.homeserver_url));
+        if entry.path() != target && !target.exists() {
+            std::fs::rename(entry.path(), &target)
+                .map_err(|e| format!("Failed to rename store for {}: {}", saved.user_id, e))?;
+            moved.push(saved.user_id);
+        }
+    }
+    Ok(moved)
+}
+
+fn migrate_root_store(base: &Path) -> Result<Option<String>, String> {
+    let legacy_session = base.join("session.json");
+    if !legacy_session.exists() {
+        return Ok(None);
+    }
+    let json = std::fs::read_to_string(&legacy_session)
+        .map_err(|e| format!("Failed to read session: {}", e))?;
+    let saved: PersistedSession = serde_json::from_str(&json)
+        .map_err(|e| format!("Failed to parse session: {}", e))?;
+
+    let target = base.join("accounts").join(account_dir_name(&saved.user_id, &saved.homeserver_url));
+    std::fs::create_dir_all(&target)
+        .map_err(|e| format!("Failed to create account dir: {}", e))?;
+
+    let entries = std::fs::read_dir(base)
+        .map_err(|e| format!("Failed to read data dir: {}", e))?;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        // Store files include their -wal/-shm companions
+        if name == "session.json" || name.contains(".sqlite3") {
+            std::fs::rename(entry.path(), target.join(name.as_ref()))
+                .map_err(|e| format!("Failed to move {}: {}", name, e))?;
+        }
+    }
+    Ok(Some(saved.user_id))
+}
+
+/// One signed-in account: its client and the background tasks syncing it.
+pub struct Account {
+    pub client: Client,
+    /// Passphrase of the account's sqlite stores, `None` if unencrypted.
+    pub store_passphrase: Option<String>,
+    /// Set on soft logout until the user re-authenticates.
+    expired: std::sync::atomic::AtomicBool,
+    sync_tasks: std::sync::Mutex<Vec<tokio::task::JoinHandle<()>>>,
+    /// Events shown as undecryptable, retried when their room key arrives.
+    pub undecrypted: Arc<std::sync::Mutex<UndecryptedEvents>>,
+    /// Replied-to messages already looked up.
+    pub replies: Arc<std::sync::Mutex<ReplyCache>>,
+}
+
+impl Account {
+    pub fn new(client: Client, store_passphrase: Option<String>) -> Self {
+        Self {
+            client,
+            store_passphrase,
+            expired: std::sync::atomic::AtomicBool::new(false),
+            sync_tasks: std::sync::Mutex::new(Vec::new()),
+            undecrypted: Arc::default(),
+            replies: Arc::default(),
+        }
+    }
+
+    pub fn mark_expired(&self) {
+        self.expired.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expired.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn set_sync_tasks(&self, tasks: Vec<tokio::task::JoinHandle<()>>) {
+        let mut current = self.sync_tasks.lock().unwrap();
+        for task in current.drain(..) {
+            task.abort();
+        }
+        *current = tasks;
+    }
+
+    pub fn abort_sync_tasks(&self) {
+        self.set_sync_tasks(Vec::new());
+    }
+}
+
+/// `(room_id, event_id)` pairs that failed to decrypt, grouped by the Megolm
+/// session whose key was missing.
+#[derive(Debug, Default)]
+pub struct UndecryptedEvents(HashMap<String, Vec<(String, String)>>);
+
+impl UndecryptedEvents {
+    pub fn insert(&mut self, session_id: &str, room_id: &str, event_id: &str) {
+        let events = self.0.entry(session_id.to_string()).or_default();
+        if !events.iter().any(|(_, e)| e == event_id) {
+            events.push((room_id.to_string(), event_id.to_string()));
+        }
+    }
+
+    /// Remove and return the events in `room_id` waiting on `session_id`.
+    pub fn take(&mut self, room_id: &str, session_id: &str) -> Vec<(String, String)> {
+        let Some(events) = self.0.get_mut(session_id) else {
+            return Vec::new();
+        };
+        let (taken, rest) = events.drain(..).partition(|(r, _)| r == room_id);
+        *events = rest;
+        if events.is_empty() {
+            self.0.remove(session_id);
+        }
+        taken
+    }
+
+    /// Remove and return every pending event as `(session_id, room_id, event_id)`.
+    pub fn take_all(&mut self) -> Vec<(String, String, String)> {
+        self.0
+            .drain()
+            .flat_map(|(session_id, events)| {
+                events.into_iter().map(move |(room_id, event_id)| (session_id.clone(), room_id, event_id))
+            })
+            .collect()
+    }
+}
+
+/// What a reply shows of the message it answers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplyPreview {
+    pub sender_name: String,
+    pub body: String,
+    pub msg_type: String,
+    pub thumbnail: Option<String>,
+    pub media_encrypted: bool,
+}
+
+/// Replied-to messages by `(room_id, event_id)`, so paging through history
+/// doesn't fetch the same events again. The oldest entries go once it's full.
+#[derive(Debug, Default)]
+pub struct ReplyCache {
+    entries: HashMap<(String, String), ReplyPreview>,
+    order: VecDeque<(String, String)>,
+}
+
+impl ReplyCache {
+    const CAPACITY: usize = 1000;
+
+    pub fn get(&self, room_id: &str, event_id: &str) -> Option<ReplyPreview> {
+        self.entries.get(&(room_id.to_string(), event_id.to_string())).cloned()
+    }
+
+    pub fn insert(&mut self, room_id: &str, event_id: &str, preview: ReplyPreview) {
+        let key = (room_id.to_string(), event_id.to_string());
+        if self.entries.insert(key.clone(), preview).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > Self::CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    /// Forget a message that was edited or deleted.
+    pub fn remove(&mut self, room_id: &str, event_id: &str) {
+        let key = (room_id.to_string(), event_id.to_string());
+        if self.entries.remove(&key).is_some() {
+            self.order.retain(|k| k != &key);
+        }
+    }
+}
+
+/// Master keys of other users as we first saw them, kept in the account dir
+/// so a replaced identity is noticed across restarts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct KnownIdentities(HashMap<String, String>);
+
+impl KnownIdentities {
+    const FILE: &'static str = "identities.json";
+
+    pub fn load(account_dir: &Path) -> Self {
+        std::fs::read_to_string(account_dir.join(Self::FILE))
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, account_dir: &Path) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| format!("Failed to serialize identities: {}", e))?;
+        std::fs::write(account_dir.join(Self::FILE), json)
+            .map_err(|e| format!("Failed to write identities: {}", e))
+    }
+
+    /// Whether `master_key` replaces the one remembered for `user_id`. The
+    /// first key seen for a user is remembered.
+    pub fn check(&mut self, user_id: &str, master_key: &str) -> bool {
+        match self.0.get(user_id) {
+            Some(known) => known != master_key,
+            None => {
+                self.accept(user_id, master_key);
+                false
+            }
+        }
+    }
+
+    pub fn accept(&mut self, user_id: &str, master_key: &str) {
+        self.0.insert(user_id.to_string(), master_key.to_string());
+    }
+}
+
+/// Error returned by commands for an account waiting to re-authenticate.
+pub const SESSION_EXPIRED: &str = "Session expired";
+
+/// Error from restore when the server no longer accepts any saved session.
+pub const SESSION_REJECTED: &str = "Session rejected";
+
+/// How a saved account came back at startup.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestoreStatus {
+    /// The server confirmed the session.
+    Online,
+    /// The server couldn't be reached; the account runs from its local store
+    /// until sync gets through.
+    Offline,
+    /// Soft logout: the account needs its password again.
+    Expired,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestoredAccount {
+    pub user_id: String,
+    pub status: RestoreStatus,
 }
 
 pub struct MatrixState {
-    pub client: Arc<Mutex<Option<Client>>>,
+    /// Signed-in accounts keyed by Matrix user ID.
+    pub accounts: Arc<Mutex<HashMap<String, Account>>>,
     pub log: Arc<ServerLog>,
-    pub sync_tasks: std::sync::Mutex<Vec<tokio::task::JoinHandle<()>>>,
+    pub secrets: Arc<SecretStore>,
+    pub registration: Mutex<Option<PendingRegistration>>,
 }
 
 impl MatrixState {
     pub fn new() -> Self {
         Self {
-            client: Arc::new(Mutex::new(None)),
+            accounts: Arc::new(Mutex::new(HashMap::new())),
             log: Arc::new(ServerLog::new()),
-            sync_tasks: std::sync::Mutex::new(Vec::new()),
+            secrets: Arc::new(SecretStore::new()),
+            registration: Mutex::new(None),
         }
     }
 
-    pub fn abort_sync_tasks(&self) {
-        let mut tasks = self.sync_tasks.lock().unwrap();
-        for task in tasks.drain(..) {
-            task.abort();
+    /// Client for the given account, or an error if it isn't signed in or
+    /// its session has expired.
+    pub async fn client(&self, account: &str) -> Result<Client, String> {
+        match self.accounts.lock().await.get(account) {
+            Some(a) if a.is_expired() => Err(SESSION_EXPIRED.to_string()),
+            Some(a) => Ok(a.client.clone()),
+            None => Err("Not logged in".to_string()),
         }
     }
 }
@@ -247,6 +928,62 @@ impl MatrixState {
 mod tests {
     use super::*;
 
+    #[test]
+    fn known_identities_flag_replaced_master_keys() {
+        let mut known = KnownIdentities::default();
+        assert!(!known.check("@bob:x", "key1"));
+        assert!(!known.check("@bob:x", "key1"));
+        assert!(known.check("@bob:x", "key2"));
+        assert!(known.check("@bob:x", "key2"));
+        known.accept("@bob:x", "key2");
+        assert!(!known.check("@bob:x", "key2"));
+    }
+
+    #[test]
+    fn undecrypted_events_are_taken_per_room_and_session() {
+        let mut pending = UndecryptedEvents::default();
+        pending.insert("s1", "!a:x", "$1");
+        pending.insert("s1", "!a:x", "$1");
+        pending.insert("s1", "!b:x", "$2");
+        pending.insert("s2", "!a:x", "$3");
+
+        assert_eq!(pending.take("!a:x", "s1"), vec![("!a:x".to_string(), "$1".to_string())]);
+        assert!(pending.take("!a:x", "s1").is_empty());
+        assert!(pending.take("!a:x", "unknown").is_empty());
+
+        let mut rest = pending.take_all();
+        rest.sort();
+        assert_eq!(rest, vec![
+            ("s1".to_string(), "!b:x".to_string(), "$2".to_string()),
+            ("s2".to_string(), "!a:x".to_string(), "$3".to_string()),
+        ]);
+        assert!(pending.take_all().is_empty());
+    }
+
+    #[test]
+    fn reply_cache_evicts_oldest_and_forgets_removed() {
+        let preview = |body: &str| ReplyPreview {
+            sender_name: "bob".into(),
+            body: body.into(),
+            msg_type: "text".into(),
+            thumbnail: None,
+            media_encrypted: false,
+        };
+        let mut cache = ReplyCache::default();
+        for i in 0..=ReplyCache::CAPACITY {
+            cache.insert("!a:x", &format!("${}", i), preview("hi"));
+        }
+        assert_eq!(cache.get("!a:x", "$0"), None);
+        assert_eq!(cache.get("!a:x", "$1"), Some(preview("hi")));
+        assert_eq!(cache.get("!b:x", "$1"), None);
+
+        cache.insert("!a:x", "$1", preview("edited"));
+        assert_eq!(cache.get("!a:x", "$1").map(|p| p.body), Some("edited".to_string()));
+        cache.remove("!a:x", "$1");
+        assert_eq!(cache.get("!a:x", "$1"), None);
+        assert_eq!(cache.order.len(), ReplyCache::CAPACITY - 1);
+    }
+
     #[test]
     fn server_log_push_and_get() {
         let log = ServerLog::new();
@@ -278,4 +1015,161 @@ mod tests {
         let log = ServerLog::new();
         assert!(log.get_all().is_empty());
     }
+
+    #[test]
+    fn account_dir_name_hashes_user_and_homeserver() {
+        let name = account_dir_name("@alice:matrix.org", "https://matrix.org/");
+        assert_eq!(name.len(), 32);
+        assert!(name.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(name, account_dir_name("@alice:matrix.org", "https://matrix.org"));
+        assert_ne!(name, account_dir_name("@alice:matrix.org", "https://matrix-client.matrix.org/"));
+    }
+
+    #[test]
+    fn account_dir_name_is_path_safe_for_hostile_user_ids() {
+        for user_id in ["@bob/../x:matrix.org", "@../../etc:matrix.org", "@a\\..\\b:matrix.org"] {
+            let name = account_dir_name(user_id, "https://matrix.org/");
+            assert_eq!(name.len(), 32);
+            assert!(name.chars().all(|c| c.is_ascii_hexdigit()));
+            assert!(!name.contains('/') && !name.contains('\\') && !name.contains(".."));
+        }
+    }
+
+    #[test]
+    fn stale_stores_are_listed_and_skipped_on_restore() {
+        let base = std::env::temp_dir().join(format!("icq26a-stores-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let active = base.join("active");
+        let logged_out = base.join("logged-out");
+        std::fs::create_dir_all(&active).unwrap();
+        std::fs::create_dir_all(&logged_out).unwrap();
+        std::fs::write(
+            active.join("session.json"),
+            r#"{"homeserver_url":"https://matrix.org/","user_id":"@alice:matrix.org","device_id":"NEW"}"#,
+        )
+        .unwrap();
+        std::fs::write(logged_out.join("matrix-sdk-state.sqlite3"), b"12345").unwrap();
+
+        let old = base.join("old");
+        std::fs::create_dir_all(&old).unwrap();
+        std::fs::write(
+            old.join("session.json"),
+            r#"{"homeserver_url":"https://matrix.org/","user_id":"@alice:matrix.org","device_id":"OLD"}"#,
+        )
+        .unwrap();
+        let retired = retire_account_dir(&old).unwrap();
+        assert!(retired.starts_with(base.join("stale")));
+
+        let stores = list_stores_in(&base).unwrap();
+        let ids: Vec<_> = stores.iter().map(|s| (s.id.as_str(), s.stale)).collect();
+        assert_eq!(ids.len(), 3);
+        assert!(ids.contains(&("active", false)));
+        assert!(ids.contains(&("logged-out", true)));
+        let retired_info = stores.iter().find(|s| s.id.starts_with("stale/")).unwrap();
+        assert!(retired_info.stale);
+        assert_eq!(retired_info.device_id.as_deref(), Some("OLD"));
+        assert_eq!(stores.iter().find(|s| s.id == "logged-out").unwrap().size_bytes, 5);
+
+        let _ = std::fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn migrate_legacy_store_moves_files() {
+        let base = std::env::temp_dir().join(format!("icq26a-migrate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(&base).unwrap();
+        std::fs::write(
+            base.join("session.json"),
+            r#"{"homeserver_url":"https://matrix.org/","user_id":"@alice:matrix.org","device_id":"D","access_token":"t","refresh_token":null}"#,
+        )
+        .unwrap();
+        std::fs::write(base.join("matrix-sdk-state.sqlite3"), b"state").unwrap();
+        std::fs::write(base.join("matrix-sdk-crypto.sqlite3-wal"), b"wal").unwrap();
+
+        let migrated = migrate_legacy_store_in(&base).unwrap();
+        assert_eq!(migrated, vec!["@alice:matrix.org".to_string()]);
+
+        let target = base.join("accounts").join(account_dir_name("@alice:matrix.org", "https://matrix.org/"));
+        assert!(target.join("session.json").exists());
+        assert!(target.join("matrix-sdk-state.sqlite3").exists());
+        assert!(target.join("matrix-sdk-crypto.sqlite3-wal").exists());
+        assert!(!base.join("session.json").exists());
+
+        // Nothing left to migrate the second time round
+        assert!(migrate_legacy_store_in(&base).unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn migrate_renames_user_id_account_dirs() {
+        let base = std::env::temp_dir().join(format!("icq26a-rename-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let old = base.join("accounts").join("alice_matrix.org");
+        std::fs::create_dir_all(&old).unwrap();
+        std::fs::write(
+            old.join("session.json"),
+            r#"{"homeserver_url":"https://matrix.org/","user_id":"@alice:matrix.org","device_id":"D"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(migrate_legacy_store_in(&base).unwrap(), vec!["@alice:matrix.org".to_string()]);
+        let target = base.join("accounts").join(account_dir_name("@alice:matrix.org", "https://matrix.org/"));
+        assert!(target.join("session.json").exists());
+        assert!(!old.exists());
+        let _ = std::fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn persisted_session_on_disk_omits_tokens() {
+        let session = PersistedSession {
+            homeserver_url: "https://matrix.org/".into(),
+            user_id: "@alice:matrix.org".into(),
+            device_id: "ABCDEF".into(),
+            access_token: String::new(),
+            refresh_token: None,
+            secret_backend: Some(SecretBackend::Keyring),
+            store_passphrase: None,
+            oauth_client_id: None,
+        };
+        let json = serde_json::to_string(&session).unwrap();
+        assert!(!json.contains("access_token"));
+        assert!(!json.contains("refresh_token"));
+        assert!(!json.contains("store_passphrase"));
+        assert!(json.contains(r#""secret_backend":"keyring""#));
+
+        let parsed: PersistedSession = serde_json::from_str(&json).unwrap();
+        assert!(parsed.access_token.is_empty());
+        assert_eq!(parsed.secret_backend, Some(SecretBackend::Keyring));
+    }
+
+    #[test]
+    fn persisted_session_without_oauth_client_id() {
+        // Session files written before OAuth support must still load
+        let json = r#"{
+            "homeserver_url": "https://matrix.org/",
+            "user_id": "@alice:matrix.org",
+            "device_id": "ABCDEF",
+            "access_token": "syt_token",
+            "refresh_token": null
+        }"#;
+        let session: PersistedSession = serde_json::from_str(json).unwrap();
+        assert_eq!(session.user_id, "@alice:matrix.org");
+        assert!(session.oauth_client_id.is_none());
+    }
+
+    #[test]
+    fn persisted_session_omits_missing_oauth_client_id() {
+        let session = PersistedSession {
+            homeserver_url: "https://matrix.org/".into(),
+            user_id: "@alice:matrix.org".into(),
+            device_id: "ABCDEF".into(),
+            access_token: "syt_token".into(),
+            refresh_token: None,
+            secret_backend: None,
+            store_passphrase: None,
+            oauth_client_id: None,
+        };
+        let json = serde_json::to_string(&session).unwrap();
+        assert!(!json.contains("oauth_client_id"));
+    }
 }
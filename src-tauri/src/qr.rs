@@ -0,0 +1,98 @@
+use base64::Engine;
+
+/// Margin around the code, in modules, that scanners need to find it.
+const QUIET_ZONE: usize = 4;
+
+/// Every Matrix verification QR payload starts with this.
+const MATRIX_HEADER: &[u8] = b"MATRIX";
+
+/// Render a QR module grid (row-major, `true` = dark) as an SVG data URL the
+/// webview can show in an `<img>`.
+pub fn svg_data_url(width: usize, modules: &[bool]) -> String {
+    let size = width + QUIET_ZONE * 2;
+    let mut path = String::new();
+    for (i, _) in modules.iter().enumerate().filter(|(_, dark)| **dark) {
+        let (x, y) = (i % width + QUIET_ZONE, i / width + QUIET_ZONE);
+        path.push_str(&format!("M{x} {y}h1v1h-1z"));
+    }
+    let svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {size} {size}\" shape-rendering=\"crispEdges\">\
+         <rect width=\"{size}\" height=\"{size}\" fill=\"#fff\"/><path fill=\"#000\" d=\"{path}\"/></svg>"
+    );
+    format!(
+        "data:image/svg+xml;base64,{}",
+        base64::engine::general_purpose::STANDARD.encode(svg)
+    )
+}
+
+/// Turn what the user gave us into raw QR payload bytes: an image (as a data
+/// URL) to decode, base64 of the payload, or the payload pasted as-is.
+pub fn payload_from_input(input: &str) -> Result<Vec<u8>, String> {
+    let input = input.trim();
+    if let Some(rest) = input.strip_prefix("data:") {
+        let (_, data) = rest.split_once(";base64,").ok_or("Unsupported image data")?;
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .map_err(|e| format!("Invalid image data: {}", e))?;
+        return payload_from_image(&bytes);
+    }
+    if let Ok(bytes) = base64::engine::general_purpose::STANDARD.decode(input) {
+        if bytes.starts_with(MATRIX_HEADER) {
+            return Ok(bytes);
+        }
+    }
+    if input.as_bytes().starts_with(MATRIX_HEADER) {
+        return Ok(input.as_bytes().to_vec());
+    }
+    Err("That isn't a Matrix verification code".to_string())
+}
+
+/// Find and decode the first Matrix QR code in a PNG or JPEG.
+pub fn payload_from_image(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let image = image::load_from_memory(bytes)
+        .map_err(|e| format!("Could not read image: {}", e))?
+        .to_luma8();
+    let mut prepared = rqrr::PreparedImage::prepare_from_greyscale(
+        image.width() as usize,
+        image.height() as usize,
+        |x, y| image.get_pixel(x as u32, y as u32).0[0],
+    );
+    prepared
+        .detect_grids()
+        .iter()
+        .find_map(|grid| {
+            let mut payload = Vec::new();
+            grid.decode_to(&mut payload).ok()?;
+            payload.starts_with(MATRIX_HEADER).then_some(payload)
+        })
+        .ok_or_else(|| "No Matrix verification code found in the image".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn svg(url: &str) -> String {
+        let data = url.strip_prefix("data:image/svg+xml;base64,").unwrap();
+        String::from_utf8(base64::engine::general_purpose::STANDARD.decode(data).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn svg_draws_dark_modules_inside_quiet_zone() {
+        let out = svg(&svg_data_url(2, &[true, false, false, true]));
+        assert!(out.contains("viewBox=\"0 0 10 10\""));
+        assert!(out.contains("M4 4h1v1h-1z"));
+        assert!(out.contains("M5 5h1v1h-1z"));
+        assert!(!out.contains("M5 4"));
+    }
+
+    #[test]
+    fn payload_from_base64_or_raw_text() {
+        let raw = b"MATRIX\x02\x00payload".to_vec();
+        let encoded = base64::engine::general_purpose::STANDARD.encode(&raw);
+        assert_eq!(payload_from_input(&format!(" {encoded}\n")).unwrap(), raw);
+        assert_eq!(payload_from_input("MATRIXabc").unwrap(), b"MATRIXabc");
+        assert!(payload_from_input("hello").is_err());
+        assert!(payload_from_input("data:image/png;base64,!!").is_err());
+    }
+}
@@ -0,0 +1,338 @@
+use matrix_sdk_store_encryption::StoreCipher;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Keyring service name all of our entries are filed under.
+const KEYRING_SERVICE: &str = "icq26a";
+
+/// Where an account's access and refresh tokens are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretBackend {
+    /// OS secret service: Keychain, libsecret, Windows Credential Manager.
+    Keyring,
+    /// `secrets.enc` in the account dir, encrypted with a passphrase-protected key.
+    EncryptedFile,
+}
+
+/// The parts of a session that must not be written to disk in clear text.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionSecrets {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    /// Passphrase the account's sqlite stores are encrypted with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub store_passphrase: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecretStorageStatus {
+    pub backend: SecretBackend,
+    /// Whether the encrypted-file key has been created with a passphrase yet.
+    pub initialized: bool,
+    pub unlocked: bool,
+    /// Whether new sqlite stores get a random passphrase.
+    pub encrypt_stores: bool,
+    /// Whether existing unencrypted stores are encrypted on their next restore.
+    pub encrypt_existing_stores: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SecretSettings {
+    backend: Option<SecretBackend>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encrypt_stores: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encrypt_existing_stores: Option<bool>,
+}
+
+/// Token storage shared by all accounts. Holds the encrypted-file key once
+/// the user has unlocked it with their passphrase.
+pub struct SecretStore {
+    base: PathBuf,
+    cipher: std::sync::Mutex<Option<StoreCipher>>,
+}
+
+impl SecretStore {
+    pub fn new() -> Self {
+        Self::with_base(crate::matrix_client::data_dir().unwrap_or_default())
+    }
+
+    fn with_base(base: PathBuf) -> Self {
+        Self {
+            base,
+            cipher: std::sync::Mutex::new(None),
+        }
+    }
+
+    fn settings_path(&self) -> PathBuf {
+        self.base.join("secret_storage.json")
+    }
+
+    fn key_path(&self) -> PathBuf {
+        self.base.join("secrets.key")
+    }
+
+    fn settings(&self) -> SecretSettings {
+        std::fs::read_to_string(self.settings_path())
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    fn save_settings(&self, settings: &SecretSettings) -> Result<(), String> {
+        std::fs::create_dir_all(&self.base)
+            .map_err(|e| format!("Failed to create data dir: {}", e))?;
+        let json = serde_json::to_string_pretty(settings)
+            .map_err(|e| format!("Failed to serialize settings: {}", e))?;
+        std::fs::write(self.settings_path(), json)
+            .map_err(|e| format!("Failed to write settings: {}", e))
+    }
+
+    /// Backend new secrets are written to. Defaults to the OS keyring.
+    pub fn configured_backend(&self) -> SecretBackend {
+        self.settings().backend.unwrap_or(SecretBackend::Keyring)
+    }
+
+    pub fn set_configured_backend(&self, backend: SecretBackend) -> Result<(), String> {
+        let mut settings = self.settings();
+        settings.backend = Some(backend);
+        self.save_settings(&settings)
+    }
+
+    /// Whether new account stores should be encrypted. On unless turned off.
+    pub fn encrypt_stores(&self) -> bool {
+        self.settings().encrypt_stores.unwrap_or(true)
+    }
+
+    /// Whether existing unencrypted stores should be migrated. Off unless the
+    /// user asks for it, and never without `encrypt_stores`.
+    pub fn encrypt_existing_stores(&self) -> bool {
+        let settings = self.settings();
+        settings.encrypt_stores.unwrap_or(true) && settings.encrypt_existing_stores.unwrap_or(false)
+    }
+
+    pub fn set_encrypt_stores(&self, enabled: bool, existing: bool) -> Result<(), String> {
+        let mut settings = self.settings();
+        settings.encrypt_stores = Some(enabled);
+        settings.encrypt_existing_stores = Some(existing);
+        self.save_settings(&settings)
+    }
+
+    pub fn status(&self) -> SecretStorageStatus {
+        SecretStorageStatus {
+            backend: self.configured_backend(),
+            initialized: self.key_path().exists(),
+            unlocked: self.is_unlocked(),
+            encrypt_stores: self.encrypt_stores(),
+            encrypt_existing_stores: self.encrypt_existing_stores(),
+        }
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.cipher.lock().unwrap().is_some()
+    }
+
+    /// Unlock the encrypted-file key with the user's passphrase, creating the
+    /// key on first use.
+    pub fn unlock(&self, passphrase: &str) -> Result<(), String> {
+        if passphrase.is_empty() {
+            return Err("Passphrase must not be empty".to_string());
+        }
+        let key_path = self.key_path();
+        let cipher = if key_path.exists() {
+            let exported = std::fs::read(&key_path)
+                .map_err(|e| format!("Failed to read key file: {}", e))?;
+            StoreCipher::import(passphrase, &exported)
+                .map_err(|_| "Wrong passphrase".to_string())?
+        } else {
+            let cipher = StoreCipher::new()
+                .map_err(|e| format!("Failed to generate key: {}", e))?;
+            let exported = cipher
+                .export(passphrase)
+                .map_err(|e| format!("Failed to protect key: {}", e))?;
+            std::fs::create_dir_all(&self.base)
+                .map_err(|e| format!("Failed to create data dir: {}", e))?;
+            std::fs::write(&key_path, exported)
+                .map_err(|e| format!("Failed to write key file: {}", e))?;
+            cipher
+        };
+        *self.cipher.lock().unwrap() = Some(cipher);
+        Ok(())
+    }
+
+    /// Save an account's tokens in the configured backend. If the keyring is
+    /// unavailable and the encrypted file is unlocked, fall back to the file
+    /// in `account_dir`. Returns the backend the tokens ended up in.
+    pub fn store(&self, user_id: &str, account_dir: &Path, secrets: &SessionSecrets) -> Result<SecretBackend, String> {
+        match self.configured_backend() {
+            SecretBackend::Keyring => match keyring_store(user_id, secrets) {
+                Ok(()) => {
+                    // Don't leave an older copy behind in the other backend
+                    let _ = std::fs::remove_file(secrets_file(account_dir));
+                    Ok(SecretBackend::Keyring)
+                }
+                Err(_) if self.is_unlocked() => {
+                    self.file_store(account_dir, secrets)?;
+                    Ok(SecretBackend::EncryptedFile)
+                }
+                Err(e) => Err(format!(
+                    "System keyring unavailable ({}) — set a passphrase to use the encrypted file instead",
+                    e
+                )),
+            },
+            SecretBackend::EncryptedFile => {
+                self.file_store(account_dir, secrets)?;
+                let _ = keyring_delete(user_id);
+                Ok(SecretBackend::EncryptedFile)
+            }
+        }
+    }
+
+    pub fn load(&self, user_id: &str, account_dir: &Path, backend: SecretBackend) -> Result<SessionSecrets, String> {
+        match backend {
+            SecretBackend::Keyring => keyring_load(user_id),
+            SecretBackend::EncryptedFile => self.file_load(account_dir),
+        }
+    }
+
+    /// Remove an account's tokens from both backends.
+    pub fn delete(&self, user_id: &str, account_dir: &Path) {
+        let _ = keyring_delete(user_id);
+        let _ = std::fs::remove_file(secrets_file(account_dir));
+    }
+
+    fn file_store(&self, account_dir: &Path, secrets: &SessionSecrets) -> Result<(), String> {
+        let guard = self.cipher.lock().unwrap();
+        let cipher = guard.as_ref().ok_or("Secret store is locked")?;
+        let encrypted = cipher
+            .encrypt_value(secrets)
+            .map_err(|e| format!("Failed to encrypt tokens: {}", e))?;
+        std::fs::create_dir_all(account_dir)
+            .map_err(|e| format!("Failed to create account dir: {}", e))?;
+        write_private(&secrets_file(account_dir), &encrypted)
+    }
+
+    fn file_load(&self, account_dir: &Path) -> Result<SessionSecrets, String> {
+        let guard = self.cipher.lock().unwrap();
+        let cipher = guard.as_ref().ok_or("Secret store is locked")?;
+        let encrypted = std::fs::read(secrets_file(account_dir))
+            .map_err(|e| format!("Failed to read tokens: {}", e))?;
+        cipher
+            .decrypt_value(&encrypted)
+            .map_err(|e| format!("Failed to decrypt tokens: {}", e))
+    }
+}
+
+fn secrets_file(account_dir: &Path) -> PathBuf {
+    account_dir.join("secrets.enc")
+}
+
+fn keyring_entry(user_id: &str) -> Result<keyring::Entry, String> {
+    keyring::Entry::new(KEYRING_SERVICE, user_id).map_err(|e| e.to_string())
+}
+
+fn keyring_store(user_id: &str, secrets: &SessionSecrets) -> Result<(), String> {
+    let json = serde_json::to_string(secrets).map_err(|e| e.to_string())?;
+    keyring_entry(user_id)?.set_password(&json).map_err(|e| e.to_string())
+}
+
+fn keyring_load(user_id: &str) -> Result<SessionSecrets, String> {
+    let json = keyring_entry(user_id)?
+        .get_password()
+        .map_err(|e| format!("Failed to read tokens from keyring: {}", e))?;
+    serde_json::from_str(&json).map_err(|e| format!("Corrupt keyring entry: {}", e))
+}
+
+fn keyring_delete(user_id: &str) -> Result<(), String> {
+    keyring_entry(user_id)?.delete_credential().map_err(|e| e.to_string())
+}
+
+/// Write a file readable only by the current user where the OS supports it.
+fn write_private(path: &Path, data: &[u8]) -> Result<(), String> {
+    std::fs::write(path, data).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_store(name: &str) -> (SecretStore, PathBuf) {
+        let base = std::env::temp_dir().join(format!("icq26a-secrets-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        (SecretStore::with_base(base.clone()), base)
+    }
+
+    fn secrets() -> SessionSecrets {
+        SessionSecrets {
+            access_token: "syt_access".to_string(),
+            refresh_token: Some("syr_refresh".to_string()),
+            store_passphrase: Some("store-pass".to_string()),
+        }
+    }
+
+    #[test]
+    fn encrypted_file_round_trip() {
+        let (store, base) = temp_store("roundtrip");
+        store.set_configured_backend(SecretBackend::EncryptedFile).unwrap();
+        store.unlock("hunter2").unwrap();
+
+        let account_dir = base.join("accounts").join("alice");
+        let backend = store.store("@alice:example.org", &account_dir, &secrets()).unwrap();
+        assert_eq!(backend, SecretBackend::EncryptedFile);
+
+        let on_disk = std::fs::read(secrets_file(&account_dir)).unwrap();
+        assert!(!String::from_utf8_lossy(&on_disk).contains("syt_access"));
+
+        // A fresh process has to unlock again before reading
+        let reopened = SecretStore::with_base(base.clone());
+        assert!(reopened.load("@alice:example.org", &account_dir, SecretBackend::EncryptedFile).is_err());
+        reopened.unlock("hunter2").unwrap();
+        let loaded = reopened.load("@alice:example.org", &account_dir, SecretBackend::EncryptedFile).unwrap();
+        assert_eq!(loaded.access_token, "syt_access");
+        assert_eq!(loaded.refresh_token.as_deref(), Some("syr_refresh"));
+        assert_eq!(loaded.store_passphrase.as_deref(), Some("store-pass"));
+
+        let _ = std::fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let (store, base) = temp_store("wrong");
+        store.unlock("correct horse").unwrap();
+
+        let reopened = SecretStore::with_base(base.clone());
+        assert_eq!(reopened.unlock("battery staple"), Err("Wrong passphrase".to_string()));
+        assert!(!reopened.is_unlocked());
+
+        let _ = std::fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn settings_keep_each_other() {
+        let (store, base) = temp_store("settings");
+        store.set_encrypt_stores(false, true).unwrap();
+        store.set_configured_backend(SecretBackend::EncryptedFile).unwrap();
+        assert!(!store.encrypt_stores());
+        // Existing stores are never migrated while encryption is off
+        assert!(!store.encrypt_existing_stores());
+        assert_eq!(store.configured_backend(), SecretBackend::EncryptedFile);
+        let _ = std::fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn backend_defaults_to_keyring() {
+        let (store, base) = temp_store("default");
+        assert_eq!(store.configured_backend(), SecretBackend::Keyring);
+        assert!(!store.status().initialized);
+        assert!(store.encrypt_stores());
+        assert!(!store.encrypt_existing_stores());
+        let _ = std::fs::remove_dir_all(&base);
+    }
+}
@@ -0,0 +1,122 @@
+/// Appended by `/shrug`.
+pub const SHRUG: &str = "¯\\_(ツ)_/¯";
+
+/// What the user typed into the message box, once IRC-style commands are
+/// picked apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SlashCommand {
+    /// Plain message, sent as Markdown.
+    Text(String),
+    Me(String),
+    Notice(String),
+    /// Optional text followed by the shrug, sent as plain text.
+    Shrug(String),
+    Join(String),
+    /// Leave the given room, or the current one.
+    Part(Option<String>),
+    Invite(String),
+    Nick(String),
+    Topic(String),
+    Ignore(String),
+    Msg { user: String, body: String },
+}
+
+/// Parse a message box entry. Anything not starting with `/` is text, and
+/// `//` escapes a leading slash.
+pub fn parse(input: &str) -> Result<SlashCommand, String> {
+    let Some(command) = input.strip_prefix('/') else {
+        return Ok(SlashCommand::Text(input.to_string()));
+    };
+    if command.starts_with('/') {
+        return Ok(SlashCommand::Text(command.to_string()));
+    }
+
+    let (name, args) = match command.split_once(char::is_whitespace) {
+        Some((name, args)) => (name, args.trim()),
+        None => (command, ""),
+    };
+    match name.to_lowercase().as_str() {
+        "me" => required(args, "/me <action>").map(SlashCommand::Me),
+        "notice" => required(args, "/notice <message>").map(SlashCommand::Notice),
+        "shrug" => Ok(SlashCommand::Shrug(args.to_string())),
+        "join" | "j" => single(args, "/join <room>").map(SlashCommand::Join),
+        "part" | "leave" => match args {
+            "" => Ok(SlashCommand::Part(None)),
+            _ => single(args, "/part [room]").map(|room| SlashCommand::Part(Some(room))),
+        },
+        "invite" => single(args, "/invite <user>").map(SlashCommand::Invite),
+        "nick" => required(args, "/nick <name>").map(SlashCommand::Nick),
+        "topic" => required(args, "/topic <text>").map(SlashCommand::Topic),
+        "ignore" => single(args, "/ignore <user>").map(SlashCommand::Ignore),
+        "msg" | "query" => {
+            let usage = "Usage: /msg <user> <message>";
+            let (user, body) = args.split_once(char::is_whitespace).ok_or(usage)?;
+            let body = body.trim();
+            if body.is_empty() {
+                return Err(usage.to_string());
+            }
+            Ok(SlashCommand::Msg { user: user.to_string(), body: body.to_string() })
+        }
+        "" => Err("Type a command after the /, or start with // to send a slash".to_string()),
+        other => Err(format!("Unknown command /{}. Start with // to send it as text.", other)),
+    }
+}
+
+fn required(args: &str, usage: &str) -> Result<String, String> {
+    if args.is_empty() {
+        return Err(format!("Usage: {}", usage));
+    }
+    Ok(args.to_string())
+}
+
+fn single(args: &str, usage: &str) -> Result<String, String> {
+    let mut words = args.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some(word), None) => Ok(word.to_string()),
+        _ => Err(format!("Usage: {}", usage)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_and_escaped_slash() {
+        assert_eq!(parse("hello /me").unwrap(), SlashCommand::Text("hello /me".into()));
+        assert_eq!(parse("//me waves").unwrap(), SlashCommand::Text("/me waves".into()));
+    }
+
+    #[test]
+    fn message_commands() {
+        assert_eq!(parse("/me waves hello").unwrap(), SlashCommand::Me("waves hello".into()));
+        assert_eq!(parse("/NOTICE  hi ").unwrap(), SlashCommand::Notice("hi".into()));
+        assert_eq!(parse("/shrug").unwrap(), SlashCommand::Shrug(String::new()));
+        assert_eq!(parse("/shrug oh well").unwrap(), SlashCommand::Shrug("oh well".into()));
+        assert!(parse("/me").is_err());
+    }
+
+    #[test]
+    fn room_and_user_commands() {
+        assert_eq!(parse("/join #room:example.org").unwrap(), SlashCommand::Join("#room:example.org".into()));
+        assert_eq!(parse("/part").unwrap(), SlashCommand::Part(None));
+        assert_eq!(parse("/leave !abc:example.org").unwrap(), SlashCommand::Part(Some("!abc:example.org".into())));
+        assert_eq!(parse("/invite @bob:example.org").unwrap(), SlashCommand::Invite("@bob:example.org".into()));
+        assert_eq!(parse("/nick Bob Smith").unwrap(), SlashCommand::Nick("Bob Smith".into()));
+        assert_eq!(parse("/topic Weekly sync").unwrap(), SlashCommand::Topic("Weekly sync".into()));
+        assert_eq!(parse("/ignore @spam:example.org").unwrap(), SlashCommand::Ignore("@spam:example.org".into()));
+        assert_eq!(
+            parse("/msg @bob:example.org hi there").unwrap(),
+            SlashCommand::Msg { user: "@bob:example.org".into(), body: "hi there".into() }
+        );
+    }
+
+    #[test]
+    fn bad_usage_and_unknown_commands_are_errors() {
+        assert_eq!(parse("/invite").unwrap_err(), "Usage: /invite <user>");
+        assert!(parse("/invite @a:x @b:x").is_err());
+        assert!(parse("/msg @bob:example.org").is_err());
+        assert!(parse("/").is_err());
+        assert!(parse("/frobnicate now").unwrap_err().starts_with("Unknown command /frobnicate"));
+    }
+}
@@ -0,0 +1,401 @@
+use base64::Engine;
+use matrix_sdk::ruma::events::secret::request::SecretName;
+use matrix_sdk::SqliteCryptoStore;
+use matrix_sdk_crypto::store::types::{Changes, DeviceChanges, IdentityChanges, PendingChanges};
+use matrix_sdk_crypto::store::CryptoStore;
+use rand::RngCore;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Crypto store file inside an account dir. Its presence means the account
+/// already has a device whose keys we must not lose.
+const CRYPTO_DATABASE: &str = "matrix-sdk-crypto.sqlite3";
+
+/// Where the encrypted copy is built before it replaces the old store.
+const STAGING_DIR: &str = "encrypting";
+
+/// Where the unencrypted store waits until the encrypted one has opened.
+const BACKUP_DIR: &str = "unencrypted-backup";
+
+/// Written into the backup dir once every unencrypted file is in it, so a
+/// rollback knows the store files left in the account dir are new ones.
+const BACKUP_COMPLETE: &str = "complete";
+
+/// Random passphrase for an account's sqlite stores.
+pub fn generate_store_passphrase() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base64::engine::general_purpose::STANDARD_NO_PAD.encode(bytes)
+}
+
+pub fn has_store(store_dir: &Path) -> bool {
+    store_dir.join(CRYPTO_DATABASE).exists()
+}
+
+/// Copy an unencrypted account store into a staging dir, encrypted with
+/// `passphrase`. Returns the staging dir for `swap_in_encrypted_store`.
+///
+/// Only the crypto store is copied. The state, event cache and media stores
+/// hold data the server sends again, so they are rebuilt by the next sync.
+/// See `copy_crypto_store` for what the copy leaves out.
+pub async fn copy_to_encrypted_store(store_dir: &Path, passphrase: &str) -> Result<PathBuf, String> {
+    let staging = store_dir.join(STAGING_DIR);
+    let _ = std::fs::remove_dir_all(&staging);
+    std::fs::create_dir_all(&staging)
+        .map_err(|e| format!("Failed to create staging dir: {}", e))?;
+
+    if let Err(e) = copy_crypto_store(store_dir, &staging, passphrase).await {
+        let _ = std::fs::remove_dir_all(&staging);
+        return Err(e);
+    }
+    Ok(staging)
+}
+
+/// Copy the crypto store through the `CryptoStore` API, which has no way to
+/// list everything it holds. Copied:
+///
+/// - the device's Olm account, cross-signing keys and dehydrated device key
+/// - key backup version and decryption key
+/// - tracked users with their devices, identities and our Olm sessions with those devices
+/// - every room key (inbound group session)
+/// - our outgoing room key and the room settings ("only verified devices",
+///   rotation) for each room we hold a room key in
+/// - cross-signing and backup secrets waiting in the secrets inbox
+/// - the sync token
+///
+/// Lost, and recreated or re-requested when next needed:
+///
+/// - Olm sessions with devices of users we don't track; those devices start a new session
+/// - room settings for rooms we hold no room keys in
+/// - withheld-key notices, so such messages show a generic decryption error
+/// - pending outgoing key and secret requests
+/// - received room key bundles not yet imported
+async fn copy_crypto_store(from: &Path, to: &Path, passphrase: &str) -> Result<(), String> {
+    let old = SqliteCryptoStore::open(from, None)
+        .await
+        .map_err(|e| format!("Failed to open existing crypto store: {}", e))?;
+    let new = SqliteCryptoStore::open(to, Some(passphrase))
+        .await
+        .map_err(|e| format!("Failed to create encrypted crypto store: {}", e))?;
+
+    let account = old.load_account().await.map_err(read_err)?;
+    let Some(account) = account else {
+        return Err("Existing crypto store has no device keys".to_string());
+    };
+    new.save_pending_changes(PendingChanges { account: Some(account) })
+        .await
+        .map_err(|e| format!("Failed to write device keys: {}", e))?;
+
+    let tracked_users = old.load_tracked_users().await.map_err(read_err)?;
+    let mut devices = Vec::new();
+    let mut identities = Vec::new();
+    let mut sessions = Vec::new();
+    for user in &tracked_users {
+        let user_devices = old.get_user_devices(&user.user_id).await.map_err(read_err)?;
+        for device in user_devices.into_values() {
+            if let Some(key) = device.curve25519_key() {
+                if let Some(olm_sessions) = old.get_sessions(&key.to_base64()).await.map_err(read_err)? {
+                    sessions.extend(olm_sessions);
+                }
+            }
+            devices.push(device);
+        }
+        if let Some(identity) = old.get_user_identity(&user.user_id).await.map_err(read_err)? {
+            identities.push(identity);
+        }
+    }
+
+    let inbound_group_sessions = old.get_inbound_group_sessions().await.map_err(read_err)?;
+    let rooms: BTreeSet<_> = inbound_group_sessions
+        .iter()
+        .map(|session| session.room_id().to_owned())
+        .collect();
+    let mut outbound_group_sessions = Vec::new();
+    let mut room_settings = HashMap::new();
+    for room_id in rooms {
+        if let Some(session) = old.get_outbound_group_session(&room_id).await.map_err(read_err)? {
+            outbound_group_sessions.push(session);
+        }
+        if let Some(settings) = old.get_room_settings(&room_id).await.map_err(read_err)? {
+            room_settings.insert(room_id, settings);
+        }
+    }
+
+    let mut secrets = Vec::new();
+    for name in [
+        SecretName::CrossSigningMasterKey,
+        SecretName::CrossSigningSelfSigningKey,
+        SecretName::CrossSigningUserSigningKey,
+        SecretName::RecoveryKey,
+    ] {
+        secrets.extend(old.get_secrets_from_inbox(&name).await.map_err(read_err)?);
+    }
+
+    let backup = old.load_backup_keys().await.map_err(read_err)?;
+    let changes = Changes {
+        private_identity: old.load_identity().await.map_err(read_err)?,
+        backup_version: backup.backup_version,
+        backup_decryption_key: backup.decryption_key,
+        dehydrated_device_pickle_key: old.load_dehydrated_device_pickle_key().await.map_err(read_err)?,
+        sessions,
+        inbound_group_sessions,
+        outbound_group_sessions,
+        identities: IdentityChanges { new: identities, ..Default::default() },
+        devices: DeviceChanges { new: devices, ..Default::default() },
+        room_settings,
+        secrets,
+        next_batch_token: old.next_batch_token().await.map_err(read_err)?,
+        ..Default::default()
+    };
+    new.save_changes(changes)
+        .await
+        .map_err(|e| format!("Failed to write encrypted crypto store: {}", e))?;
+
+    let tracked: Vec<_> = tracked_users.iter().map(|u| (u.user_id.as_ref(), u.dirty)).collect();
+    new.save_tracked_users(&tracked)
+        .await
+        .map_err(|e| format!("Failed to write tracked users: {}", e))
+}
+
+fn read_err(e: impl std::fmt::Display) -> String {
+    format!("Failed to read crypto store: {}", e)
+}
+
+/// An encrypted store that has replaced the unencrypted one and opened with
+/// its passphrase. The unencrypted files stay in a backup dir until `commit`.
+pub struct StoreSwap {
+    store_dir: PathBuf,
+    backup: PathBuf,
+}
+
+impl StoreSwap {
+    /// Delete the unencrypted backup. Only call once the passphrase is saved.
+    pub fn commit(self) {
+        let _ = std::fs::remove_dir_all(&self.backup);
+    }
+
+    /// Put the unencrypted store back in place of the encrypted one.
+    pub fn roll_back(self) -> Result<(), String> {
+        restore_backup(&self.store_dir, &self.backup)
+    }
+}
+
+/// Move the unencrypted sqlite files in `store_dir` into a backup dir, move
+/// the encrypted crypto store out of `staging` in their place and check it
+/// opens with `passphrase`. On failure the unencrypted files are put back.
+pub async fn swap_in_encrypted_store(
+    store_dir: &Path,
+    staging: &Path,
+    passphrase: &str,
+) -> Result<StoreSwap, String> {
+    let backup = store_dir.join(BACKUP_DIR);
+    if backup.exists() {
+        return Err(format!("{} already exists", backup.display()));
+    }
+    std::fs::create_dir_all(&backup)
+        .map_err(|e| format!("Failed to create backup dir: {}", e))?;
+
+    let moved_out = move_store_files(store_dir, &backup).and_then(|()| {
+        std::fs::write(backup.join(BACKUP_COMPLETE), "")
+            .map_err(|e| format!("Failed to write backup marker: {}", e))
+    });
+    let result = match moved_out {
+        Ok(()) => match move_store_files(staging, store_dir) {
+            Ok(()) => open_encrypted(store_dir, passphrase).await,
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        if let Err(restore_error) = restore_backup(store_dir, &backup) {
+            return Err(format!("{}; restoring the unencrypted store also failed: {}", e, restore_error));
+        }
+        return Err(e);
+    }
+
+    let _ = std::fs::remove_dir_all(staging);
+    Ok(StoreSwap {
+        store_dir: store_dir.to_path_buf(),
+        backup,
+    })
+}
+
+/// Finish a swap that was interrupted by a crash. With `encrypted` the
+/// passphrase was saved, so the backup is no longer needed; otherwise the
+/// unencrypted store is put back.
+pub fn recover_interrupted_swap(store_dir: &Path, encrypted: bool) -> Result<(), String> {
+    let _ = std::fs::remove_dir_all(store_dir.join(STAGING_DIR));
+    let backup = store_dir.join(BACKUP_DIR);
+    if !backup.exists() {
+        return Ok(());
+    }
+    if encrypted {
+        std::fs::remove_dir_all(&backup)
+            .map_err(|e| format!("Failed to remove {}: {}", backup.display(), e))
+    } else {
+        restore_backup(store_dir, &backup)
+    }
+}
+
+async fn open_encrypted(store_dir: &Path, passphrase: &str) -> Result<(), String> {
+    let store = SqliteCryptoStore::open(store_dir, Some(passphrase))
+        .await
+        .map_err(|e| format!("Encrypted store does not open: {}", e))?;
+    match store.load_account().await {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err("Encrypted store has no device keys".to_string()),
+        Err(e) => Err(format!("Encrypted store does not open: {}", e)),
+    }
+}
+
+/// Move the backup back into `store_dir`. If the backup was complete, the
+/// store files in `store_dir` are new ones and are deleted first, including
+/// -wal/-shm files that must not be applied to the old databases.
+fn restore_backup(store_dir: &Path, backup: &Path) -> Result<(), String> {
+    if backup.join(BACKUP_COMPLETE).exists() {
+        for path in store_files(store_dir)? {
+            std::fs::remove_file(&path)
+                .map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+        }
+    }
+    move_store_files(backup, store_dir)?;
+    std::fs::remove_dir_all(backup)
+        .map_err(|e| format!("Failed to remove {}: {}", backup.display(), e))
+}
+
+/// Sqlite files in `dir`, including their -wal/-shm companions.
+fn store_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = std::fs::read_dir(dir)
+        .map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+    Ok(entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().contains(".sqlite3"))
+        .map(|entry| entry.path())
+        .collect())
+}
+
+fn move_store_files(from: &Path, to: &Path) -> Result<(), String> {
+    for path in store_files(from)? {
+        let Some(name) = path.file_name() else { continue };
+        std::fs::rename(&path, to.join(name))
+            .map_err(|e| format!("Failed to move {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::{device_id, room_id, user_id};
+    use matrix_sdk_crypto::olm::Account;
+    use matrix_sdk_crypto::store::types::RoomSettings;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("icq26a-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn store_passphrases_are_random() {
+        let a = generate_store_passphrase();
+        let b = generate_store_passphrase();
+        assert_eq!(a.len(), 43);
+        assert_ne!(a, b);
+    }
+
+    #[tokio::test]
+    async fn encrypting_keeps_keys_and_room_settings() {
+        let dir = temp_dir("encrypt");
+        let user = user_id!("@alice:localhost");
+        let room = room_id!("!room:localhost");
+
+        let account = Account::with_device_id(user, device_id!("ALICEDEVICE"));
+        let identity_keys = account.identity_keys();
+        let (outbound, inbound) = account.create_group_session_pair_with_defaults(room).await;
+        let session_id = inbound.session_id().to_owned();
+        {
+            let store = SqliteCryptoStore::open(&dir, None).await.unwrap();
+            store.save_pending_changes(PendingChanges { account: Some(account) }).await.unwrap();
+            store
+                .save_changes(Changes {
+                    inbound_group_sessions: vec![inbound],
+                    outbound_group_sessions: vec![outbound],
+                    room_settings: HashMap::from([(
+                        room.to_owned(),
+                        RoomSettings { only_allow_trusted_devices: true, ..Default::default() },
+                    )]),
+                    next_batch_token: Some("s42".to_string()),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            store.save_tracked_users(&[(user, false)]).await.unwrap();
+        }
+        std::fs::write(dir.join("matrix-sdk-state.sqlite3"), "state").unwrap();
+
+        let passphrase = generate_store_passphrase();
+        let staging = copy_to_encrypted_store(&dir, &passphrase).await.unwrap();
+        swap_in_encrypted_store(&dir, &staging, &passphrase).await.unwrap().commit();
+        assert!(!dir.join(BACKUP_DIR).exists());
+        assert!(!staging.exists());
+        // The state store is rebuilt by the next sync
+        assert!(!dir.join("matrix-sdk-state.sqlite3").exists());
+
+        let store = SqliteCryptoStore::open(&dir, Some(&passphrase)).await.unwrap();
+        let account = store.load_account().await.unwrap().unwrap();
+        assert_eq!(account.identity_keys(), identity_keys);
+        assert!(store.get_inbound_group_session(room, &session_id).await.unwrap().is_some());
+        let outbound = store.get_outbound_group_session(room).await.unwrap().unwrap();
+        assert_eq!(outbound.session_id(), session_id);
+        assert!(store.get_room_settings(room).await.unwrap().unwrap().only_allow_trusted_devices);
+        assert_eq!(store.next_batch_token().await.unwrap().as_deref(), Some("s42"));
+        assert!(store.load_tracked_users().await.unwrap().iter().any(|u| u.user_id == user));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn failed_swap_puts_unencrypted_store_back() {
+        let dir = temp_dir("swap");
+        let staging = dir.join(STAGING_DIR);
+        std::fs::create_dir_all(&staging).unwrap();
+        std::fs::write(dir.join("session.json"), "{}").unwrap();
+        std::fs::write(dir.join("matrix-sdk-state.sqlite3"), "old state").unwrap();
+        std::fs::write(dir.join("matrix-sdk-crypto.sqlite3"), "old crypto").unwrap();
+        std::fs::write(dir.join("matrix-sdk-crypto.sqlite3-wal"), "old wal").unwrap();
+        // Not a database, so it won't open
+        std::fs::write(staging.join("matrix-sdk-crypto.sqlite3"), "new").unwrap();
+
+        assert!(swap_in_encrypted_store(&dir, &staging, "passphrase").await.is_err());
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("session.json"), "{}");
+        assert_eq!(read("matrix-sdk-state.sqlite3"), "old state");
+        assert_eq!(read("matrix-sdk-crypto.sqlite3"), "old crypto");
+        assert_eq!(read("matrix-sdk-crypto.sqlite3-wal"), "old wal");
+        assert!(!dir.join(BACKUP_DIR).exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn interrupted_swap_is_rolled_back_without_passphrase() {
+        let dir = temp_dir("interrupted");
+        let backup = dir.join(BACKUP_DIR);
+        std::fs::create_dir_all(&backup).unwrap();
+        std::fs::write(backup.join("matrix-sdk-crypto.sqlite3"), "old").unwrap();
+        std::fs::write(backup.join(BACKUP_COMPLETE), "").unwrap();
+        std::fs::write(dir.join("matrix-sdk-crypto.sqlite3"), "new").unwrap();
+        std::fs::write(dir.join("matrix-sdk-crypto.sqlite3-shm"), "new").unwrap();
+
+        recover_interrupted_swap(&dir, false).unwrap();
+
+        assert_eq!(std::fs::read_to_string(dir.join("matrix-sdk-crypto.sqlite3")).unwrap(), "old");
+        assert!(!dir.join("matrix-sdk-crypto.sqlite3-shm").exists());
+        assert!(!backup.exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
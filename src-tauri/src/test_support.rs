@@ -0,0 +1,70 @@
+//! Mock homeserver helpers shared by tests that go through the SDK.
+
+use matrix_sdk::authentication::matrix::MatrixSession;
+use matrix_sdk::config::RequestConfig;
+use matrix_sdk::ruma::{owned_device_id, owned_user_id};
+use matrix_sdk::{Client, SessionMeta, SessionTokens};
+use wiremock::matchers::{method, path, path_regex};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+pub const USER_ID: &str = "@alice:localhost";
+
+/// A mock homeserver that answers `/versions`; tests mount the rest.
+pub async fn mock_homeserver() -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/versions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "versions": ["v1.13", "v1.14", "v1.15"]
+        })))
+        .mount(&server)
+        .await;
+    server
+}
+
+/// An in-memory client signed in as `USER_ID` on `server`, without syncing.
+/// Failed requests aren't retried, so errors show up straight away.
+pub async fn logged_in_client(server: &MockServer) -> Client {
+    let client = Client::builder()
+        .homeserver_url(server.uri())
+        .request_config(RequestConfig::new().disable_retry())
+        .build()
+        .await
+        .unwrap();
+    client
+        .restore_session(MatrixSession {
+            meta: SessionMeta {
+                user_id: owned_user_id!("@alice:localhost"),
+                device_id: owned_device_id!("ALICEDEVICE"),
+            },
+            tokens: SessionTokens {
+                access_token: "syt_token".to_string(),
+                refresh_token: None,
+            },
+        })
+        .await
+        .unwrap();
+    client
+}
+
+/// Serve OAuth 2.0 server metadata for `server` and return its issuer.
+/// Metadata URLs must be https, so clients need `insecure_rewrite_https_to_http`.
+pub async fn mount_oauth_metadata(server: &MockServer) -> String {
+    let issuer = server.uri().replace("http://", "https://");
+    Mock::given(method("GET"))
+        .and(path_regex("/auth_metadata$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "issuer": format!("{}/", issuer),
+            "authorization_endpoint": format!("{}/oauth2/authorize", issuer),
+            "token_endpoint": format!("{}/oauth2/token", issuer),
+            "registration_endpoint": format!("{}/oauth2/registration", issuer),
+            "revocation_endpoint": format!("{}/oauth2/revoke", issuer),
+            "response_types_supported": ["code"],
+            "response_modes_supported": ["query", "fragment"],
+            "grant_types_supported": ["authorization_code", "refresh_token"],
+            "code_challenge_methods_supported": ["S256"]
+        })))
+        .mount(server)
+        .await;
+    issuer
+}
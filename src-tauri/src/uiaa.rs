@@ -0,0 +1,398 @@
+use crate::matrix_client::ServerError;
+use matrix_sdk::ruma::api::client::uiaa::{self as ruma_uiaa, AuthData, AuthType, Password, UserIdentifier};
+use matrix_sdk::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::future::IntoFuture;
+
+pub const DUMMY: &str = "m.login.dummy";
+pub const TERMS: &str = "m.login.terms";
+pub const REGISTRATION_TOKEN: &str = "m.login.registration_token";
+/// Name used by servers that implemented MSC3231 before it was merged.
+pub const UNSTABLE_REGISTRATION_TOKEN: &str = "org.matrix.msc3231.login.registration_token";
+pub const EMAIL_IDENTITY: &str = "m.login.email.identity";
+
+/// Error returned when an action needs the account password; the UI asks for
+/// it and calls again.
+pub const PASSWORD_REQUIRED: &str = "Password required";
+
+/// Body of a 401 user-interactive authentication response.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UiaaInfo {
+    #[serde(default)]
+    pub flows: Vec<UiaaFlow>,
+    #[serde(default)]
+    pub completed: Vec<String>,
+    #[serde(default)]
+    pub params: serde_json::Map<String, Value>,
+    pub session: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UiaaFlow {
+    #[serde(default)]
+    pub stages: Vec<String>,
+}
+
+/// A policy the user has to accept in the `m.login.terms` stage.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TermsPolicy {
+    pub id: String,
+    pub name: String,
+    pub url: String,
+    pub version: String,
+}
+
+impl UiaaInfo {
+    /// Parse a response body, or `None` if it isn't a UIAA challenge.
+    pub fn parse(body: &Value) -> Option<Self> {
+        body.get("flows")?;
+        serde_json::from_value(body.clone()).ok()
+    }
+
+    /// The flow to follow: of the flows that contain every completed stage,
+    /// the one with the fewest stages outside `native` (those need the
+    /// browser fallback), then the shortest.
+    pub fn choose_flow(&self, native: &[&str]) -> Option<&[String]> {
+        self.flows
+            .iter()
+            .filter(|flow| self.completed.iter().all(|done| flow.stages.contains(done)))
+            .min_by_key(|flow| {
+                let fallback = flow.stages.iter().filter(|s| !native.contains(&s.as_str())).count();
+                (fallback, flow.stages.len())
+            })
+            .map(|flow| flow.stages.as_slice())
+    }
+
+    /// First stage of the chosen flow that hasn't been completed yet.
+    pub fn next_stage(&self, native: &[&str]) -> Option<String> {
+        self.choose_flow(native)?
+            .iter()
+            .find(|stage| !self.completed.contains(stage))
+            .cloned()
+    }
+
+    /// Stages of the chosen flow still to do, including the next one.
+    pub fn remaining_stages(&self, native: &[&str]) -> Vec<String> {
+        self.choose_flow(native)
+            .unwrap_or_default()
+            .iter()
+            .filter(|stage| !self.completed.contains(stage))
+            .cloned()
+            .collect()
+    }
+
+    /// Policies listed in the `m.login.terms` params, in the user's language
+    /// where offered and English otherwise.
+    pub fn terms_policies(&self, lang: &str) -> Vec<TermsPolicy> {
+        let Some(policies) = self.params.get(TERMS).and_then(|p| p["policies"].as_object()) else {
+            return Vec::new();
+        };
+        let mut result: Vec<TermsPolicy> = policies
+            .iter()
+            .filter_map(|(id, policy)| {
+                let version = policy["version"].as_str().unwrap_or_default().to_string();
+                let localized = policy
+                    .get(lang)
+                    .or_else(|| policy.get("en"))
+                    .or_else(|| policy.as_object()?.values().find(|v| v.is_object()))?;
+                Some(TermsPolicy {
+                    id: id.clone(),
+                    name: localized["name"].as_str().unwrap_or(id).to_string(),
+                    url: localized["url"].as_str()?.to_string(),
+                    version,
+                })
+            })
+            .collect();
+        result.sort_by(|a, b| a.id.cmp(&b.id));
+        result
+    }
+
+    /// Web page the homeserver serves to complete `stage` in a browser.
+    pub fn fallback_url(&self, homeserver: &str, stage: &str) -> Option<String> {
+        let session = self.session.as_deref()?;
+        let mut url = reqwest::Url::parse(homeserver).ok()?;
+        url.path_segments_mut()
+            .ok()?
+            .pop_if_empty()
+            .extend(["_matrix", "client", "v3", "auth", stage, "fallback", "web"]);
+        url.query_pairs_mut().append_pair("session", session);
+        Some(url.to_string())
+    }
+
+    /// `auth` dict for a stage, merging in the stage's own fields.
+    pub fn auth(&self, stage: &str, fields: Value) -> Value {
+        let mut auth = serde_json::json!({ "type": stage });
+        if let Some(session) = &self.session {
+            auth["session"] = Value::String(session.clone());
+        }
+        if let (Some(auth), Value::Object(fields)) = (auth.as_object_mut(), fields) {
+            auth.extend(fields);
+        }
+        auth
+    }
+
+    /// `auth` dict sent after a stage was completed in the fallback page.
+    pub fn fallback_auth(&self) -> Value {
+        serde_json::json!({ "session": self.session })
+    }
+}
+
+/// Errors from SDK calls that the server can answer with a UIAA challenge.
+pub trait UiaaError {
+    fn uiaa_response(&self) -> Option<&ruma_uiaa::UiaaInfo>;
+    fn to_server_error(&self) -> ServerError;
+}
+
+impl UiaaError for matrix_sdk::HttpError {
+    fn uiaa_response(&self) -> Option<&ruma_uiaa::UiaaInfo> {
+        self.as_uiaa_response()
+    }
+
+    fn to_server_error(&self) -> ServerError {
+        ServerError::from_http(self)
+    }
+}
+
+impl UiaaError for matrix_sdk::Error {
+    fn uiaa_response(&self) -> Option<&ruma_uiaa::UiaaInfo> {
+        self.as_uiaa_response()
+    }
+
+    fn to_server_error(&self) -> ServerError {
+        match self {
+            matrix_sdk::Error::Http(e) => ServerError::from_http(e),
+            e => e.to_string().into(),
+        }
+    }
+}
+
+/// Run an SDK request that the server may guard with user-interactive auth.
+/// It goes out without auth first; if the server then asks for
+/// `m.login.password`, it is sent again with `password`, or fails with
+/// `PASSWORD_REQUIRED` when there is none yet.
+pub async fn with_password<T, E, F, Fut>(client: &Client, password: Option<&str>, request: F) -> Result<T, ServerError>
+where
+    E: UiaaError,
+    F: Fn(Option<AuthData>) -> Fut,
+    Fut: IntoFuture<Output = Result<T, E>>,
+{
+    let info = match request(None).await {
+        Ok(value) => return Ok(value),
+        Err(e) => match e.uiaa_response() {
+            Some(info) => info.clone(),
+            None => return Err(e.to_server_error()),
+        },
+    };
+    if !offers_password(&info) {
+        return Err("The server wants a confirmation the app can't do (no password step offered)".to_string().into());
+    }
+    let Some(password) = password else {
+        return Err(PASSWORD_REQUIRED.to_string().into());
+    };
+
+    let user_id = client.user_id().ok_or("Not logged in".to_string())?;
+    let mut auth = Password::new(UserIdentifier::UserIdOrLocalpart(user_id.to_string()), password.to_string());
+    auth.session = info.session;
+    request(Some(AuthData::Password(auth))).await.map_err(|e| {
+        let mut error = e.to_server_error();
+        if e.uiaa_response().is_some_and(|info| info.auth_error.is_none()) {
+            error.message = "The server asked for more than a password".to_string();
+        }
+        error
+    })
+}
+
+/// Whether one of the challenge's flows is a password alone.
+fn offers_password(info: &ruma_uiaa::UiaaInfo) -> bool {
+    info.flows.iter().any(|flow| flow.stages == [AuthType::Password])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const NATIVE: &[&str] = &[DUMMY, TERMS, REGISTRATION_TOKEN, EMAIL_IDENTITY];
+
+    fn challenge(flows: Value, completed: Value) -> UiaaInfo {
+        UiaaInfo::parse(&json!({
+            "flows": flows,
+            "completed": completed,
+            "session": "sess1",
+            "params": {
+                "m.login.terms": {
+                    "policies": {
+                        "privacy_policy": {
+                            "version": "1.2",
+                            "en": { "name": "Privacy Policy", "url": "https://example.org/privacy" },
+                            "de": { "name": "Datenschutz", "url": "https://example.org/de/privacy" }
+                        }
+                    }
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parse_rejects_non_uiaa_errors() {
+        assert!(UiaaInfo::parse(&json!({ "errcode": "M_FORBIDDEN", "error": "nope" })).is_none());
+    }
+
+    #[test]
+    fn prefers_flows_without_fallback_stages() {
+        let info = challenge(
+            json!([
+                { "stages": ["m.login.recaptcha", "m.login.terms"] },
+                { "stages": ["m.login.email.identity", "m.login.terms", "m.login.dummy"] }
+            ]),
+            json!([]),
+        );
+        assert_eq!(info.next_stage(NATIVE).as_deref(), Some(EMAIL_IDENTITY));
+    }
+
+    #[test]
+    fn follows_flow_matching_completed_stages() {
+        let info = challenge(
+            json!([
+                { "stages": ["m.login.dummy"] },
+                { "stages": ["m.login.recaptcha", "m.login.terms"] }
+            ]),
+            json!(["m.login.recaptcha"]),
+        );
+        assert_eq!(info.next_stage(NATIVE).as_deref(), Some(TERMS));
+        assert_eq!(info.remaining_stages(NATIVE), vec![TERMS.to_string()]);
+    }
+
+    #[test]
+    fn reads_terms_in_requested_language() {
+        let info = challenge(json!([{ "stages": ["m.login.terms"] }]), json!([]));
+        let german = info.terms_policies("de");
+        assert_eq!(german[0].name, "Datenschutz");
+        let fallback = info.terms_policies("fr");
+        assert_eq!(fallback, vec![TermsPolicy {
+            id: "privacy_policy".into(),
+            name: "Privacy Policy".into(),
+            url: "https://example.org/privacy".into(),
+            version: "1.2".into(),
+        }]);
+    }
+
+    #[test]
+    fn password_only_flow_is_detected() {
+        let password = ruma_uiaa::UiaaInfo::new(vec![
+            ruma_uiaa::AuthFlow::new(vec![AuthType::Sso]),
+            ruma_uiaa::AuthFlow::new(vec![AuthType::Password]),
+        ]);
+        assert!(offers_password(&password));
+        let two_step = ruma_uiaa::UiaaInfo::new(vec![ruma_uiaa::AuthFlow::new(vec![
+            AuthType::Password,
+            AuthType::EmailIdentity,
+        ])]);
+        assert!(!offers_password(&two_step));
+    }
+
+    #[test]
+    fn builds_auth_and_fallback_url() {
+        let info = challenge(json!([{ "stages": ["m.login.recaptcha"] }]), json!([]));
+        assert_eq!(
+            info.auth(REGISTRATION_TOKEN, json!({ "token": "abc" })),
+            json!({ "type": REGISTRATION_TOKEN, "session": "sess1", "token": "abc" })
+        );
+        assert_eq!(
+            info.fallback_url("https://matrix.example.org/", "m.login.recaptcha").as_deref(),
+            Some("https://matrix.example.org/_matrix/client/v3/auth/m.login.recaptcha/fallback/web?session=sess1")
+        );
+    }
+
+    // ── with_password against a mock homeserver ──────────────
+
+    use crate::test_support::{logged_in_client, mock_homeserver};
+    use wiremock::matchers::{body_string_contains, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const CHANGE_PASSWORD: &str = "/_matrix/client/v3/account/password";
+
+    async fn change_password(server: &MockServer, password: Option<&str>) -> Result<(), ServerError> {
+        use matrix_sdk::ruma::api::client::account::change_password;
+
+        let client = logged_in_client(server).await;
+        with_password(&client, password, |auth| {
+            let mut request = change_password::v3::Request::new("new password".to_string());
+            request.auth = auth;
+            client.send(request)
+        })
+        .await
+        .map(|_| ())
+    }
+
+    async fn mount_password_challenge(server: &MockServer) {
+        Mock::given(method("POST"))
+            .and(path(CHANGE_PASSWORD))
+            .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+                "flows": [{ "stages": ["m.login.password"] }],
+                "params": {},
+                "session": "sess1"
+            })))
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn wrong_password_keeps_the_errcode() {
+        let server = mock_homeserver().await;
+        Mock::given(method("POST"))
+            .and(path(CHANGE_PASSWORD))
+            .and(body_string_contains("m.login.password"))
+            .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+                "errcode": "M_FORBIDDEN",
+                "error": "Invalid password",
+                "flows": [{ "stages": ["m.login.password"] }],
+                "params": {},
+                "session": "sess1"
+            })))
+            .with_priority(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        mount_password_challenge(&server).await;
+
+        let error = change_password(&server, Some("wrong")).await.unwrap_err();
+        assert_eq!(error, ServerError {
+            errcode: Some("M_FORBIDDEN".into()),
+            message: "Invalid password".into(),
+            status: Some(401),
+        });
+        assert_eq!(error.to_string(), "Invalid password (M_FORBIDDEN)");
+    }
+
+    #[tokio::test]
+    async fn missing_password_is_asked_for() {
+        let server = mock_homeserver().await;
+        mount_password_challenge(&server).await;
+
+        let error = change_password(&server, None).await.unwrap_err();
+        assert_eq!(error.message, PASSWORD_REQUIRED);
+        assert_eq!(error.errcode, None);
+    }
+
+    #[tokio::test]
+    async fn plain_server_errors_keep_errcode_and_status() {
+        let server = mock_homeserver().await;
+        Mock::given(method("POST"))
+            .and(path(CHANGE_PASSWORD))
+            .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+                "errcode": "M_WEAK_PASSWORD",
+                "error": "Password too short"
+            })))
+            .mount(&server)
+            .await;
+
+        let error = change_password(&server, Some("secret")).await.unwrap_err();
+        assert_eq!(error.errcode.as_deref(), Some("M_WEAK_PASSWORD"));
+        assert_eq!(error.status, Some(400));
+        assert_eq!(error.message, "Password too short");
+    }
+}
@@ -1,158 +1,330 @@
 import { invoke } from '@tauri-apps/api/core'
-import type { Buddy, Room, Space, Message, MessagesPage, LoginCredentials, LogEntry, UserProfile, RoomProfile, InviteInfo, RoomTagMap, PublicSpace, SpaceChild } from './types'
+import type { Buddy, Room, Space, Message, MessagesPage, LoginCredentials, LogEntry, UserProfile, RoomProfile, InviteInfo, RoomTagMap, PublicSpace, SpaceChild, SecretBackend, SecretStorageStatus, StoreInfo, RegistrationStatus, RegistrationResponse, ServerCapabilities, DeviceInfo, ServerError, RestoredAccount, RecoveryStatus, SendOutcome, ThreadsPage } from './types'
 
 export async function matrixLogin(credentials: LoginCredentials): Promise<string> {
   return invoke('matrix_login', { credentials })
 }
 
-export async function matrixRegister(credentials: LoginCredentials): Promise<string> {
+export async function matrixLoginSso(homeserver: string, idpId?: string): Promise<string> {
+  return invoke('matrix_login_sso', { homeserver, idpId: idpId ?? null })
+}
+
+export async function matrixLoginOauth(homeserver: string): Promise<string> {
+  return invoke('matrix_login_oauth', { homeserver })
+}
+
+export async function matrixRegister(credentials: LoginCredentials): Promise<RegistrationStatus> {
   return invoke('matrix_register', { credentials })
 }
 
-export async function matrixLogout(): Promise<void> {
-  return invoke('matrix_logout')
+export async function matrixRegisterStage(response: RegistrationResponse): Promise<RegistrationStatus> {
+  return invoke('matrix_register_stage', { response })
+}
+
+export async function matrixRegisterCancel(): Promise<void> {
+  return invoke('matrix_register_cancel')
+}
+
+export async function discoverServer(homeserver: string | null, account?: string): Promise<ServerCapabilities> {
+  return invoke('discover_server', { homeserver, account: account ?? null })
+}
+
+export async function matrixLogout(account: string): Promise<void> {
+  return invoke('matrix_logout', { account })
 }
 
-export async function matrixDisconnect(): Promise<void> {
-  return invoke('matrix_disconnect')
+export const SESSION_EXPIRED = 'Session expired'
+
+export async function matrixReauthenticate(account: string, password: string): Promise<string> {
+  return invoke('matrix_reauthenticate', { account, password })
 }
 
-export async function getBuddyList(): Promise<Buddy[]> {
-  return invoke('get_buddy_list')
+export async function matrixDisconnect(account: string): Promise<void> {
+  return invoke('matrix_disconnect', { account })
 }
 
-export async function getRoomMembers(roomId: string): Promise<Buddy[]> {
-  return invoke('get_room_members', { roomId })
+export async function getBuddyList(account: string): Promise<Buddy[]> {
+  return invoke('get_buddy_list', { account })
 }
 
-export async function getRooms(): Promise<Room[]> {
-  return invoke('get_rooms')
+export async function getRoomMembers(account: string, roomId: string): Promise<Buddy[]> {
+  return invoke('get_room_members', { account, roomId })
 }
 
-export async function getSpaces(): Promise<Space[]> {
-  return invoke('get_spaces')
+export async function getRooms(account: string): Promise<Room[]> {
+  return invoke('get_rooms', { account })
 }
 
-export async function getRoomMessages(roomId: string, limit: number = 50, from?: string): Promise<MessagesPage> {
-  return invoke('get_room_messages', { roomId, limit, from: from ?? null })
+export async function getSpaces(account: string): Promise<Space[]> {
+  return invoke('get_spaces', { account })
 }
 
-export async function sendMessage(roomId: string, body: string, inReplyToEventId?: string): Promise<void> {
-  return invoke('send_message', { roomId, body, inReplyToEventId: inReplyToEventId ?? null })
+export async function getRoomMessages(account: string, roomId: string, limit: number = 50, from?: string): Promise<MessagesPage> {
+  return invoke('get_room_messages', { account, roomId, limit, from: from ?? null })
 }
 
-export async function sendTyping(roomId: string, typing: boolean): Promise<void> {
-  return invoke('send_typing', { roomId, typing })
+export async function getRoomThreads(account: string, roomId: string, limit: number = 20, from?: string): Promise<ThreadsPage> {
+  return invoke('get_room_threads', { account, roomId, limit, from: from ?? null })
 }
 
-export async function markAsRead(roomId: string, eventId: string): Promise<void> {
-  return invoke('mark_as_read', { roomId, eventId })
+// Replies in a thread, without the root
+export async function getThreadMessages(account: string, roomId: string, threadRoot: string, limit: number = 50, from?: string): Promise<MessagesPage> {
+  return invoke('get_thread_messages', { account, roomId, threadRoot, limit, from: from ?? null })
 }
 
-export async function setPresence(status: string): Promise<void> {
-  return invoke('set_presence', { status })
+// Slash commands like /me, /join or /msg are interpreted by the backend
+export async function sendMessage(account: string, roomId: string, body: string, inReplyToEventId?: string, threadRoot?: string): Promise<SendOutcome> {
+  return invoke('send_message', { account, roomId, body, inReplyToEventId: inReplyToEventId ?? null, threadRoot: threadRoot ?? null })
 }
 
-export async function tryRestoreSession(): Promise<string> {
+export async function sendTyping(account: string, roomId: string, typing: boolean): Promise<void> {
+  return invoke('send_typing', { account, roomId, typing })
+}
+
+export async function markAsRead(account: string, roomId: string, eventId: string): Promise<void> {
+  return invoke('mark_as_read', { account, roomId, eventId })
+}
+
+export async function setPresence(account: string, status: string): Promise<void> {
+  return invoke('set_presence', { account, status })
+}
+
+// Returned by restore when the server has signed out every saved session
+export const SESSION_REJECTED = 'Session rejected'
+
+export async function tryRestoreSession(): Promise<RestoredAccount[]> {
   return invoke('try_restore_session')
 }
 
-export async function acceptVerification(userId: string, flowId: string): Promise<void> {
-  return invoke('accept_verification', { userId, flowId })
+export async function getAccounts(): Promise<string[]> {
+  return invoke('get_accounts')
+}
+
+// Returned by restore and login when tokens are in the encrypted file and it hasn't been unlocked
+export const SECRET_STORE_LOCKED = 'Secret store is locked'
+
+export async function getSecretStorage(): Promise<SecretStorageStatus> {
+  return invoke('get_secret_storage')
+}
+
+export async function unlockSecretStorage(passphrase: string): Promise<SecretStorageStatus> {
+  return invoke('unlock_secret_storage', { passphrase })
+}
+
+export async function setSecretStorage(backend: SecretBackend): Promise<SecretStorageStatus> {
+  return invoke('set_secret_storage', { backend })
+}
+
+export async function listStores(): Promise<StoreInfo[]> {
+  return invoke('list_stores')
+}
+
+export async function deleteStore(id: string): Promise<StoreInfo[]> {
+  return invoke('delete_store', { id })
+}
+
+export async function setStoreEncryption(enabled: boolean, existing: boolean): Promise<SecretStorageStatus> {
+  return invoke('set_store_encryption', { enabled, existing })
+}
+
+export async function acceptVerification(account: string, userId: string, flowId: string): Promise<void> {
+  return invoke('accept_verification', { account, userId, flowId })
+}
+
+export async function confirmVerification(account: string, userId: string, flowId: string): Promise<void> {
+  return invoke('confirm_verification', { account, userId, flowId })
+}
+
+export async function cancelVerification(account: string, userId: string, flowId: string): Promise<void> {
+  return invoke('cancel_verification', { account, userId, flowId })
+}
+
+export async function startSasVerification(account: string, userId: string, flowId: string): Promise<void> {
+  return invoke('start_sas_verification', { account, userId, flowId })
+}
+
+// Pass either a pasted code (image data URL, or the payload as base64 or raw) or an image file path
+export async function scanVerificationQr(account: string, userId: string, flowId: string, scan: { code?: string; imagePath?: string }): Promise<void> {
+  return invoke('scan_verification_qr', { account, userId, flowId, code: scan.code ?? null, imagePath: scan.imagePath ?? null })
 }
 
-export async function confirmVerification(userId: string, flowId: string): Promise<void> {
-  return invoke('confirm_verification', { userId, flowId })
+// Both return the flow ID; the main window's verification dialog takes it from there
+export async function requestUserVerification(account: string, userId: string): Promise<string> {
+  return invoke('request_user_verification', { account, userId })
 }
 
-export async function cancelVerification(userId: string, flowId: string): Promise<void> {
-  return invoke('cancel_verification', { userId, flowId })
+export async function requestDeviceVerification(account: string, deviceId: string): Promise<string> {
+  return invoke('request_device_verification', { account, deviceId })
 }
 
 export async function getServerLog(): Promise<LogEntry[]> {
   return invoke('get_server_log')
 }
 
-export async function getUserProfile(userId: string): Promise<UserProfile> {
-  return invoke('get_user_profile', { userId })
+export async function getUserProfile(account: string, userId: string): Promise<UserProfile> {
+  return invoke('get_user_profile', { account, userId })
 }
 
-export async function getRoomInfo(roomId: string): Promise<RoomProfile> {
-  return invoke('get_room_info', { roomId })
+export async function getRoomInfo(account: string, roomId: string): Promise<RoomProfile> {
+  return invoke('get_room_info', { account, roomId })
 }
 
-export async function createDmRoom(userId: string): Promise<Room> {
-  return invoke('create_dm_room', { userId })
+// DMs are encrypted unless encrypted is false
+export async function createDmRoom(account: string, userId: string, encrypted?: boolean): Promise<Room> {
+  return invoke('create_dm_room', { account, userId, encrypted: encrypted ?? null })
 }
 
-export async function searchUsers(query: string): Promise<Buddy[]> {
-  return invoke('search_users', { query })
+export async function searchUsers(account: string, query: string): Promise<Buddy[]> {
+  return invoke('search_users', { account, query })
 }
 
-export async function joinRoom(roomIdOrAlias: string): Promise<Room> {
-  return invoke('join_room', { roomIdOrAlias })
+export async function joinRoom(account: string, roomIdOrAlias: string): Promise<Room> {
+  return invoke('join_room', { account, roomIdOrAlias })
 }
 
-export async function createRoom(roomAlias: string): Promise<Room> {
-  return invoke('create_room', { roomAlias })
+// Rooms are public by default; encryption defaults to on for private rooms and off for public ones
+export async function createRoom(account: string, roomAlias: string, options: { private?: boolean; encrypted?: boolean } = {}): Promise<Room> {
+  return invoke('create_room', { account, roomAlias, private: options.private ?? null, encrypted: options.encrypted ?? null })
 }
 
-export async function leaveRoom(roomId: string): Promise<void> {
-  return invoke('leave_room', { roomId })
+// Can't be undone
+export async function enableRoomEncryption(account: string, roomId: string): Promise<void> {
+  return invoke('enable_room_encryption', { account, roomId })
 }
 
-export async function removeBuddy(userId: string): Promise<void> {
-  return invoke('remove_buddy', { userId })
+export async function leaveRoom(account: string, roomId: string): Promise<void> {
+  return invoke('leave_room', { account, roomId })
 }
 
-export async function editMessage(roomId: string, eventId: string, newBody: string): Promise<void> {
-  return invoke('edit_message', { roomId, eventId, newBody })
+export async function removeBuddy(account: string, userId: string): Promise<void> {
+  return invoke('remove_buddy', { account, userId })
 }
 
-export async function deleteMessage(roomId: string, eventId: string): Promise<void> {
-  return invoke('delete_message', { roomId, eventId })
+export async function editMessage(account: string, roomId: string, eventId: string, newBody: string): Promise<void> {
+  return invoke('edit_message', { account, roomId, eventId, newBody })
 }
 
-export async function sendReaction(roomId: string, eventId: string, reactionKey: string): Promise<void> {
-  return invoke('send_reaction', { roomId, eventId, reactionKey })
+export async function deleteMessage(account: string, roomId: string, eventId: string): Promise<void> {
+  return invoke('delete_message', { account, roomId, eventId })
 }
 
-export async function getPendingInvites(): Promise<InviteInfo[]> {
-  return invoke('get_pending_invites')
+export async function sendReaction(account: string, roomId: string, eventId: string, reactionKey: string): Promise<void> {
+  return invoke('send_reaction', { account, roomId, eventId, reactionKey })
 }
 
-export async function acceptInvite(roomId: string): Promise<Room> {
-  return invoke('accept_invite', { roomId })
+export async function getPendingInvites(account: string): Promise<InviteInfo[]> {
+  return invoke('get_pending_invites', { account })
 }
 
-export async function rejectInvite(roomId: string): Promise<void> {
-  return invoke('reject_invite', { roomId })
+export async function acceptInvite(account: string, roomId: string): Promise<Room> {
+  return invoke('accept_invite', { account, roomId })
+}
+
+export async function rejectInvite(account: string, roomId: string): Promise<void> {
+  return invoke('reject_invite', { account, roomId })
 }
 
 export async function setDockBadge(count: number): Promise<void> {
   return invoke('set_dock_badge', { count })
 }
 
-export async function fetchMedia(mxcUrl: string): Promise<string> {
-  return invoke('fetch_media', { mxcUrl })
+// Encrypted media needs the message it came from; the backend decrypts it
+export async function fetchMedia(account: string, mxcUrl: string, roomId?: string, eventId?: string): Promise<string> {
+  return invoke('fetch_media', { account, mxcUrl, roomId, eventId })
+}
+
+export async function getRoomTags(account: string): Promise<RoomTagMap> {
+  return invoke('get_room_tags', { account })
+}
+
+export async function setRoomTag(account: string, roomId: string, tag: string): Promise<void> {
+  return invoke('set_room_tag', { account, roomId, tag })
+}
+
+export async function removeRoomTag(account: string, roomId: string, tag: string): Promise<void> {
+  return invoke('remove_room_tag', { account, roomId, tag })
+}
+
+export async function searchSpaces(account: string, query: string, limit?: number, server?: string): Promise<PublicSpace[]> {
+  return invoke('search_spaces', { account, query, limit, server })
+}
+
+export async function getSpaceHierarchy(account: string, spaceId: string): Promise<SpaceChild[]> {
+  return invoke('get_space_hierarchy', { account, spaceId })
+}
+
+// Returned by commands that need the account password before they go through
+export const PASSWORD_REQUIRED = 'Password required'
+
+export async function listDevices(account: string): Promise<DeviceInfo[]> {
+  return invoke('list_devices', { account })
+}
+
+export async function renameDevice(account: string, deviceId: string, name: string): Promise<void> {
+  return invoke('rename_device', { account, deviceId, name })
+}
+
+export async function deleteDevices(account: string, deviceIds: string[], password?: string): Promise<void> {
+  return invoke('delete_devices', { account, deviceIds, password: password ?? null })
+}
+
+export async function changePassword(account: string, password: string, newPassword: string, logoutDevices: boolean): Promise<void> {
+  return invoke('change_password', { account, password, newPassword, logoutDevices })
+}
+
+export async function deactivateAccount(account: string, password: string, erase: boolean): Promise<void> {
+  return invoke('deactivate_account', { account, password, erase })
+}
+
+// Readable text for a ServerError thrown by the commands above
+export function describeServerError(e: unknown): string {
+  const error = e as ServerError
+  if (typeof error?.message !== 'string') return String(e)
+  switch (error.errcode) {
+    case 'M_FORBIDDEN':
+      return error.status === 401 ? 'Incorrect password' : error.message
+    case 'M_WEAK_PASSWORD':
+      return `Password too weak: ${error.message}`
+    case 'M_LIMIT_EXCEEDED':
+      return 'Too many attempts, try again later'
+    default:
+      return error.message
+  }
+}
+
+// True once this session holds all cross-signing keys
+export async function getCrossSigningStatus(account: string): Promise<boolean> {
+  return invoke('get_cross_signing_status', { account })
+}
+
+// Throws a ServerError; PASSWORD_REQUIRED as its message means ask and call again
+export async function bootstrapCrossSigning(account: string, password?: string): Promise<void> {
+  return invoke('bootstrap_cross_signing', { account, password })
+}
+
+export async function acceptIdentityChange(account: string, userId: string): Promise<void> {
+  return invoke('accept_identity_change', { account, userId })
 }
 
-export async function getRoomTags(): Promise<RoomTagMap> {
-  return invoke('get_room_tags')
+export async function getRecoveryStatus(account: string): Promise<RecoveryStatus> {
+  return invoke('get_recovery_status', { account })
 }
 
-export async function setRoomTag(roomId: string, tag: string): Promise<void> {
-  return invoke('set_room_tag', { roomId, tag })
+// Resolves with the new recovery key once all room keys are backed up
+export async function enableRecovery(account: string, passphrase?: string): Promise<string> {
+  return invoke('enable_recovery', { account, passphrase })
 }
 
-export async function removeRoomTag(roomId: string, tag: string): Promise<void> {
-  return invoke('remove_room_tag', { roomId, tag })
+// Accepts a recovery key or the passphrase it was set up with
+export async function recoverKeys(account: string, recoveryKey: string): Promise<void> {
+  return invoke('recover_keys', { account, recoveryKey })
 }
 
-export async function searchSpaces(query: string, limit?: number, server?: string): Promise<PublicSpace[]> {
-  return invoke('search_spaces', { query, limit, server })
+// Key files use the MEGOLM SESSION DATA format Element and other clients share; both return the key count
+export async function exportRoomKeys(account: string, filePath: string, passphrase: string): Promise<number> {
+  return invoke('export_room_keys', { account, filePath, passphrase })
 }
 
-export async function getSpaceHierarchy(spaceId: string): Promise<SpaceChild[]> {
-  return invoke('get_space_hierarchy', { spaceId })
+export async function importRoomKeys(account: string, filePath: string, passphrase: string): Promise<number> {
+  return invoke('import_room_keys', { account, filePath, passphrase })
 }
@@ -1,8 +1,26 @@
 import { writable } from 'svelte/store'
-import type { Buddy, Room, Space, PresenceStatus, AppPreferences, RoomTagMap } from './types'
+import type { Buddy, Room, Space, PresenceStatus, AppPreferences, RoomTagMap, RestoredAccount } from './types'
 
 export const isLoggedIn = writable(false)
 export const currentUserId = writable<string | null>(null)
+// Every signed-in account; currentUserId is the first one signed in
+export const accounts = writable<string[]>([])
+// Set while the login screen is shown to sign in an additional account
+export const addingAccount = writable(false)
+// Shown on the login screen after a session ended on its own
+export const loginNotice = writable<string | null>(null)
+// Accounts soft-logged-out by the server, waiting for their password
+export const expiredAccounts = writable<string[]>([])
+// Accounts restored while their homeserver was unreachable, until they sync
+export const offlineAccounts = writable<string[]>([])
+// Record restored accounts; returns the ones ready to start syncing
+export function setRestoredAccounts(restored: RestoredAccount[]): string[] {
+  accounts.set(restored.map(a => a.user_id))
+  expiredAccounts.set(restored.filter(a => a.status === 'expired').map(a => a.user_id))
+  offlineAccounts.set(restored.filter(a => a.status === 'offline').map(a => a.user_id))
+  return restored.filter(a => a.status !== 'expired').map(a => a.user_id)
+}
+
 export const buddyList = writable<Buddy[]>([])
 export const rooms = writable<Room[]>([])
 export const spaces = writable<Space[]>([])
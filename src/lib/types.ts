@@ -1,4 +1,5 @@
 export interface Buddy {
+  account: string
   user_id: string
   display_name: string
   avatar_url: string | null
@@ -17,54 +18,80 @@ export type PresenceStatus =
   | 'unknown'
 
 export interface Room {
+  account: string
   room_id: string
   name: string
   is_direct: boolean
+  is_encrypted: boolean
   last_message: string | null
   unread_count: number
 }
 
+// What send_message did; slash commands can open or leave a room
+export type SendOutcome =
+  | { status: 'sent' }
+  | { status: 'open_room'; room: Room }
+  | { status: 'left'; room_id: string }
+
 export interface Space {
+  account: string
   room_id: string
   name: string
   child_room_ids: string[]
 }
 
 export interface Message {
+  account: string
   room_id: string
   event_id: string
   sender: string
   sender_name: string
   body: string
+  // Sanitized by the backend, so it can go straight into {@html}
+  formatted_body: string | null
   timestamp: number
-  msg_type: 'text' | 'image' | 'file' | 'audio' | 'video' | 'unknown'
+  msg_type: 'text' | 'image' | 'file' | 'audio' | 'video' | 'undecryptable' | 'unknown'
   media_url?: string | null
+  media_encrypted: boolean
   filename?: string | null
   in_reply_to?: string | null
   reply_sender_name?: string | null
   reply_body?: string | null
+  reply_msg_type?: string | null
+  // Thumbnail of a replied-to media message; encrypted ones are fetched via in_reply_to
+  reply_thumbnail?: string | null
+  reply_media_encrypted?: boolean
+  // Root event of the thread this message belongs to
+  thread_root?: string | null
+  // Megolm session an undecryptable message is waiting on
+  session_id?: string | null
 }
 
 export interface TypingEvent {
+  account: string
   room_id: string
   user_ids: string[]
   display_names: string[]
 }
 
 export interface MessageEditEvent {
+  account: string
   room_id: string
   original_event_id: string
   new_body: string
+  new_formatted_body: string | null
   sender: string
   sender_name: string
 }
 
 export interface MessageDeletedEvent {
+  account: string
   room_id: string
   event_id: string
 }
 
 export interface ReactionEvent {
+  account: string
   room_id: string
   event_id: string
   reaction_key: string
@@ -74,17 +101,38 @@ export interface ReactionEvent {
 }
 
 export interface InviteInfo {
+  account: string
   room_id: string
   room_name: string | null
   inviter: string | null
   inviter_name: string | null
 }
 
+export interface SyncStatusEvent {
+  account: string
+  status: 'syncing' | 'synced'
+}
+
+export interface SessionEvent {
+  account: string
+}
+
 export interface MessagesPage {
   messages: Message[]
   end_token: string | null
 }
 
+export interface RoomThread {
+  root: Message
+  reply_count: number
+  latest_reply: Message | null
+}
+
+export interface ThreadsPage {
+  threads: RoomThread[]
+  end_token: string | null
+}
+
 export interface SharedRoom {
   room_id: string
   name: string
@@ -97,6 +145,22 @@ export interface UserProfile {
   presence: string
   last_seen_ago: number | null
   shared_rooms: SharedRoom[]
+  verified: boolean
+  // Master key replaced since we first saw it, not yet accepted
+  identity_changed: boolean
+  devices: UserDevice[]
+}
+
+export interface UserDevice {
+  device_id: string
+  display_name: string | null
+  verified: boolean
+}
+
+export interface IdentityChangedEvent {
+  account: string
+  user_id: string
+  changed: boolean
 }
 
 export interface RoomProfile {
@@ -104,6 +168,7 @@ export interface RoomProfile {
   name: string
   topic: string | null
   is_direct: boolean
+  is_encrypted: boolean
   member_count: number
 }
 
@@ -115,6 +180,111 @@ export interface LoginCredentials {
   password: string
 }
 
+export type SecretBackend = 'keyring' | 'encrypted_file'
+
+export interface SecretStorageStatus {
+  backend: SecretBackend
+  initialized: boolean
+  unlocked: boolean
+  encrypt_stores: boolean
+  encrypt_existing_stores: boolean
+}
+
+export interface TermsPolicy {
+  id: string
+  name: string
+  url: string
+  version: string
+}
+
+export interface IdentityProviderInfo {
+  id: string
+  name: string
+  brand: string | null
+}
+
+export interface ServerCapabilities {
+  homeserver_url: string
+  versions: string[]
+  unstable_features: string[]
+  login_flows: string[]
+  identity_providers: IdentityProviderInfo[]
+  oauth: boolean
+  registration_flows: string[][] | null
+  change_password: boolean | null
+  set_displayname: boolean | null
+  set_avatar_url: boolean | null
+  default_room_version: string | null
+  max_upload_size: number | null
+}
+
+export interface RegistrationStage {
+  stage: string
+  completed: string[]
+  remaining: string[]
+  policies: TermsPolicy[]
+  email_sent_to: string | null
+  fallback_url: string | null
+  error: string | null
+}
+
+export type RegistrationStatus =
+  | { status: 'complete'; user_id: string }
+  | ({ status: 'stage' } & RegistrationStage)
+
+export type RegistrationResponse =
+  | { type: 'accept_terms' }
+  | { type: 'registration_token'; token: string }
+  | { type: 'request_email'; email: string }
+  | { type: 'email_confirmed' }
+  | { type: 'fallback_done' }
+
+export interface DeviceInfo {
+  device_id: string
+  display_name: string | null
+  last_seen_ip: string | null
+  last_seen_ts: number | null
+  is_current: boolean
+  cross_signed: boolean
+}
+
+// Error from account management commands; errcode is the Matrix error code
+export interface ServerError {
+  errcode: string | null
+  message: string
+  status: number | null
+}
+
+export interface RecoveryStatus {
+  state: 'enabled' | 'disabled' | 'incomplete' | 'unknown'
+  backup_enabled: boolean
+  backup_exists_on_server: boolean
+}
+
+export interface BackupProgressEvent {
+  account: string
+  stage: 'creating_backup' | 'creating_recovery_key' | 'backing_up' | 'upload_error' | 'recovering' | 'downloading' | 'exporting' | 'importing' | 'retrying' | 'done'
+  done: number | null
+  total: number | null
+}
+
+export type RestoreStatus = 'online' | 'offline' | 'expired'
+
+export interface RestoredAccount {
+  user_id: string
+  status: RestoreStatus
+}
+
+export interface StoreInfo {
+  id: string
+  user_id: string | null
+  homeserver_url: string | null
+  device_id: string | null
+  size_bytes: number
+  modified: number
+  stale: boolean
+}
+
 export interface AppPreferences {
   homeserver: string
   notification_sounds: boolean
@@ -132,12 +302,23 @@ export interface VerificationEmoji {
 }
 
 export interface VerificationRequestEvent {
+  account: string
   flow_id: string
   user_id: string
   is_self_verification: boolean
 }
 
+export interface VerificationReadyEvent {
+  account: string
+  flow_id: string
+  user_id: string
+  qr_code: string | null
+  can_scan: boolean
+  can_sas: boolean
+}
+
 export interface VerificationEmojisEvent {
+  account: string
   flow_id: string
   user_id: string
   emojis: VerificationEmoji[]
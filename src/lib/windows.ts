@@ -1,4 +1,5 @@
 import { WebviewWindow } from '@tauri-apps/api/webviewWindow'
+import type { Room } from './types'
 
 interface ChildWindowOptions {
   label: string
@@ -40,15 +41,15 @@ export function openPreferencesWindow() {
     url: '/?window=preferences',
     title: 'Preferences',
     width: 380,
-    height: 220,
+    height: 440,
     parent: 'main',
   })
 }
 
-export function openDirectMessageWindow(roomId: string, roomName: string) {
+export function openDirectMessageWindow(account: string, roomId: string, roomName: string) {
   openChildWindow({
-    label: `dm-${sanitizeLabel(roomId)}`,
-    url: `/?window=dm&roomId=${encodeURIComponent(roomId)}&roomName=${encodeURIComponent(roomName)}`,
+    label: `dm-${sanitizeLabel(account)}-${sanitizeLabel(roomId)}`,
+    url: `/?window=dm&account=${encodeURIComponent(account)}&roomId=${encodeURIComponent(roomId)}&roomName=${encodeURIComponent(roomName)}`,
     title: `${roomName} - Message Session`,
     width: 440,
     height: 480,
@@ -65,40 +66,49 @@ export function openServerLogWindow() {
   })
 }
 
-export function openUserInfoWindow(userId: string, displayName: string) {
+export function openUserInfoWindow(account: string, userId: string, displayName: string) {
   openChildWindow({
-    label: `userinfo-${sanitizeLabel(userId)}`,
-    url: `/?window=userinfo&userId=${encodeURIComponent(userId)}&displayName=${encodeURIComponent(displayName)}`,
+    label: `userinfo-${sanitizeLabel(account)}-${sanitizeLabel(userId)}`,
+    url: `/?window=userinfo&account=${encodeURIComponent(account)}&userId=${encodeURIComponent(userId)}&displayName=${encodeURIComponent(displayName)}`,
     title: `User Info - ${displayName}`,
     width: 300,
     height: 340,
   })
 }
 
-export function openRoomInfoWindow(roomId: string, roomName: string) {
+export function openRoomInfoWindow(account: string, roomId: string, roomName: string) {
   openChildWindow({
-    label: `roominfo-${sanitizeLabel(roomId)}`,
-    url: `/?window=roominfo&roomId=${encodeURIComponent(roomId)}&roomName=${encodeURIComponent(roomName)}`,
+    label: `roominfo-${sanitizeLabel(account)}-${sanitizeLabel(roomId)}`,
+    url: `/?window=roominfo&account=${encodeURIComponent(account)}&roomId=${encodeURIComponent(roomId)}&roomName=${encodeURIComponent(roomName)}`,
     title: `Room Info - ${roomName}`,
     width: 300,
     height: 340,
   })
 }
 
-export function openChatRoomWindow(roomId: string, roomName: string) {
+export function openChatRoomWindow(account: string, roomId: string, roomName: string) {
   openChildWindow({
-    label: `chatroom-${sanitizeLabel(roomId)}`,
-    url: `/?window=chatroom&roomId=${encodeURIComponent(roomId)}&roomName=${encodeURIComponent(roomName)}`,
+    label: `chatroom-${sanitizeLabel(account)}-${sanitizeLabel(roomId)}`,
+    url: `/?window=chatroom&account=${encodeURIComponent(account)}&roomId=${encodeURIComponent(roomId)}&roomName=${encodeURIComponent(roomName)}`,
     title: `ICQ Chat - ${roomName}`,
     width: 640,
     height: 500,
   })
 }
 
-export function openFindUserWindow() {
+// DM rooms get a message session, everything else a chat room
+export function openRoomWindow(room: Room) {
+  if (room.is_direct) {
+    openDirectMessageWindow(room.account, room.room_id, room.name)
+  } else {
+    openChatRoomWindow(room.account, room.room_id, room.name)
+  }
+}
+
+export function openFindUserWindow(account: string) {
   openChildWindow({
     label: 'finduser',
-    url: '/?window=finduser',
+    url: `/?window=finduser&account=${encodeURIComponent(account)}`,
     title: 'Find Users',
     width: 340,
     height: 380,
@@ -106,10 +116,10 @@ export function openFindUserWindow() {
   })
 }
 
-export function openJoinRoomWindow() {
+export function openJoinRoomWindow(account: string) {
   openChildWindow({
     label: 'joinroom',
-    url: '/?window=joinroom',
+    url: `/?window=joinroom&account=${encodeURIComponent(account)}`,
     title: 'Join Room',
     width: 340,
     height: 200,
@@ -117,10 +127,10 @@ export function openJoinRoomWindow() {
   })
 }
 
-export function openBrowseSpacesWindow() {
+export function openBrowseSpacesWindow(account: string) {
   openChildWindow({
     label: 'browsespaces',
-    url: '/?window=browsespaces',
+    url: `/?window=browsespaces&account=${encodeURIComponent(account)}`,
     title: 'Browse Spaces',
     width: 400,
     height: 500,
@@ -128,13 +138,33 @@ export function openBrowseSpacesWindow() {
   })
 }
 
-export function openBrowseSpaceWindow(spaceId: string, spaceName: string) {
+export function openBrowseSpaceWindow(account: string, spaceId: string, spaceName: string) {
   openChildWindow({
-    label: `browsespaces-${sanitizeLabel(spaceId)}`,
-    url: `/?window=browsespaces&spaceId=${encodeURIComponent(spaceId)}&spaceName=${encodeURIComponent(spaceName)}`,
+    label: `browsespaces-${sanitizeLabel(account)}-${sanitizeLabel(spaceId)}`,
+    url: `/?window=browsespaces&account=${encodeURIComponent(account)}&spaceId=${encodeURIComponent(spaceId)}&spaceName=${encodeURIComponent(spaceName)}`,
     title: `Browse: ${spaceName}`,
     width: 400,
     height: 500,
     parent: 'main',
   })
 }
+
+export function openDevicesWindow(account: string) {
+  openChildWindow({
+    label: `devices-${sanitizeLabel(account)}`,
+    url: `/?window=devices&account=${encodeURIComponent(account)}`,
+    title: 'Sessions',
+    width: 420,
+    height: 380,
+  })
+}
+
+export function openAccountWindow(account: string) {
+  openChildWindow({
+    label: `account-${sanitizeLabel(account)}`,
+    url: `/?window=account&account=${encodeURIComponent(account)}`,
+    title: 'My Account',
+    width: 320,
+    height: 500,
+  })
+}
@@ -35,9 +35,9 @@ document.addEventListener('keydown', async (e) => {
   ))
 })
 
-// Intercept clicks on <a target="_blank"> to open in system browser
+// Intercept clicks on <a target="_blank"> and links in formatted messages to open in system browser
 document.addEventListener('click', (e) => {
-  const anchor = (e.target as HTMLElement).closest('a[target="_blank"]') as HTMLAnchorElement | null
+  const anchor = (e.target as HTMLElement).closest('a[target="_blank"], .formatted-body a[href]') as HTMLAnchorElement | null
   if (anchor?.href) {
     e.preventDefault()
     open(anchor.href)
@@ -46,6 +46,7 @@ document.addEventListener('click', (e) => {
 
 const params = new URLSearchParams(window.location.search)
 const windowType = params.get('window')
+const account = params.get('account') ?? ''
 
 ;(async () => {
   let component: any
@@ -58,6 +59,7 @@ const windowType = params.get('window')
     const mod = await import('./components/DirectMessage.svelte')
     component = mod.default
     props = {
+      account,
       roomId: params.get('roomId') ?? '',
       roomName: params.get('roomName') ?? 'Unknown',
     }
@@ -68,6 +70,7 @@ const windowType = params.get('window')
     const mod = await import('./components/UserInfo.svelte')
     component = mod.default
     props = {
+      account,
       userId: params.get('userId') ?? '',
       displayName: params.get('displayName') ?? 'Unknown',
     }
@@ -75,6 +78,7 @@ const windowType = params.get('window')
     const mod = await import('./components/RoomInfo.svelte')
     component = mod.default
     props = {
+      account,
       roomId: params.get('roomId') ?? '',
       roomName: params.get('roomName') ?? 'Room',
     }
@@ -82,23 +86,35 @@ const windowType = params.get('window')
     const mod = await import('./components/ChatRoom.svelte')
     component = mod.default
     props = {
+      account,
       roomId: params.get('roomId') ?? '',
       roomName: params.get('roomName') ?? 'Chat',
     }
   } else if (windowType === 'finduser') {
     const mod = await import('./components/FindUser.svelte')
     component = mod.default
+    props = { account }
   } else if (windowType === 'joinroom') {
     const mod = await import('./components/JoinRoom.svelte')
     component = mod.default
+    props = { account }
   } else if (windowType === 'browsespaces') {
     const mod = await import('./components/BrowseSpaces.svelte')
     component = mod.default
+    props = { account }
     const spaceId = params.get('spaceId')
     const spaceName = params.get('spaceName')
     if (spaceId && spaceName) {
-      props = { spaceId, spaceName }
+      props = { account, spaceId, spaceName }
     }
+  } else if (windowType === 'account') {
+    const mod = await import('./components/AccountSettings.svelte')
+    component = mod.default
+    props = { account }
+  } else if (windowType === 'devices') {
+    const mod = await import('./components/Devices.svelte')
+    component = mod.default
+    props = { account }
   } else {
     const mod = await import('./App.svelte')
     component = mod.default